@@ -0,0 +1,256 @@
+//! Administration API.
+//!
+//! Routes are nested under `/_admin`, and are only available when the server is started with an
+//! admin token, which must be supplied as a bearer token, eg.
+//!
+//!     $ curl -H "Authorization: Bearer $TOKEN" https://seed.example.com/_admin/quotas/<project>
+//!
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use axum::async_trait;
+use axum::extract::{FromRequest, Path as AxumPath, RequestParts};
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router};
+use git2::Oid;
+use serde_json::json;
+
+use librad::git::Urn;
+use librad::PeerId;
+
+use shared::notify::{self, Notification};
+
+use crate::admission::{self, Pending};
+use crate::error::Error;
+use crate::quota::{self, Limits, Usage};
+use crate::tokens::{self, Scope, Token};
+use crate::Context;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/quotas/:project", get(quota_handler))
+        .route("/admission", get(admission_queue_handler))
+        .route(
+            "/admission/:project/approve",
+            post(admission_approve_handler),
+        )
+        .route("/admission/:project/reject", post(admission_reject_handler))
+        .route("/tokens", get(tokens_handler).post(token_issue_handler))
+        .route("/tokens/:id", delete(token_revoke_handler))
+}
+
+/// Extractor that only succeeds for requests bearing the admin token.
+pub struct Admin;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Admin {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(ctx) = Extension::<Context>::from_request(req)
+            .await
+            .map_err(|_| Error::Backend)?;
+        // When no admin token is configured, the admin API is disabled.
+        let expected = ctx.admin_token.as_deref().ok_or(Error::NotFound)?;
+        let token =
+            crate::bearer_token(req.headers()).ok_or(Error::Unauthorized("missing admin token"))?;
+
+        if constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+            Ok(Admin)
+        } else {
+            Err(Error::Unauthorized("invalid admin token"))
+        }
+    }
+}
+
+/// Get the usage of a project and of each peer that pushed to it, against their limits.
+/// `GET /_admin/quotas/:project`
+async fn quota_handler(
+    _: Admin,
+    Extension(ctx): Extension<Context>,
+    AxumPath(project): AxumPath<String>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let urn = Urn::try_from_id(project).map_err(|_| Error::InvalidId)?;
+    let git_dir = ctx.paths.git_dir().to_path_buf();
+    let response = tokio::task::spawn_blocking(move || quota_usage(&git_dir, &urn.encode_id()))
+        .await
+        .map_err(|_| Error::Backend)??;
+
+    Ok(Json(response))
+}
+
+/// List the projects pending approval.
+/// `GET /_admin/admission`
+async fn admission_queue_handler(
+    _: Admin,
+    Extension(ctx): Extension<Context>,
+) -> Result<Json<Vec<Pending>>, Error> {
+    let git_dir = ctx.paths.git_dir().to_path_buf();
+    let queue = tokio::task::spawn_blocking(move || admission::queue(&git_dir))
+        .await
+        .map_err(|_| Error::Backend)??;
+
+    Ok(Json(queue))
+}
+
+/// Approve a project pending approval, publishing it on the seed.
+/// `POST /_admin/admission/:project/approve`
+async fn admission_approve_handler(
+    _: Admin,
+    Extension(ctx): Extension<Context>,
+    AxumPath(project): AxumPath<String>,
+) -> Result<Json<Pending>, Error> {
+    let paths = ctx.paths.clone();
+    let pending = tokio::task::spawn_blocking(move || admission::approve(&paths, &project))
+        .await
+        .map_err(|_| Error::Backend)??;
+
+    tracing::info!("Approved project {} pushed by {}", pending.id, pending.peer);
+    notify_changed(&ctx, &pending.id);
+
+    Ok(Json(pending))
+}
+
+/// Reject a project pending approval, deleting its refs.
+/// `POST /_admin/admission/:project/reject`
+async fn admission_reject_handler(
+    _: Admin,
+    Extension(ctx): Extension<Context>,
+    AxumPath(project): AxumPath<String>,
+) -> Result<Json<Pending>, Error> {
+    let git_dir = ctx.paths.git_dir().to_path_buf();
+    let pending = tokio::task::spawn_blocking(move || admission::reject(&git_dir, &project))
+        .await
+        .map_err(|_| Error::Backend)??;
+
+    tracing::info!("Rejected project {} pushed by {}", pending.id, pending.peer);
+    notify_changed(&ctx, &pending.id);
+
+    Ok(Json(pending))
+}
+
+/// List the issued tokens.
+/// `GET /_admin/tokens`
+async fn tokens_handler(
+    _: Admin,
+    Extension(ctx): Extension<Context>,
+) -> Result<Json<Vec<Token>>, Error> {
+    let git_dir = ctx.paths.git_dir().to_path_buf();
+    let tokens = tokio::task::spawn_blocking(move || tokens::load(&git_dir))
+        .await
+        .map_err(|_| Error::Backend)??;
+
+    Ok(Json(tokens.iter().map(Token::redacted).collect()))
+}
+
+/// Issue a token. The token secret is only returned once.
+/// `POST /_admin/tokens`
+async fn token_issue_handler(
+    _: Admin,
+    Extension(ctx): Extension<Context>,
+    Json(scope): Json<Scope>,
+) -> Result<Json<serde_json::Value>, Error> {
+    if let Some(project) = &scope.project {
+        Urn::try_from_id(project).map_err(|_| Error::InvalidId)?;
+    }
+    if scope.project.is_none() && scope.peer.is_none() {
+        return Err(Error::BadRequest("token must have a project or a peer"));
+    }
+    if !scope.refs.is_empty() && (scope.project.is_none() || scope.peer.is_none()) {
+        return Err(Error::BadRequest(
+            "tokens with ref patterns must have a project and a peer",
+        ));
+    }
+    let git_dir = ctx.paths.git_dir().to_path_buf();
+    let (token, secret) = tokio::task::spawn_blocking(move || tokens::issue(&git_dir, scope))
+        .await
+        .map_err(|_| Error::Backend)??;
+
+    tracing::info!("Issued token {}", token.id);
+
+    Ok(Json(json!({
+        "token": token.redacted(),
+        "secret": secret,
+    })))
+}
+
+/// Revoke a token.
+/// `DELETE /_admin/tokens/:id`
+async fn token_revoke_handler(
+    _: Admin,
+    Extension(ctx): Extension<Context>,
+    AxumPath(id): AxumPath<String>,
+) -> Result<Json<Token>, Error> {
+    let git_dir = ctx.paths.git_dir().to_path_buf();
+    let token = tokio::task::spawn_blocking(move || tokens::revoke(&git_dir, &id))
+        .await
+        .map_err(|_| Error::Backend)??;
+
+    tracing::info!("Revoked token {}", token.id);
+
+    Ok(Json(token.redacted()))
+}
+
+/// Notify other services that the refs of a project changed.
+fn notify_changed(ctx: &Context, namespace: &str) {
+    let notification = Notification {
+        namespace: namespace.to_owned(),
+        updates: Vec::new(),
+    };
+    if let Err(err) = notify::send(ctx.paths.git_dir(), &notification) {
+        tracing::warn!("Failed to send notification: {}", err);
+    }
+}
+
+fn quota_usage(git_dir: &Path, namespace: &str) -> Result<serde_json::Value, Error> {
+    let repo = git2::Repository::open_bare(git_dir)?;
+    let config = repo.config()?.snapshot()?;
+    let prefix = format!("refs/namespaces/{}/", namespace);
+    let refs = quota::refs(&repo, &prefix)?;
+
+    if refs.is_empty() {
+        return Err(Error::NotFound);
+    }
+
+    // Group the refs of each remote.
+    let mut remotes: Vec<(PeerId, BTreeMap<String, Oid>)> = Vec::new();
+    for (name, oid) in refs.iter() {
+        let peer_id = match name
+            .strip_prefix(&prefix)
+            .and_then(|r| crate::parse_ref(r).ok())
+        {
+            Some((peer_id, _)) => peer_id,
+            None => continue,
+        };
+        match remotes.iter_mut().find(|(p, _)| *p == peer_id) {
+            Some((_, refs)) => {
+                refs.insert(name.clone(), *oid);
+            }
+            None => {
+                remotes.push((peer_id, BTreeMap::from([(name.clone(), *oid)])));
+            }
+        }
+    }
+
+    let mut peers = Vec::new();
+    for (peer_id, refs) in remotes {
+        peers.push(json!({
+            "id": peer_id,
+            "limits": Limits::peer(&config, &peer_id)?,
+            "usage": Usage::compute(&repo, &refs)?,
+        }));
+    }
+
+    Ok(json!({
+        "project": {
+            "limits": Limits::project(&config, namespace)?,
+            "usage": Usage::compute(&repo, &refs)?,
+        },
+        "peers": peers,
+    }))
+}
+
+/// Compare two byte strings without leaking where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
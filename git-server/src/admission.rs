@@ -0,0 +1,456 @@
+//! Admission of new projects.
+//!
+//! By default, any peer with an authorized key may push a new identity to the seed. Seed
+//! operators can restrict this with an admission policy, configured in the monorepo's git config:
+//!
+//! ```text
+//! [admission]
+//!     policy = approval
+//!     allow = hyypw8z5g7tbui9ceh6tng58i1qk696isjnzix9fq9g41fzgjgqk8g
+//!     allow = rad:git:hnrkbjg7r54q48sqsaho1n4qfxhi4nbmdh51y
+//!     maxProjectsPerPeer = 10
+//! ```
+//!
+//! The policy is one of:
+//!
+//! * `open`: any peer may create new projects (the default).
+//! * `allowlist`: only the peers listed under `allow` may create new projects.
+//! * `approval`: new projects from peers not listed under `allow` are queued until they are
+//!   approved or rejected by the operator, via the admin API.
+//!
+//! Entries under `allow` are either peer ids, or person URNs, in which case any of the person's
+//! delegate keys is allowed.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use either::Either;
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+
+use librad::git;
+use librad::git::identities;
+use librad::git::identities::SomeIdentity;
+use librad::git::Urn;
+use librad::PeerId;
+
+use crate::error::Error;
+use crate::quota;
+
+/// File in the monorepo git directory where projects pending approval are queued.
+pub const ADMISSION_QUEUE_FILE: &str = "admission-queue.json";
+
+/// Who may create new projects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Anyone may create new projects.
+    Open,
+    /// Only allowed peers may create new projects.
+    AllowList,
+    /// New projects from peers that aren't allowed must be approved.
+    Approval,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::Open
+    }
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(Self::Open),
+            "allowlist" => Ok(Self::AllowList),
+            "approval" => Ok(Self::Approval),
+            _ => Err(Error::InvalidConfig(
+                "admission policy must be 'open', 'allowlist' or 'approval'",
+            )),
+        }
+    }
+}
+
+/// Admission settings of the seed.
+#[derive(Debug, Default, Clone)]
+pub struct Admission {
+    /// Who may create new projects.
+    pub policy: Policy,
+    /// Allowed peer ids and person URNs.
+    pub allow: Vec<String>,
+    /// Maximum number of projects a single peer may create.
+    pub max_projects_per_peer: Option<u64>,
+}
+
+impl Admission {
+    /// Load the admission settings from the monorepo config.
+    pub fn load(repo: &Repository) -> Result<Self, Error> {
+        let config = repo.config()?.snapshot()?;
+        let policy = match config.get_string("admission.policy") {
+            Ok(policy) => policy.parse()?,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Policy::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let max_projects_per_peer = match config.get_i64("admission.maxProjectsPerPeer") {
+            Ok(max) => Some(max.max(0) as u64),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let mut allow = Vec::new();
+
+        match config.multivar("admission.allow", None) {
+            Ok(entries) => {
+                for entry in &entries {
+                    if let Some(value) = entry?.value() {
+                        allow.push(value.to_owned());
+                    }
+                }
+            }
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(Self {
+            policy,
+            allow,
+            max_projects_per_peer,
+        })
+    }
+
+    /// Check whether a peer is on the allow-list, either directly or through one of the
+    /// listed persons.
+    pub fn is_allowed(
+        &self,
+        storage: &git::storage::ReadOnly,
+        peer_id: &PeerId,
+    ) -> Result<bool, Error> {
+        for entry in &self.allow {
+            if *entry == peer_id.default_encoding() {
+                return Ok(true);
+            }
+            let urn = match Urn::from_str(entry) {
+                Ok(urn) => urn,
+                Err(_) => continue,
+            };
+            if let Some(SomeIdentity::Person(person)) = identities::any::get(storage, &urn)? {
+                if person
+                    .delegations()
+                    .iter()
+                    .any(|key| PeerId::from(*key) == *peer_id)
+                {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Check that a peer may push a new project. Projects that require approval pass this
+    /// check, and are queued by the `post-receive` hook.
+    pub fn check(
+        &self,
+        repo: &Repository,
+        storage: &git::storage::ReadOnly,
+        peer_id: &PeerId,
+    ) -> Result<(), Error> {
+        if self.policy == Policy::AllowList && !self.is_allowed(storage, peer_id)? {
+            return Err(Error::AdmissionDenied(format!(
+                "{} is not allowed to create projects on this seed",
+                peer_id
+            )));
+        }
+        if let Some(max) = self.max_projects_per_peer {
+            let count = project_count(repo, peer_id, |urn| delegates(storage, urn))?;
+
+            if count >= max {
+                return Err(Error::AdmissionDenied(format!(
+                    "{} has reached the limit of {} projects on this seed",
+                    peer_id, max
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a new project pushed by the given peer must be approved by the operator.
+    pub fn requires_approval(
+        &self,
+        storage: &git::storage::ReadOnly,
+        peer_id: &PeerId,
+    ) -> Result<bool, Error> {
+        Ok(self.policy == Policy::Approval && !self.is_allowed(storage, peer_id)?)
+    }
+}
+
+/// A project waiting for approval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pending {
+    /// Project id, ie. the namespace.
+    pub id: String,
+    /// Peer that pushed the project.
+    pub peer: PeerId,
+    /// Identity commit that was pushed.
+    pub identity: String,
+    /// When the project was queued, in seconds since the epoch.
+    pub timestamp: u64,
+}
+
+impl Pending {
+    pub fn new(id: String, peer: PeerId, identity: git2::Oid) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Self {
+            id,
+            peer,
+            identity: identity.to_string(),
+            timestamp,
+        }
+    }
+}
+
+/// Number of projects a peer has created, including projects pending approval. Projects the
+/// peer only pushed its own refs to, without being one of their delegates, aren't counted.
+pub fn project_count<F>(repo: &Repository, peer_id: &PeerId, delegates: F) -> Result<u64, Error>
+where
+    F: Fn(&Urn) -> Result<Vec<PeerId>, Error>,
+{
+    let glob = format!(
+        "refs/namespaces/*/refs/remotes/{}/rad/id",
+        peer_id.default_encoding()
+    );
+    let queue = queue(repo.path())?;
+    let mut count = 0;
+
+    for r in repo.references_glob(&glob)? {
+        let r = r?;
+        let namespace = match r
+            .name()
+            .and_then(|n| n.strip_prefix("refs/namespaces/"))
+            .and_then(|n| n.split('/').next())
+        {
+            Some(namespace) => namespace,
+            None => continue,
+        };
+        let urn = match Urn::try_from_id(namespace) {
+            Ok(urn) => urn,
+            Err(_) => continue,
+        };
+        let id_ref = format!("refs/namespaces/{}/refs/rad/id", namespace);
+
+        let created = if repo.find_reference(&id_ref).is_ok() {
+            delegates(&urn)?.contains(peer_id)
+        } else {
+            // Projects pending approval don't have an identity yet, and belong to the peer
+            // that pushed them.
+            queue
+                .iter()
+                .find(|p| p.id == namespace)
+                .map_or(true, |p| p.peer == *peer_id)
+        };
+        if created {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Get the keys of a project's delegates, including the keys of its indirect delegates.
+pub fn delegates(storage: &git::storage::ReadOnly, urn: &Urn) -> Result<Vec<PeerId>, Error> {
+    let mut peer_ids = Vec::new();
+
+    if let Some(SomeIdentity::Project(doc)) = identities::any::get(storage, urn)? {
+        for delegation in doc.delegations() {
+            match delegation {
+                Either::Left(key) => peer_ids.push(PeerId::from(*key)),
+                Either::Right(person) => {
+                    peer_ids.extend(person.delegations().iter().cloned().map(PeerId::from))
+                }
+            }
+        }
+    }
+    Ok(peer_ids)
+}
+
+/// Get the projects pending approval.
+pub fn queue(git_dir: &Path) -> Result<Vec<Pending>, Error> {
+    match fs::read(git_dir.join(ADMISSION_QUEUE_FILE)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|_| Error::InvalidConfig("admission queue is corrupted")),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Check whether a project is pending approval.
+pub fn is_pending(git_dir: &Path, id: &str) -> Result<bool, Error> {
+    Ok(queue(git_dir)?.iter().any(|p| p.id == id))
+}
+
+/// Add a project to the approval queue, replacing any previous entry for the same project.
+pub fn enqueue(git_dir: &Path, pending: Pending) -> Result<(), Error> {
+    let mut queue = queue(git_dir)?;
+
+    queue.retain(|p| p.id != pending.id);
+    queue.push(pending);
+
+    write_queue(git_dir, &queue)
+}
+
+/// Remove a project from the approval queue, returning its entry.
+pub fn dequeue(git_dir: &Path, id: &str) -> Result<Pending, Error> {
+    let mut queue = queue(git_dir)?;
+    let index = queue
+        .iter()
+        .position(|p| p.id == id)
+        .ok_or(Error::NotFound)?;
+    let pending = queue.remove(index);
+
+    write_queue(git_dir, &queue)?;
+
+    Ok(pending)
+}
+
+/// Approve a pending project: verify its identity, and make it available on the seed.
+pub fn approve(paths: &librad::paths::Paths, id: &str) -> Result<Pending, Error> {
+    let git_dir = paths.git_dir();
+    let pending = queue(git_dir)?
+        .into_iter()
+        .find(|p| p.id == id)
+        .ok_or(Error::NotFound)?;
+    let urn = Urn::try_from_id(&pending.id).map_err(|_| Error::InvalidId)?;
+    let identity = git2::Oid::from_str(&pending.identity)?;
+    let storage = git::storage::ReadOnly::open(paths)?;
+
+    crate::identity::verify(&storage, &urn, identity)?;
+    track(paths, &urn, pending.peer)?;
+
+    let repo = Repository::open_bare(git_dir)?;
+    repo.reference(
+        &format!("refs/namespaces/{}/refs/rad/id", pending.id),
+        identity,
+        true,
+        "set-id (admission)",
+    )?;
+
+    dequeue(git_dir, id)
+}
+
+/// Reject a pending project, deleting everything that was pushed to its namespace.
+pub fn reject(git_dir: &Path, id: &str) -> Result<Pending, Error> {
+    let pending = dequeue(git_dir, id)?;
+    let repo = Repository::open_bare(git_dir)?;
+
+    let refs = quota::refs(&repo, &format!("refs/namespaces/{}/", pending.id))?;
+
+    for name in refs.keys() {
+        repo.find_reference(name)?.delete()?;
+    }
+    Ok(pending)
+}
+
+/// Track the peer that pushed a project.
+#[cfg(feature = "hooks")]
+fn track(paths: &librad::paths::Paths, urn: &Urn, peer_id: PeerId) -> Result<(), Error> {
+    use librad::git::tracking;
+
+    let storage = crate::hooks::storage::Storage::open(paths)?;
+    tracking::track(
+        &storage,
+        urn,
+        Some(peer_id),
+        tracking::config::Config::default(),
+        tracking::policy::Track::Any,
+    )??;
+
+    Ok(())
+}
+
+/// Tracking requires the storage used by the hooks.
+#[cfg(not(feature = "hooks"))]
+fn track(_paths: &librad::paths::Paths, _urn: &Urn, _peer_id: PeerId) -> Result<(), Error> {
+    Err(Error::ServiceUnavailable("tracking"))
+}
+
+fn write_queue(git_dir: &Path, queue: &[Pending]) -> Result<(), Error> {
+    let path = git_dir.join(ADMISSION_QUEUE_FILE);
+    let tmp = path.with_extension("json.tmp");
+    let bytes =
+        serde_json::to_vec_pretty(queue).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    // Write atomically, so that concurrent readers never see a partial queue.
+    fs::write(&tmp, bytes)?;
+    fs::rename(tmp, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use librad::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_project_count() {
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path();
+        let repo = Repository::init_bare(git_dir).unwrap();
+        let peer = PeerId::from(SecretKey::new());
+        let other = PeerId::from(SecretKey::new());
+        let sig = git2::Signature::now("radicle", "radicle@localhost").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+
+        // Create a project, with the given delegate, or pending approval if there is none.
+        let project = |name: &str, delegate: Option<&PeerId>, pushers: &[&PeerId]| {
+            let oid = repo.commit(None, &sig, &sig, name, &tree, &[]).unwrap();
+            let urn = Urn::new(oid.into());
+            let namespace = format!("refs/namespaces/{}/refs", urn.encode_id());
+
+            for pusher in pushers {
+                let remote = pusher.default_encoding();
+                let name = format!("{}/remotes/{}/rad/id", namespace, remote);
+                repo.reference(&name, oid, true, "").unwrap();
+            }
+            if delegate.is_some() {
+                let name = format!("{}/rad/id", namespace);
+                repo.reference(&name, oid, true, "").unwrap();
+            } else {
+                let pending = Pending::new(urn.encode_id(), *pushers[0], oid);
+                enqueue(git_dir, pending).unwrap();
+            }
+            (urn, delegate.copied())
+        };
+        let projects = vec![
+            // Created by the peer.
+            project("created", Some(&peer), &[&peer]),
+            // Forked by the peer.
+            project("forked", Some(&other), &[&other, &peer]),
+            // Pending approval, pushed by the peer.
+            project("pending", None, &[&peer]),
+            // Pending approval, pushed by another peer first.
+            project("pushed", None, &[&other, &peer]),
+        ];
+        let delegates = |urn: &Urn| -> Result<Vec<PeerId>, Error> {
+            Ok(projects
+                .iter()
+                .filter(|(u, _)| u == urn)
+                .filter_map(|(_, d)| *d)
+                .collect())
+        };
+
+        assert_eq!(project_count(&repo, &peer, delegates).unwrap(), 2);
+        assert_eq!(project_count(&repo, &other, delegates).unwrap(), 2);
+
+        assert!(is_pending(git_dir, &projects[2].0.encode_id()).unwrap());
+        assert!(!is_pending(git_dir, &projects[0].0.encode_id()).unwrap());
+    }
+}
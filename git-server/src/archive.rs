@@ -0,0 +1,410 @@
+//! Source archives of project revisions.
+//!
+//! Archives can be fetched with a plain HTTP request, eg.
+//! `GET /<project>.git/archive/<ref>.tar.gz`, or via the `git-upload-archive` service, which
+//! speaks the protocol used by `git archive --remote`.
+//!
+//! Since all projects share a monorepo, revisions are always resolved within the project
+//! namespace, so that objects belonging to other projects can't be archived.
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+
+use git2::{Oid, Repository};
+
+use crate::error::Error;
+
+/// Largest packet allowed by the git pkt-line format, including its length prefix.
+pub const LARGE_PACKET_MAX: usize = 65520;
+/// Sideband channel carrying archive data.
+const SIDEBAND_DATA: u8 = 1;
+/// Sideband channel carrying fatal errors.
+const SIDEBAND_ERROR: u8 = 3;
+
+/// Archive formats that can be served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    /// Split an archive path such as `master.tar.gz` into a revision and a format.
+    pub fn split(path: &str) -> Option<(&str, Self)> {
+        if let Some(rev) = path.strip_suffix(".tar.gz") {
+            Some((rev, Self::TarGz))
+        } else if let Some(rev) = path.strip_suffix(".zip") {
+            Some((rev, Self::Zip))
+        } else {
+            None
+        }
+    }
+
+    /// Parse a format given to `git archive --format`.
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "tar" => Some(Self::Tar),
+            "tgz" | "tar.gz" => Some(Self::TarGz),
+            "zip" => Some(Self::Zip),
+            _ => None,
+        }
+    }
+
+    /// The format name, as understood by `git archive`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tar => "tar",
+            Self::TarGz => "tar.gz",
+            Self::Zip => "zip",
+        }
+    }
+
+    /// The HTTP content type of archives in this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Tar => "application/x-tar",
+            Self::TarGz => "application/gzip",
+            Self::Zip => "application/zip",
+        }
+    }
+}
+
+/// Resolve a branch, tag or commit id within a project namespace to a commit.
+///
+/// Commit ids are only resolved if they are reachable from one of the project's canonical
+/// branches.
+pub fn resolve(repo: &Repository, namespace: &str, rev: &str) -> Result<Oid, Error> {
+    let prefix = format!("refs/namespaces/{}", namespace);
+
+    if rev == "HEAD" {
+        let head = repo
+            .find_reference(&format!("{}/HEAD", prefix))
+            .map_err(|_| Error::ReferenceNotFound)?;
+
+        return Ok(head.resolve()?.peel_to_commit()?.id());
+    }
+    for kind in &["heads", "tags"] {
+        if let Ok(r) = repo.find_reference(&format!("{}/refs/{}/{}", prefix, kind, rev)) {
+            return Ok(r.peel_to_commit()?.id());
+        }
+    }
+
+    // Only full commit ids are accepted, since abbreviated ids are resolved across the whole
+    // monorepo.
+    if rev.len() != 40 {
+        return Err(Error::ReferenceNotFound);
+    }
+    let oid = Oid::from_str(rev).map_err(|_| Error::ReferenceNotFound)?;
+
+    for r in repo.references_glob(&format!("{}/refs/heads/*", prefix))? {
+        if let Some(head) = r?.target() {
+            if head == oid || repo.graph_descendant_of(head, oid)? {
+                return Ok(oid);
+            }
+        }
+    }
+    Err(Error::ReferenceNotFound)
+}
+
+/// Arguments sent by a `git archive --remote` client.
+#[derive(Debug, Default)]
+struct Arguments {
+    format: Option<Format>,
+    prefix: Option<String>,
+    rev: Option<String>,
+    paths: Vec<String>,
+}
+
+impl Arguments {
+    /// Parse the pkt-line encoded arguments of an upload-archive request.
+    ///
+    /// Only a safe subset of `git archive` options is accepted.
+    fn parse(mut input: &[u8]) -> Result<Self, String> {
+        let mut args = Self::default();
+
+        loop {
+            if input.len() < 4 {
+                return Err("unexpected end of request".to_owned());
+            }
+            let len = std::str::from_utf8(&input[..4])
+                .ok()
+                .and_then(|l| usize::from_str_radix(l, 16).ok())
+                .ok_or_else(|| "invalid packet length".to_owned())?;
+
+            // Flush packet.
+            if len == 0 {
+                break;
+            }
+            if len < 4 || len > input.len() {
+                return Err("invalid packet length".to_owned());
+            }
+            let line = std::str::from_utf8(&input[4..len])
+                .map_err(|_| "invalid packet".to_owned())?
+                .trim_end_matches('\n');
+            let arg = line
+                .strip_prefix("argument ")
+                .ok_or_else(|| format!("unexpected packet {:?}", line))?;
+
+            if let Some(format) = arg.strip_prefix("--format=") {
+                args.format = Some(
+                    Format::from_arg(format)
+                        .ok_or_else(|| format!("unsupported format {:?}", format))?,
+                );
+            } else if let Some(prefix) = arg.strip_prefix("--prefix=") {
+                args.prefix = Some(prefix.to_owned());
+            } else if arg.starts_with('-') {
+                return Err(format!("argument not permitted: {}", arg));
+            } else if args.rev.is_none() {
+                args.rev = Some(arg.to_owned());
+            } else {
+                args.paths.push(arg.to_owned());
+            }
+            input = &input[len..];
+        }
+        Ok(args)
+    }
+}
+
+/// Spawn `git archive` on a resolved commit. The archive is written to the process' standard
+/// output, and errors to its standard error.
+pub fn archive(
+    git_dir: &Path,
+    oid: Oid,
+    format: Format,
+    prefix: Option<&str>,
+    paths: &[String],
+) -> Result<Child, Error> {
+    let mut cmd = Command::new("git");
+
+    cmd.arg("--git-dir")
+        .arg(git_dir)
+        .arg("archive")
+        .arg(format!("--format={}", format.as_str()));
+
+    if let Some(prefix) = prefix {
+        cmd.arg(format!("--prefix={}", prefix));
+    }
+    cmd.arg(oid.to_string())
+        .arg("--")
+        .args(paths)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    Ok(cmd.spawn()?)
+}
+
+/// Serve a `git-upload-archive` request for the given namespace.
+///
+/// The response follows the protocol used by `git archive --remote`: an `ACK` or `NACK` packet,
+/// followed by the archive multiplexed over sideband channel 1. It is written as the archive is
+/// produced, so that archives aren't held in memory.
+pub fn upload_archive<W: Write>(
+    git_dir: &Path,
+    namespace: &str,
+    request: &[u8],
+    w: &mut W,
+) -> Result<(), Error> {
+    let args = match Arguments::parse(request) {
+        Ok(args) => args,
+        Err(reason) => {
+            write_packet(w, format!("NACK {}\n", reason).as_bytes())?;
+            write_flush(w)?;
+
+            return Ok(());
+        }
+    };
+    let repo = Repository::open_bare(git_dir)?;
+    let rev = args.rev.as_deref().unwrap_or("HEAD");
+    let oid = match resolve(&repo, namespace, rev) {
+        Ok(oid) => oid,
+        Err(_) => {
+            write_packet(w, format!("NACK no such ref: {}\n", rev).as_bytes())?;
+            write_flush(w)?;
+
+            return Ok(());
+        }
+    };
+
+    write_packet(w, b"ACK\n")?;
+    write_flush(w)?;
+
+    let result = archive(
+        git_dir,
+        oid,
+        args.format.unwrap_or(Format::Tar),
+        args.prefix.as_deref(),
+        &args.paths,
+    )
+    .and_then(|mut child| {
+        let result = relay(&mut child, w);
+        if result.is_err() {
+            // Don't leave the process blocked on a pipe nobody reads, eg. if the client is gone.
+            child.kill().ok();
+            child.wait().ok();
+        }
+        result
+    });
+    if let Err(err) = result {
+        write_sideband(
+            w,
+            SIDEBAND_ERROR,
+            format!("archive failed: {}\n", err).as_bytes(),
+        )?;
+    }
+    write_flush(w)?;
+
+    Ok(())
+}
+
+/// Relay the output of a `git archive` process over sideband channel 1, as it is produced.
+fn relay<W: Write>(child: &mut Child, w: &mut W) -> Result<(), Error> {
+    let mut stdout = child.stdout.take().ok_or(Error::Backend)?;
+    let mut stderr = child.stderr.take().ok_or(Error::Backend)?;
+    // Errors are read concurrently, so that the process can't block on a full pipe.
+    let errors = thread::spawn(move || {
+        let mut errors = String::new();
+        stderr.read_to_string(&mut errors).ok();
+        errors
+    });
+    let mut buf = vec![0; LARGE_PACKET_MAX - 5];
+
+    loop {
+        let n = match stdout.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        write_sideband(w, SIDEBAND_DATA, &buf[..n])?;
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        if let Ok(errors) = errors.join() {
+            tracing::error!("git-archive: stderr: {}", errors.trim_end());
+        }
+        return Err(Error::Backend);
+    }
+    Ok(())
+}
+
+fn write_packet<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    write!(w, "{:04x}", data.len() + 4)?;
+    w.write_all(data)
+}
+
+fn write_sideband<W: Write>(w: &mut W, band: u8, data: &[u8]) -> io::Result<()> {
+    write!(w, "{:04x}", data.len() + 5)?;
+    w.write_all(&[band])?;
+    w.write_all(data)
+}
+
+fn write_flush<W: Write>(w: &mut W) -> io::Result<()> {
+    w.write_all(b"0000")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Encode arguments as an upload-archive request.
+    fn request(args: &[&str]) -> Vec<u8> {
+        let mut request = Vec::new();
+
+        for arg in args {
+            write_packet(&mut request, format!("argument {}\n", arg).as_bytes()).unwrap();
+        }
+        write_flush(&mut request).unwrap();
+
+        request
+    }
+
+    #[test]
+    fn test_arguments_parse() {
+        let args = Arguments::parse(&request(&[
+            "--format=tgz",
+            "--prefix=nakamoto/",
+            "master",
+            "src",
+        ]))
+        .unwrap();
+
+        assert_eq!(args.format, Some(Format::TarGz));
+        assert_eq!(args.prefix.as_deref(), Some("nakamoto/"));
+        assert_eq!(args.rev.as_deref(), Some("master"));
+        assert_eq!(args.paths, vec![String::from("src")]);
+
+        // Options that could run commands or read other files are rejected.
+        assert!(Arguments::parse(&request(&["--exec=/bin/sh"])).is_err());
+        assert!(Arguments::parse(&request(&["--remote=origin"])).is_err());
+        assert!(Arguments::parse(&request(&["--format=tar.xz"])).is_err());
+
+        // Malformed packets.
+        assert!(Arguments::parse(b"").is_err());
+        assert!(Arguments::parse(b"00").is_err());
+        assert!(Arguments::parse(b"zzzz").is_err());
+        assert!(Arguments::parse(b"0003").is_err());
+        assert!(Arguments::parse(b"00ffargument master\n0000").is_err());
+        assert!(Arguments::parse(b"000fwant master\n0000").is_err());
+        assert!(Arguments::parse(b"0010argument \xff\xfe\n0000").is_err());
+        // A request without a flush packet is incomplete.
+        assert!(Arguments::parse(b"0014argument master\n").is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = Repository::init_bare(tmp.path()).unwrap();
+        let sig = git2::Signature::now("radicle", "radicle@localhost").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let commit = |message: &str| repo.commit(None, &sig, &sig, message, &tree, &[]).unwrap();
+        let head = commit("Initial commit");
+        let other = commit("Other project");
+
+        repo.reference("refs/namespaces/acme/refs/heads/master", head, false, "")
+            .unwrap();
+        repo.reference("refs/namespaces/other/refs/heads/master", other, false, "")
+            .unwrap();
+        repo.reference_symbolic(
+            "refs/namespaces/acme/HEAD",
+            "refs/namespaces/acme/refs/heads/master",
+            false,
+            "",
+        )
+        .unwrap();
+
+        assert_eq!(resolve(&repo, "acme", "HEAD").unwrap(), head);
+        assert_eq!(resolve(&repo, "acme", "master").unwrap(), head);
+        assert_eq!(resolve(&repo, "acme", &head.to_string()).unwrap(), head);
+
+        // Commits of other projects, abbreviated ids and unknown refs aren't resolved.
+        assert!(resolve(&repo, "acme", &other.to_string()).is_err());
+        assert!(resolve(&repo, "acme", &head.to_string()[..7]).is_err());
+        assert!(resolve(&repo, "acme", "develop").is_err());
+        assert!(resolve(&repo, "acme", "../other/refs/heads/master").is_err());
+        assert!(resolve(&repo, "other", "HEAD").is_err());
+
+        // Malformed requests and unknown refs are refused.
+        let mut response = Vec::new();
+        upload_archive(tmp.path(), "acme", b"00", &mut response).unwrap();
+        assert_eq!(response, b"0023NACK unexpected end of request\n0000");
+
+        let mut response = Vec::new();
+        upload_archive(
+            tmp.path(),
+            "acme",
+            &request(&[&other.to_string()]),
+            &mut response,
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            format!("003fNACK no such ref: {}\n0000", other).into_bytes()
+        );
+    }
+}
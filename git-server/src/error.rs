@@ -32,10 +32,38 @@ pub enum Error {
     #[error("custom hook failed to spawn: {0}")]
     CustomHook(std::io::Error),
 
+    /// Mirror push failed to spawn.
+    #[error("mirror push failed to spawn: {0}")]
+    Mirror(std::io::Error),
+
+    /// Mirror push was rejected or failed.
+    #[error("mirror push failed: {0}")]
+    MirrorPush(String),
+
+    /// A quota was exceeded.
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    /// A new project was refused by the admission policy.
+    #[error("admission denied: {0}")]
+    AdmissionDenied(String),
+
+    /// An identity update was refused.
+    #[error("identity update rejected: {0}")]
+    IdentityRejected(String),
+
+    /// Invalid configuration value.
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(&'static str),
+
     /// Failed certificate verification.
     #[error("failed certification verification")]
     FailedCertificateVerification,
 
+    /// Invalid request.
+    #[error("bad request: {0}")]
+    BadRequest(&'static str),
+
     /// Unauthorized.
     #[error("unauthorized: {0}")]
     Unauthorized(&'static str),
@@ -48,10 +76,22 @@ pub enum Error {
     #[error("signer key mismatch: expected {expected}, got {actual}")]
     KeyMismatch { actual: String, expected: String },
 
+    /// Entity not found.
+    #[error("not found")]
+    NotFound,
+
     /// Project alias not found.
     #[error("alias does not exist")]
     AliasNotFound,
 
+    /// Alias registry error.
+    #[error(transparent)]
+    Alias(#[from] shared::alias::Error),
+
+    /// Access control error.
+    #[error(transparent)]
+    Access(#[from] shared::access::Error),
+
     /// Id is not valid.
     #[error("id is not valid")]
     InvalidId,
@@ -142,15 +182,23 @@ pub enum Error {
     InvalidHeaderValue(#[from] axum::http::header::InvalidHeaderValue),
 }
 
+/// Challenge sent with unauthorized responses.
+pub const WWW_AUTHENTICATE_BASIC: &str = "Basic realm=\"radicle\"";
+
 impl Error {
     pub fn status(&self) -> http::StatusCode {
         match self {
             Error::UnsupportedContentEncoding(_) => http::StatusCode::NOT_IMPLEMENTED,
             Error::ServiceUnavailable(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::BadRequest(_) => http::StatusCode::BAD_REQUEST,
             Error::Unauthorized(_) => http::StatusCode::UNAUTHORIZED,
             Error::KeyMismatch { .. } => http::StatusCode::UNAUTHORIZED,
+            Error::AdmissionDenied(_) => http::StatusCode::FORBIDDEN,
+            Error::IdentityRejected(_) => http::StatusCode::FORBIDDEN,
+            Error::NotFound => http::StatusCode::NOT_FOUND,
             Error::AliasNotFound => http::StatusCode::NOT_FOUND,
             Error::InvalidId => http::StatusCode::NOT_FOUND,
+            Error::ReferenceNotFound => http::StatusCode::NOT_FOUND,
             _ => http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -160,6 +208,15 @@ impl IntoResponse for Error {
     fn into_response(self) -> Response {
         tracing::error!("{}", self);
 
-        self.status().into_response()
+        let status = self.status();
+        if status == http::StatusCode::UNAUTHORIZED {
+            // Let git clients know that they can retry with credentials, ie. a token.
+            return (
+                status,
+                [(http::header::WWW_AUTHENTICATE, WWW_AUTHENTICATE_BASIC)],
+            )
+                .into_response();
+        }
+        status.into_response()
     }
 }
@@ -14,18 +14,31 @@ use git2::{Oid, Repository};
 use librad::git;
 use librad::git::identities;
 use librad::git::identities::SomeIdentity;
-use librad::git::storage::read::ReadOnlyStorage as _;
 use librad::git::tracking;
 use librad::git::Urn;
 use librad::paths::Paths;
 use librad::profile::Profile;
 use librad::PeerId;
 
+use shared::notify::{self, Notification, RefUpdate};
+
 use super::storage::Storage;
 use super::{types::ReceivePackEnv, CertSignerDetails};
+use crate::admission::{self, Admission, Pending};
 use crate::error::Error;
+use crate::identity::{self, Quorum};
+use crate::quota;
 
 pub const RAD_ID_REF: &str = "rad/id";
+/// Identity revisions that are not yet signed by a quorum of delegates are stored under this ref.
+pub const RAD_ID_PROPOSALS_REF: &str = "rad/id-proposals";
+
+/// Whether a pushed ref is the project identity of the pusher's tree, ie.
+/// `refs/remotes/<peer>/rad/id`, which the project's own `refs/rad/id` is updated from. Refs that
+/// merely end with `rad/id`, such as a `heads/rad/id` branch, aren't.
+pub fn is_identity_ref(refname: &str) -> bool {
+    matches!(crate::parse_ref(refname), Ok((_, rest)) if rest == RAD_ID_REF)
+}
 
 /// `PostReceive` provides access to the standard input values passed into the `post-receive`
 /// git hook, as well as parses environmental variables that may be used to process the hook.
@@ -79,11 +92,14 @@ impl PostReceive {
         } else {
             Vec::new()
         };
-        let key_fingerprint = env
-            .cert_key
-            .as_ref()
-            .ok_or(Error::PostReceive("push certificate is not available"))?
-            .to_owned();
+        let key_fingerprint = match (&env.cert_key, &env.token) {
+            (Some(key), _) => key.to_owned(),
+            // Pushes authenticated with a token are not signed.
+            (None, Some(id)) => format!("token-{}", id),
+            (None, None) => {
+                return Err(Error::PostReceive("push certificate is not available"));
+            }
+        };
 
         Ok(Self {
             urn,
@@ -120,9 +136,14 @@ impl PostReceive {
         } else {
             println!("Pushing new identity...");
 
-            post_receive.initialize_identity(&repo)?;
-            post_receive.track_identity(None)?;
+            if post_receive.requires_approval(&repo)? {
+                post_receive.queue_identity()?;
+            } else {
+                post_receive.initialize_identity(&repo)?;
+                post_receive.track_identity(None)?;
+            }
         }
+        post_receive.notify();
 
         Ok(())
     }
@@ -206,20 +227,81 @@ impl PostReceive {
         Ok(oid)
     }
 
+    /// Update the project identity, if a new revision was pushed and it is signed by a quorum of
+    /// the current delegates. Otherwise, the revision is stored as a proposal.
     fn update_identity(&mut self, repo: &Repository) -> Result<(), Error> {
-        if let Some(oid) = self.find_identity_update() {
+        let oid = if let Some(oid) = self.find_identity_update() {
+            oid
+        } else {
+            return Ok(());
+        };
+        let current = repo
+            .find_reference(&self.namespace_ref(RAD_ID_REF))?
+            .target()
+            .ok_or(Error::PostReceive("identity ref is not a direct reference"))?;
+
+        if oid == current {
+            return Ok(());
+        }
+        if !repo.graph_descendant_of(oid, current)? {
+            println!(
+                "Identity {} does not descend from {}, ignoring.",
+                oid, current
+            );
+            return Ok(());
+        }
+
+        let storage = git::storage::ReadOnly::open(&self.paths)?;
+        let quorum = Quorum::compute(&storage, current, oid)?;
+
+        println!(
+            "Identity {} is signed by {}/{} delegate(s), {} required:",
+            oid,
+            quorum.signed(),
+            quorum.signatures.len(),
+            quorum.threshold
+        );
+        for (delegate, signed) in &quorum.signatures {
+            println!("  {} {}", if *signed { "✓" } else { "✗" }, delegate);
+        }
+
+        if quorum.is_reached() {
             eprintln!("Updating identity to {}...", oid);
-            self.set_identity_ref(oid, repo)
+            self.set_identity_ref(oid, repo)?;
+            self.clear_identity_proposals(repo)
         } else {
+            println!("Storing identity {} as a proposal...", oid);
+            repo.reference(
+                &self.namespace_ref(&format!("{}/{}", RAD_ID_PROPOSALS_REF, oid)),
+                oid,
+                true,
+                &format!("propose-id ({})", self.key_fingerprint),
+            )?;
             Ok(())
         }
     }
 
+    /// Remove identity proposals that are superseded by the current identity.
+    fn clear_identity_proposals(&self, repo: &Repository) -> Result<(), Error> {
+        let prefix = format!("{}/", self.namespace_ref(RAD_ID_PROPOSALS_REF));
+        let current = repo
+            .find_reference(&self.namespace_ref(RAD_ID_REF))?
+            .target()
+            .ok_or(Error::PostReceive("identity ref is not a direct reference"))?;
+
+        for (name, oid) in quota::refs(repo, &prefix)? {
+            if oid == current || repo.graph_descendant_of(current, oid)? {
+                repo.find_reference(&name)?.delete()?;
+            }
+        }
+        Ok(())
+    }
+
     fn find_identity_update(&self) -> Option<Oid> {
         if let Some(update) = self
             .updates
             .iter()
-            .find(|(refname, _, _)| refname.ends_with(RAD_ID_REF))
+            .find(|(refname, _, _)| is_identity_ref(refname))
         {
             let (_, _, identity_oid) = update;
 
@@ -250,43 +332,46 @@ impl PostReceive {
         self.set_identity_ref(identity_oid, repo)
     }
 
-    fn set_identity_ref(&self, identity_oid: Oid, repo: &Repository) -> Result<(), Error> {
-        let storage = git::storage::ReadOnly::open(&self.paths)?;
-        let lookup = |urn| {
-            let refname = git::types::Reference::rad_id(git::types::Namespace::from(urn));
-            storage.reference_oid(&refname).map(|oid| oid.into())
+    /// Whether the new identity must be approved by the seed operator before it is published.
+    fn requires_approval(&self, repo: &Repository) -> Result<bool, Error> {
+        let peer_id = match self.updates.first() {
+            Some((refname, _, _)) => crate::parse_ref(refname)?.0,
+            None => return Ok(false),
         };
+        let storage = git::storage::ReadOnly::open(&self.paths)?;
 
-        let identity = storage
-            .identities::<identities::SomeIdentity>()
-            .some_identity(identity_oid)
-            .map_err(|_| Error::NamespaceNotFound)?;
+        Admission::load(repo)?.requires_approval(&storage, &peer_id)
+    }
 
-        // Make sure that the identity we're pushing matches the namespace
-        // we're pushing to.
-        if identity.urn() != self.urn {
-            return Err(Error::PostReceive(
-                "identity document doesn't match project id",
-            ));
-        }
+    /// Verify the new identity and queue it for approval, instead of publishing it.
+    fn queue_identity(&self) -> Result<(), Error> {
+        let identity_oid = self.find_identity_update().ok_or(Error::PostReceive(
+            "identity ref 'rad/id' not found in updates",
+        ))?;
+        let (refname, _, _) = self
+            .updates
+            .first()
+            .ok_or(Error::PostReceive("no ref updates"))?;
+        let (peer_id, _) = crate::parse_ref(refname)?;
+        let storage = git::storage::ReadOnly::open(&self.paths)?;
 
-        match identity {
-            identities::SomeIdentity::Person(_) => {
-                storage
-                    .identities::<git::identities::Person>()
-                    .verify(identity_oid)
-                    .map_err(|e| Error::VerifyIdentity(e.to_string()))?;
-            }
-            identities::SomeIdentity::Project(_) => {
-                storage
-                    .identities::<git::identities::Project>()
-                    .verify(identity_oid, lookup)
-                    .map_err(|e| Error::VerifyIdentity(e.to_string()))?;
-            }
-            _ => {
-                return Err(Error::PostReceive("unknown identity type"));
-            }
-        }
+        identity::verify(&storage, &self.urn, identity_oid)?;
+        admission::enqueue(
+            &self.env.git_dir,
+            Pending::new(self.env.git_namespace.clone(), peer_id, identity_oid),
+        )?;
+        println!(
+            "Project {} is pending approval by the seed operator.",
+            self.urn
+        );
+
+        Ok(())
+    }
+
+    fn set_identity_ref(&self, identity_oid: Oid, repo: &Repository) -> Result<(), Error> {
+        let storage = git::storage::ReadOnly::open(&self.paths)?;
+
+        identity::verify(&storage, &self.urn, identity_oid)?;
 
         // Set local identity to point to the verified commit pushed by the user.
         repo.reference(
@@ -418,4 +503,47 @@ impl PostReceive {
 
         Ok(())
     }
+
+    /// Notify other services sharing the monorepo, eg. the HTTP API, of the ref updates.
+    fn notify(&self) {
+        let notification = Notification {
+            namespace: self.env.git_namespace.clone(),
+            updates: self
+                .updates
+                .iter()
+                .map(|(name, old, new)| RefUpdate {
+                    name: name.clone(),
+                    old: old.to_string(),
+                    new: new.to_string(),
+                })
+                .collect(),
+        };
+        if let Err(err) = notify::send(&self.env.git_dir, &notification) {
+            println!("Failed to send notification: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use librad::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_is_identity_ref() {
+        let peer = PeerId::from(SecretKey::new()).default_encoding();
+
+        assert!(is_identity_ref(&format!("refs/remotes/{}/rad/id", peer)));
+        assert!(!is_identity_ref(&format!(
+            "refs/remotes/{}/heads/rad/id",
+            peer
+        )));
+        assert!(!is_identity_ref(&format!(
+            "refs/remotes/{}/rad/id-proposals",
+            peer
+        )));
+        assert!(!is_identity_ref("refs/remotes/nobody/rad/id"));
+        assert!(!is_identity_ref("refs/heads/rad/id"));
+    }
 }
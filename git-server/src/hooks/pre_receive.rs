@@ -16,20 +16,28 @@
 //!
 //! The `pre-receive` git hook provides access to GPG certificates for a signed push, useful for authorizing an
 //! update the repository.
+use std::collections::BTreeMap;
 use std::io;
 use std::io::prelude::*;
 use std::io::stdin;
+use std::path::Path;
 use std::str::FromStr;
 
 use envconfig::Envconfig;
 use git2::{Oid, Repository};
+use librad::git;
+use librad::profile::Profile;
 use librad::PeerId;
 
 use super::{
+    post_receive::is_identity_ref,
     types::{CertNonceStatus, CertStatus, ReceivePackEnv},
     CertSignerDetails,
 };
+use crate::admission::Admission;
 use crate::error::Error;
+use crate::quota::{self, Limits, Usage};
+use crate::tokens;
 
 pub type KeyRing = Vec<String>;
 
@@ -73,11 +81,14 @@ impl PreReceive {
             .map(|k| k.split(',').map(|k| k.to_owned()).collect::<KeyRing>())
             .unwrap_or_default();
 
-        let key_fingerprint = env
-            .cert_key
-            .as_ref()
-            .ok_or(Error::Unauthorized("push certificate is not available"))?
-            .to_owned();
+        let key_fingerprint = match (&env.cert_key, &env.token) {
+            (Some(key), _) => key.to_owned(),
+            // Pushes authenticated with a token are not signed.
+            (None, Some(id)) => format!("token-{}", id),
+            (None, None) => {
+                return Err(Error::Unauthorized("push certificate is not available"));
+            }
+        };
 
         Ok(Self {
             env,
@@ -98,10 +109,191 @@ impl PreReceive {
         repo.set_namespace(&pre_receive.env.git_namespace)
             .map_err(Error::from)?;
 
-        pre_receive.verify_certificate()?;
-        pre_receive.check_authorized_key()?;
-        pre_receive.authorize_ref_updates()?;
+        if pre_receive.env.token.is_some() {
+            pre_receive.authorize_token()?;
+        } else {
+            pre_receive.verify_certificate()?;
+            pre_receive.check_authorized_key()?;
+            pre_receive.authorize_ref_updates()?;
+        }
+        pre_receive.check_admission()?;
+        pre_receive.check_identity_update()?;
+        pre_receive.check_quotas()?;
+
+        Ok(())
+    }
+
+    /// Checks that the pusher is allowed to create the project, if it is new.
+    fn check_admission(&self) -> Result<(), Error> {
+        let repo = Repository::open_bare(&self.env.git_dir)?;
+        let id_ref = format!("refs/namespaces/{}/refs/rad/id", self.env.git_namespace);
+
+        if repo.find_reference(&id_ref).is_ok() {
+            return Ok(());
+        }
+        let peer_id = match self.updates.first() {
+            Some((refname, _, _)) => {
+                let (peer_id, _) = crate::parse_ref(refname)
+                    .map_err(|_| Error::InvalidRefPushed(refname.to_owned()))?;
+                peer_id
+            }
+            None => return Ok(()),
+        };
+        eprintln!("Checking admission of new project...");
+
+        let paths = if let Some(root) = &self.env.root {
+            Profile::from_root(Path::new(root), None)?.paths().clone()
+        } else {
+            Profile::load()?.paths().clone()
+        };
+        let storage = git::storage::ReadOnly::open(&paths)?;
+
+        Admission::load(&repo)?.check(&repo, &storage, &peer_id)
+    }
+
+    /// Checks that a pushed identity revision descends from the project's current identity.
+    /// Other revisions can't be applied by the `post-receive` hook, so they are rejected here,
+    /// rather than silently ignored after the push.
+    fn check_identity_update(&self) -> Result<(), Error> {
+        let new = match self
+            .updates
+            .iter()
+            .find(|(refname, _, new)| is_identity_ref(refname) && !new.is_zero())
+        {
+            Some((_, _, new)) => *new,
+            None => return Ok(()),
+        };
+        // Objects received by the push are only visible from the quarantine environment.
+        let repo = Repository::open_from_env()?;
+        repo.remove_namespace()?;
+
+        let id_ref = format!("refs/namespaces/{}/refs/rad/id", self.env.git_namespace);
+        let current = match repo.find_reference(&id_ref) {
+            Ok(reference) => reference.target(),
+            // The project is new, and is initialized with the pushed identity.
+            Err(e) if e.code() == git2::ErrorCode::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let current = match current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+
+        if new == current || repo.graph_descendant_of(new, current)? {
+            return Ok(());
+        }
+        Err(Error::IdentityRejected(format!(
+            "identity {} does not descend from the current identity {}; \
+             fetch `rad/id` and base the new revision on it",
+            new, current
+        )))
+    }
+
+    /// Checks that the ref updates don't exceed the project's quotas, nor the quotas of the
+    /// peers being pushed to.
+    fn check_quotas(&self) -> Result<(), Error> {
+        // Objects received by the push are only visible from the quarantine environment.
+        let repo = Repository::open_from_env()?;
+        // We work with fully qualified ref names, across namespaces.
+        repo.remove_namespace()?;
+        let config = repo.config()?.snapshot()?;
+        let namespace = &self.env.git_namespace;
+        let prefix = format!("refs/namespaces/{}/", namespace);
+        let pack_size = match &self.env.quarantine_path {
+            Some(path) => quota::dir_size(path)?,
+            None => 0,
+        };
+
+        // Objects stored before the push, to only count the objects the push adds. Without a
+        // quarantine, received objects are already stored, and usage is computed from scratch.
+        let stored = match &self.env.quarantine_path {
+            Some(_) => Some(Repository::open_bare(&self.env.git_dir)?.odb()?),
+            None => None,
+        };
+        let cache = quota::Cache::new(&self.env.git_dir);
+
+        // The refs of the project, before and as they will be if the push is accepted.
+        let before = quota::refs(&repo, &prefix)?;
+        let mut after = before.clone();
+        for (refname, _, new) in self.updates.iter() {
+            let name = format!("{}{}", prefix, refname);
+
+            if new.is_zero() {
+                after.remove(&name);
+            } else {
+                after.insert(name, *new);
+            }
+        }
+        let usage_of = |scope: &str,
+                        before: &BTreeMap<String, Oid>,
+                        after: &BTreeMap<String, Oid>|
+         -> Result<Usage, Error> {
+            let usage = Usage::after_push(&repo, &cache, scope, before, after, stored.as_ref())?;
+
+            if let Err(err) = cache.put(scope, after, usage) {
+                eprintln!("Failed to cache usage of {}: {}", scope, err);
+            }
+            Ok(usage)
+        };
+
+        let limits = Limits::project(&config, namespace)?;
+        if !limits.is_unlimited() {
+            eprintln!("Checking project quota...");
+
+            limits.check("project", &usage_of(namespace, &before, &after)?, pack_size)?;
+        }
+
+        let mut peers: Vec<PeerId> = Vec::new();
+        for (refname, _, _) in self.updates.iter() {
+            if let Ok((peer_id, _)) = crate::parse_ref(refname) {
+                if !peers.contains(&peer_id) {
+                    peers.push(peer_id);
+                }
+            }
+        }
+
+        for peer_id in peers {
+            let limits = Limits::peer(&config, &peer_id)?;
+            if limits.is_unlimited() {
+                continue;
+            }
+            eprintln!("Checking quota for {}...", peer_id);
+
+            let peer = peer_id.default_encoding();
+            let remote = format!("{}refs/remotes/{}/", prefix, peer);
+            let remote_refs = |refs: &BTreeMap<String, Oid>| {
+                refs.iter()
+                    .filter(|(name, _)| name.starts_with(&remote))
+                    .map(|(name, oid)| (name.clone(), *oid))
+                    .collect::<BTreeMap<_, _>>()
+            };
+            let scope = format!("{}-{}", namespace, peer);
+            let usage = usage_of(&scope, &remote_refs(&before), &remote_refs(&after))?;
+
+            limits.check("peer", &usage, pack_size)?;
+        }
+        Ok(())
+    }
 
+    /// Authorizes each ref update against the scope of the token used to push.
+    fn authorize_token(&self) -> Result<(), Error> {
+        let id = self
+            .env
+            .token
+            .as_deref()
+            .ok_or(Error::Unauthorized("token is not available"))?;
+        eprintln!("Authorizing token {}...", id);
+
+        let token = tokens::get(&self.env.git_dir, id)?
+            .ok_or(Error::Unauthorized("token is invalid or expired"))?;
+        if !token.can_push(&self.env.git_namespace) {
+            return Err(Error::Unauthorized(
+                "token is not allowed to push to this project",
+            ));
+        }
+        for (refname, _, _) in self.updates.iter() {
+            token.authorize_ref(refname)?;
+        }
         Ok(())
     }
 
@@ -9,7 +9,8 @@ use librad::paths::Paths;
 
 use git_ref_format::{refspec, RefString};
 
-use std::convert::Infallible;
+use std::borrow::Cow;
+use std::convert::{Infallible, TryFrom};
 
 use crate::error::Error;
 
@@ -71,24 +72,68 @@ impl odb::Read for Storage {
 }
 
 impl<'a> refdb::Read<'a> for Storage {
-    type FindError = Infallible;
-    type ReferencesError = Infallible;
-    type IterError = Infallible;
+    type FindError = librad::git::storage::read::Error;
+    type ReferencesError = git2::Error;
+    type IterError = error::Iter;
     type Oid = Oid;
-    type References = std::iter::Empty<Result<Ref<'a>, Infallible>>;
+    type References = References<'a>;
 
     fn find_reference(
         &self,
-        _reference: &RefName<'_, Self::Oid>,
+        reference: &RefName<'_, Self::Oid>,
     ) -> Result<Option<Ref<'_>>, Self::FindError> {
-        unimplemented!()
+        let name = RefString::from(reference);
+
+        Ok(self.reference(&name)?.and_then(|r| {
+            // Symbolic refs are not tracking refs, so they are skipped.
+            r.target().map(|target| Ref {
+                name: Cow::from(name),
+                target: target.into(),
+            })
+        }))
     }
 
     fn references(
         &'a self,
-        _spec: impl AsRef<refspec::PatternStr>,
+        spec: impl AsRef<refspec::PatternStr>,
     ) -> Result<Self::References, Self::ReferencesError> {
-        unimplemented!()
+        let inner = self.backend.references_glob(spec.as_ref().as_str())?;
+
+        Ok(References { inner })
+    }
+}
+
+/// Iterator over the direct references matching a pattern.
+pub struct References<'a> {
+    inner: git2::References<'a>,
+}
+
+impl<'a> Iterator for References<'a> {
+    type Item = Result<Ref<'a>, error::Iter>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for r in self.inner.by_ref() {
+            let r = match r {
+                Ok(r) => r,
+                Err(err) => return Some(Err(err.into())),
+            };
+            let target = match r.target() {
+                Some(target) => target,
+                // Skip symbolic refs.
+                None => continue,
+            };
+            let name = match r.name().map(RefString::try_from) {
+                Some(Ok(name)) => name,
+                Some(Err(err)) => return Some(Err(err.into())),
+                None => return Some(Err(error::Iter::Utf8)),
+            };
+
+            return Some(Ok(Ref {
+                name: Cow::from(name),
+                target: target.into(),
+            }));
+        }
+        None
     }
 }
 
@@ -129,6 +174,16 @@ mod error {
     #[error("the reference was symbolic, but it is expected to be direct")]
     pub struct SymbolicRef;
 
+    #[derive(Debug, Error)]
+    pub enum Iter {
+        #[error(transparent)]
+        Git(#[from] git2::Error),
+        #[error(transparent)]
+        RefFormat(#[from] git_ref_format::Error),
+        #[error("reference name is not valid UTF-8")]
+        Utf8,
+    }
+
     #[derive(Debug, Error)]
     pub enum Txn {
         #[error("failed to initialise git transaction")]
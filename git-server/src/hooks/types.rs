@@ -154,6 +154,10 @@ pub struct ReceivePackEnv {
     #[envconfig(from = "RADICLE_RECEIVE_HOOK")]
     pub receive_hook: Option<PathBuf>,
 
+    /// id of the token used to authenticate the push, if the push isn't signed.
+    #[envconfig(from = "RADICLE_TOKEN")]
+    pub token: Option<String>,
+
     /// project default branch.
     #[envconfig(from = "RADICLE_DEFAULT_BRANCH")]
     pub default_branch: Option<String>,
@@ -204,6 +208,11 @@ pub struct ReceivePackEnv {
     #[envconfig(from = "QUERY_STRING")]
     pub query_string: String,
 
+    /// directory where objects received by a push are kept until the push is accepted.
+    /// set by `git-receive-pack` for the `pre-receive` hook.
+    #[envconfig(from = "GIT_QUARANTINE_PATH")]
+    pub quarantine_path: Option<PathBuf>,
+
     /// top-level git directory, set by the git-http-backend.
     #[envconfig(from = "GIT_DIR")]
     pub git_dir: PathBuf,
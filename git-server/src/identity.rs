@@ -0,0 +1,167 @@
+//! Identity verification.
+use either::Either;
+use git2::Oid;
+
+use librad::git;
+use librad::git::identities;
+use librad::git::storage::read::ReadOnlyStorage as _;
+use librad::git::Urn;
+use librad::PeerId;
+
+use crate::error::Error;
+
+/// Verify that the identity at the given commit is valid, and belongs to the given URN.
+pub fn verify(storage: &git::storage::ReadOnly, urn: &Urn, identity_oid: Oid) -> Result<(), Error> {
+    let lookup = |urn| {
+        let refname = git::types::Reference::rad_id(git::types::Namespace::from(urn));
+        storage.reference_oid(&refname).map(|oid| oid.into())
+    };
+
+    let identity = storage
+        .identities::<identities::SomeIdentity>()
+        .some_identity(identity_oid)
+        .map_err(|_| Error::NamespaceNotFound)?;
+
+    // Make sure that the identity we're pushing matches the namespace
+    // we're pushing to.
+    if &identity.urn() != urn {
+        return Err(Error::PostReceive(
+            "identity document doesn't match project id",
+        ));
+    }
+
+    match identity {
+        identities::SomeIdentity::Person(_) => {
+            storage
+                .identities::<git::identities::Person>()
+                .verify(identity_oid)
+                .map_err(|e| Error::VerifyIdentity(e.to_string()))?;
+        }
+        identities::SomeIdentity::Project(_) => {
+            storage
+                .identities::<git::identities::Project>()
+                .verify(identity_oid, lookup)
+                .map_err(|e| Error::VerifyIdentity(e.to_string()))?;
+        }
+        _ => {
+            return Err(Error::PostReceive("unknown identity type"));
+        }
+    }
+    Ok(())
+}
+
+/// Signature status of a proposed identity revision, against the current delegates.
+#[derive(Debug, Clone)]
+pub struct Quorum {
+    /// Each current delegate, and whether it signed the proposal.
+    pub signatures: Vec<(String, bool)>,
+    /// Number of delegate signatures required.
+    pub threshold: usize,
+}
+
+impl Quorum {
+    /// Compute the signature status of a proposed identity revision, given the current one.
+    ///
+    /// A delegate that is a person counts as one signature if any of its keys signed.
+    pub fn compute(
+        storage: &git::storage::ReadOnly,
+        current: Oid,
+        proposed: Oid,
+    ) -> Result<Self, Error> {
+        let identities = storage.identities::<identities::SomeIdentity>();
+        let current = identities
+            .some_identity(current)
+            .map_err(|_| Error::NamespaceNotFound)?;
+        let proposed = identities
+            .some_identity(proposed)
+            .map_err(|_| Error::NamespaceNotFound)?;
+
+        let signers = match &proposed {
+            identities::SomeIdentity::Person(doc) => doc.signatures.keys().collect::<Vec<_>>(),
+            identities::SomeIdentity::Project(doc) => doc.signatures.keys().collect::<Vec<_>>(),
+            _ => return Err(Error::PostReceive("unknown identity type")),
+        };
+        let signatures = match &current {
+            identities::SomeIdentity::Person(doc) => doc
+                .delegations()
+                .iter()
+                .map(|key| (PeerId::from(*key).to_string(), signers.contains(&key)))
+                .collect::<Vec<_>>(),
+            identities::SomeIdentity::Project(doc) => doc
+                .delegations()
+                .into_iter()
+                .map(|d| match d {
+                    Either::Left(key) => (PeerId::from(*key).to_string(), signers.contains(&key)),
+                    Either::Right(person) => (
+                        person.urn().to_string(),
+                        person
+                            .delegations()
+                            .iter()
+                            .any(|key| signers.contains(&key)),
+                    ),
+                })
+                .collect::<Vec<_>>(),
+            _ => return Err(Error::PostReceive("unknown identity type")),
+        };
+        Ok(Self::new(signatures))
+    }
+
+    /// Create a quorum from each current delegate, and whether it signed the proposal.
+    pub fn new(signatures: Vec<(String, bool)>) -> Self {
+        // A quorum is a strict majority of delegates.
+        let threshold = signatures.len() / 2 + 1;
+
+        Self {
+            signatures,
+            threshold,
+        }
+    }
+
+    /// Number of current delegates that signed the proposal.
+    pub fn signed(&self) -> usize {
+        self.signatures.iter().filter(|(_, signed)| *signed).count()
+    }
+
+    /// Whether enough delegates signed the proposal for it to be accepted.
+    pub fn is_reached(&self) -> bool {
+        self.signed() >= self.threshold
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn quorum(signed: &[bool]) -> Quorum {
+        Quorum::new(
+            signed
+                .iter()
+                .enumerate()
+                .map(|(i, signed)| (format!("delegate-{}", i), *signed))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_quorum() {
+        assert!(quorum(&[true]).is_reached());
+        assert!(!quorum(&[false]).is_reached());
+
+        // Half of the delegates isn't a majority.
+        assert_eq!(quorum(&[true, false]).threshold, 2);
+        assert!(!quorum(&[true, false]).is_reached());
+        assert!(quorum(&[true, true]).is_reached());
+
+        assert_eq!(quorum(&[true, true, false]).threshold, 2);
+        assert!(quorum(&[true, false, true]).is_reached());
+        assert!(!quorum(&[false, false, true]).is_reached());
+
+        assert_eq!(quorum(&[true, true, false, false]).threshold, 3);
+        assert!(!quorum(&[true, true, false, false]).is_reached());
+        assert_eq!(quorum(&[true, true, true, false]).signed(), 3);
+        assert!(quorum(&[true, true, true, false]).is_reached());
+
+        // Without delegates, there is nobody to sign.
+        assert!(!quorum(&[]).is_reached());
+    }
+}
@@ -1,6 +1,15 @@
 #![allow(clippy::type_complexity)]
 #![allow(clippy::too_many_arguments)]
+pub mod admin;
+pub mod admission;
+pub mod archive;
 pub mod error;
+pub mod identity;
+pub mod mirror;
+pub mod quota;
+pub mod replica;
+pub mod signed_refs;
+pub mod tokens;
 
 #[cfg(feature = "hooks")]
 pub mod hooks;
@@ -9,7 +18,8 @@ use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::prelude::*;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::Arc;
@@ -18,21 +28,22 @@ use std::{io, net};
 
 use anyhow::bail;
 use anyhow::Context as _;
-use axum::body::{BoxBody, Bytes};
-use axum::extract::{ConnectInfo, Path as AxumPath, RawQuery};
+use axum::body::{BoxBody, Bytes, StreamBody};
+use axum::extract::{Path as AxumPath, RawQuery};
 use axum::http::{Method, StatusCode};
 use axum::response::IntoResponse;
 use axum::routing::any;
-use axum::{Extension, Router};
+use axum::{middleware, Extension, Router};
 use axum_server::tls_rustls::RustlsConfig;
 use either::Either;
 use flate2::write::GzDecoder;
-use http::header::HeaderName;
+use http::header::{self, HeaderName};
 use http::HeaderMap;
 use hyper::body::Buf;
 use hyper::http::{Request, Response};
 use hyper::Body;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
+use tokio_util::io::ReaderStream;
 use tower_http::trace::TraceLayer;
 use tracing::Span;
 
@@ -44,6 +55,11 @@ use librad::paths::Paths;
 use librad::profile::LnkHome;
 use librad::PeerId;
 
+use shared::access::{Access, Principal};
+use shared::alias::{Aliases, Cached};
+use shared::proxy::{self, ClientAddr, ForwardingHeader, IpNet, ProxyListener, TrustedProxies};
+use shared::ratelimit::{self, RateLimiter};
+
 use error::Error;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -61,6 +77,15 @@ pub struct Options {
     pub git_receive_pack: bool,
     pub cert_nonce_seed: Option<String>,
     pub allow_unauthorized_keys: bool,
+    pub mirror_credentials: Option<PathBuf>,
+    pub admin_token: Option<String>,
+    pub trusted_proxies: Vec<IpNet>,
+    pub proxy_header: ForwardingHeader,
+    pub proxy_protocol: bool,
+    pub rate_limit: Option<NonZeroU32>,
+    pub replica_of: Option<String>,
+    pub primary_url: Option<String>,
+    pub replica_interval: Duration,
 }
 
 #[derive(Clone)]
@@ -71,13 +96,19 @@ pub struct Context {
     cert_nonce_seed: Option<String>,
     git_receive_hook: PathBuf,
     allow_unauthorized_keys: bool,
-    aliases: Arc<RwLock<HashMap<String, Urn>>>,
+    admin_token: Option<String>,
+    replica: bool,
+    primary_url: Option<String>,
+    aliases: Arc<RwLock<Aliases>>,
     pool: Pool<git::storage::ReadOnly>,
+    sign_refs: mpsc::UnboundedSender<Urn>,
+    mirrors: mpsc::UnboundedSender<Urn>,
 }
 
 impl Context {
     fn from(options: &Options) -> anyhow::Result<Self> {
-        let (root, profile, _) = shared::profile(options.root.clone(), options.passphrase.clone())?;
+        let (root, profile, signer) =
+            shared::profile(options.root.clone(), options.passphrase.clone())?;
         let paths = profile.paths();
         let pool = git::storage::Pool::new(
             git::storage::pool::ReadConfig::new(paths.clone()),
@@ -89,6 +120,12 @@ impl Context {
 
         tracing::debug!("Git root path set to: {:?}", git_root);
 
+        let sign_refs = signed_refs::spawn(paths.clone(), signer);
+        let mirrors = mirror::spawn(
+            paths.git_dir().to_owned(),
+            options.mirror_credentials.clone(),
+        );
+
         Ok(Context {
             paths: paths.clone(),
             root,
@@ -96,8 +133,16 @@ impl Context {
             git_receive_hook,
             cert_nonce_seed: options.cert_nonce_seed.clone(),
             allow_unauthorized_keys: options.allow_unauthorized_keys,
-            aliases: Default::default(),
+            admin_token: options.admin_token.clone(),
+            replica: options.replica_of.is_some(),
+            primary_url: options
+                .primary_url
+                .as_ref()
+                .map(|url| url.trim_end_matches('/').to_owned()),
+            aliases: Arc::new(RwLock::new(Aliases::new(paths.git_dir()))),
             pool,
+            sign_refs,
+            mirrors,
         })
     }
 
@@ -126,6 +171,75 @@ impl Context {
         Ok(authorized_keys.into_iter().collect())
     }
 
+    /// Get the token presented by a request, if any.
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<Option<tokens::Token>, Error> {
+        let secret = match token_secret(headers) {
+            Some(secret) => secret,
+            None => return Ok(None),
+        };
+        let git_dir = self.paths.git_dir().to_owned();
+
+        tokio::task::spawn_blocking(move || tokens::find(&git_dir, &secret))
+            .await
+            .map_err(|_| Error::Backend)??
+            .map(Some)
+            .ok_or(Error::Unauthorized("invalid or expired token"))
+    }
+
+    /// Check that the request is allowed to read the given project.
+    ///
+    /// Unauthorized requests for private projects get a "not found" error, so that the
+    /// existence of the project isn't leaked.
+    pub async fn authorize_read(
+        &self,
+        urn: &Urn,
+        token: Option<&tokens::Token>,
+    ) -> Result<(), Error> {
+        let namespace = urn.encode_id();
+        let config = self.paths.git_dir().join("config");
+        let access = {
+            let namespace = namespace.clone();
+
+            tokio::task::spawn_blocking(move || {
+                let config = git2::Config::open(&config)?;
+                Access::load(&config, &namespace).map_err(Error::from)
+            })
+            .await
+            .map_err(|_| Error::Backend)??
+        };
+
+        if !access.is_private() {
+            return Ok(());
+        }
+        let (_, delegates, _) = self.get_meta(urn).await?;
+        let principal = token.map_or(Principal::Anonymous, |t| t.principal());
+
+        if access.can_read(&namespace, &delegates, principal) {
+            Ok(())
+        } else {
+            Err(Error::NotFound)
+        }
+    }
+
+    /// Check that the given project isn't pending approval.
+    ///
+    /// Projects pending approval haven't been verified, so nothing is served from them until
+    /// they're admitted. Like for private projects, they get a "not found" error.
+    pub async fn authorize_admitted(&self, urn: &Urn) -> Result<(), Error> {
+        let git_dir = self.paths.git_dir().to_owned();
+        let namespace = urn.encode_id();
+        let pending =
+            tokio::task::spawn_blocking(move || admission::is_pending(&git_dir, &namespace))
+                .await
+                .map_err(|_| Error::Backend)??;
+
+        if pending {
+            Err(Error::NotFound)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Sets the config receive.advertisePushOptions, which lets the user known they can provide a push option `-o`,
     /// to specify unique attributes. This is currently not used, but may be used in the future.
     pub fn advertise_push_options(&self) -> Result<(), Error> {
@@ -212,27 +326,29 @@ impl Context {
         Ok(())
     }
 
-    /// Populates alias map with unique projects' names and their urns
-    async fn populate_aliases(&self, map: &mut HashMap<String, Urn>) -> Result<(), Error> {
-        use librad::git::identities::SomeIdentity::Project;
-
+    /// Resolve a project alias.
+    async fn resolve_alias(&self, alias: &str) -> Result<Urn, Error> {
         let storage = self.pool.get().await?;
-        let identities = identities::any::list(&storage)?;
+        // Known aliases are resolved under a read lock, and only unknown ones refresh the
+        // registry.
+        let cached = self.aliases.read().await.resolve_cached(&storage, alias)?;
+        match cached {
+            Cached::Found(urn) => return Ok(urn),
+            Cached::Missing => return Err(Error::AliasNotFound),
+            Cached::Unknown => {}
+        }
+        let mut aliases = self.aliases.write().await;
 
-        for identity in identities.flatten() {
-            if let Project(project) = identity {
-                let urn = project.urn();
-                let name = project.payload().subject.name.to_string();
+        aliases
+            .resolve(&storage, alias)?
+            .ok_or(Error::AliasNotFound)
+    }
 
-                tracing::info!("alias {:?} for {:?}", name, urn.to_string());
+    /// Update the alias registry entry of a project, after its refs changed.
+    async fn update_alias(&self, urn: &Urn) -> Result<(), Error> {
+        let storage = self.pool.get().await?;
 
-                if let std::collections::hash_map::Entry::Vacant(e) = map.entry(name.clone()) {
-                    e.insert(urn);
-                } else {
-                    tracing::warn!("alias {:?} exists, skipping", name);
-                }
-            }
-        }
+        self.aliases.write().await.update(&storage, urn)?;
 
         Ok(())
     }
@@ -293,13 +409,18 @@ pub async fn run(options: Options) -> anyhow::Result<()> {
         .stdout;
     tracing::info!("{}", std::str::from_utf8(&git_version)?.trim());
 
+    if options.replica_of.is_some() && options.git_receive_pack {
+        bail!("A replica can't service 'git-receive-pack'");
+    }
     let ctx = Context::from(&options).expect("context creation must not fail");
     {
-        let mut aliases = ctx.aliases.write().await;
+        let storage = ctx.pool.get().await?;
 
-        ctx.populate_aliases(&mut aliases)
+        ctx.aliases
+            .write()
             .await
-            .context("populating aliases")?;
+            .refresh(&storage)
+            .context("refreshing aliases")?;
     }
 
     if let Err(e) = ctx.set_cert_nonce_seed() {
@@ -318,14 +439,36 @@ pub async fn run(options: Options) -> anyhow::Result<()> {
         bail!("Failed to disable gc: {:?}", e);
     }
 
+    if let Some(url) = &options.replica_of {
+        tracing::info!("Running as a read-only replica of {}", url);
+
+        replica::spawn(
+            ctx.paths.git_dir().to_path_buf(),
+            url.clone(),
+            options.replica_interval,
+        );
+    }
+
+    let proxies = TrustedProxies::new(options.trusted_proxies.clone(), options.proxy_header);
+    let limiter = RateLimiter::new(options.rate_limit);
     let app = Router::new()
         .route("/:project_id/*request", any(git_handler))
+        .nest("/_admin", admin::router())
         .layer(Extension(ctx.clone()))
+        .layer(middleware::from_fn(ratelimit::middleware))
+        .layer(Extension(limiter))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &Request<Body>| {
+                    let client = request
+                        .extensions()
+                        .get::<ClientAddr>()
+                        .map(|ClientAddr(addr)| addr.to_string())
+                        .unwrap_or_default();
+
                     tracing::info_span!(
                         "request",
+                        client = %client,
                         method = %request.method(),
                         uri = %request.uri(),
                         status = tracing::field::Empty,
@@ -341,15 +484,28 @@ pub async fn run(options: Options) -> anyhow::Result<()> {
                     },
                 ),
         )
+        .layer(middleware::from_fn(proxy::middleware))
+        .layer(Extension(proxies.clone()))
         .into_make_service_with_connect_info::<SocketAddr>();
 
     if let (Some(cert), Some(key)) = (options.tls_cert, options.tls_key) {
+        if options.proxy_protocol {
+            bail!("The PROXY protocol is not supported with TLS");
+        }
         let config = RustlsConfig::from_pem_file(cert, key).await.unwrap();
 
         tracing::info!("listening on https://{}", options.listen);
         axum_server::bind_rustls(options.listen, config)
             .serve(app)
             .await?;
+    } else if options.proxy_protocol {
+        if proxies.is_empty() {
+            bail!("The PROXY protocol requires at least one trusted proxy");
+        }
+        let listener = ProxyListener::bind(options.listen, proxies).await?;
+
+        tracing::info!("listening on http://{} (PROXY protocol)", options.listen);
+        axum::Server::builder(listener).serve(app).await?;
     } else {
         tracing::info!("listening on http://{}", options.listen);
         axum::Server::bind(&options.listen).serve(app).await?;
@@ -364,9 +520,9 @@ async fn git_handler(
     method: Method,
     headers: HeaderMap,
     body: Bytes,
-    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    Extension(ClientAddr(remote)): Extension<ClientAddr>,
     query: RawQuery,
-) -> impl IntoResponse {
+) -> Result<Response<BoxBody>, Error> {
     let peer_id = None;
     let query = query.0.unwrap_or_default();
 
@@ -376,25 +532,74 @@ async fn git_handler(
         } else {
             tracing::debug!("looking for project alias {:?}", name);
 
-            let mut aliases = ctx.aliases.write().await;
-            if !aliases.contains_key(name) {
-                // If the alias does not exist, rebuild the cache.
-                ctx.populate_aliases(&mut aliases).await?;
-            }
-            let urn = aliases.get(name).cloned().ok_or(Error::AliasNotFound)?;
+            let urn = ctx.resolve_alias(name).await?;
             tracing::debug!("project alias resolved to {}", urn);
 
             urn
         }
     } else {
-        Urn::try_from_id(project_id).map_err(|_| Error::InvalidId)?
+        Urn::try_from_id(&project_id).map_err(|_| Error::InvalidId)?
     };
 
+    let token = ctx.authenticate(&headers).await?;
+    ctx.authorize_read(&urn, token.as_ref()).await?;
+
+    let path = request.trim_start_matches('/');
+    let receive_pack = method == Method::POST && path == "git-receive-pack";
+
+    // Projects pending approval can be pushed to again by their peer, but aren't fetchable.
+    if !receive_pack && query != "service=git-receive-pack" {
+        ctx.authorize_admitted(&urn).await?;
+    }
+    if let (&Method::GET, Some(archive_path)) = (&method, path.strip_prefix("archive/")) {
+        return archive_handler(ctx, urn, archive_path).await;
+    }
+    if method == Method::POST && path == "git-upload-archive" {
+        return upload_archive_handler(ctx, urn, body).await;
+    }
+
+    if ctx.replica && (receive_pack || query == "service=git-receive-pack") {
+        return Ok(replica::push_response(
+            ctx.primary_url.as_deref(),
+            &project_id,
+            path,
+        ));
+    }
+    let refs = if receive_pack {
+        Some(namespace_refs(&ctx, &urn).await?)
+    } else {
+        None
+    };
     let (status, headers, body) = git(
-        ctx, method, headers, body, remote, urn, peer_id, &request, query,
+        ctx.clone(),
+        method,
+        headers,
+        body,
+        remote,
+        urn.clone(),
+        peer_id,
+        token,
+        &request,
+        query,
     )
     .await?;
 
+    // Once a push updated the project, the seed's refs of the project must be re-signed.
+    // git-http-backend responds with a success even when the push is rejected by a hook, so
+    // the refs are compared instead.
+    if let Some(before) = refs {
+        if status.is_success() && namespace_refs(&ctx, &urn).await? != before {
+            // This server isn't notified of its own pushes, so its aliases are updated here.
+            if let Err(err) = ctx.update_alias(&urn).await {
+                tracing::warn!("Failed to update the aliases of {}: {}", urn, err);
+            }
+            // Mirrors are pushed to in the background, so that slow mirrors don't hold up the
+            // push.
+            ctx.mirrors.send(urn.clone()).ok();
+            ctx.sign_refs.send(urn).ok();
+        }
+    }
+
     let mut response_headers = HeaderMap::new();
     for (name, vec) in headers.iter() {
         for value in vec {
@@ -403,7 +608,138 @@ async fn git_handler(
         }
     }
 
-    Ok::<_, Error>((status, response_headers, body))
+    Ok((status, response_headers, body).into_response())
+}
+
+/// Get the refs of a project namespace, with their target, sorted by name.
+async fn namespace_refs(ctx: &Context, urn: &Urn) -> Result<Vec<(String, git2::Oid)>, Error> {
+    let git_dir = ctx.paths.git_dir().to_path_buf();
+    let glob = format!("refs/namespaces/{}/*", urn.encode_id());
+
+    tokio::task::spawn_blocking(move || {
+        let repo = git2::Repository::open_bare(&git_dir)?;
+        let mut refs = Vec::new();
+
+        for r in repo.references_glob(&glob)? {
+            let r = r?;
+            if let (Some(name), Some(target)) = (r.name(), r.target()) {
+                refs.push((name.to_owned(), target));
+            }
+        }
+        refs.sort();
+
+        Ok(refs)
+    })
+    .await
+    .map_err(|_| Error::Backend)?
+}
+
+/// Stream an archive of a project revision.
+/// `GET /<project>.git/archive/<ref>.{tar.gz,zip}`
+async fn archive_handler(ctx: Context, urn: Urn, path: &str) -> Result<Response<BoxBody>, Error> {
+    let (rev, format) = archive::Format::split(path).ok_or(Error::ReferenceNotFound)?;
+    let namespace = urn.encode_id();
+    let git_dir = ctx.paths.git_dir().canonicalize()?;
+    let oid = {
+        let repo = git2::Repository::open_bare(&git_dir)?;
+        archive::resolve(&repo, &namespace, rev)?
+    };
+    let (name, _, _) = ctx.get_meta(&urn).await?;
+    let basename = format!("{}-{}", name.unwrap_or(namespace), rev.replace('/', "-"));
+
+    let mut child = tokio::process::Command::new("git")
+        .arg("--git-dir")
+        .arg(&git_dir)
+        .arg("archive")
+        .arg(format!("--format={}", format.as_str()))
+        .arg(format!("--prefix={}/", basename))
+        .arg(oid.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = child.stdout.take().ok_or(Error::Backend)?;
+
+    // Reap the child process once the archive has been written.
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if !status.success() => {
+                tracing::error!("git-archive: exited with code {}", status);
+            }
+            Err(err) => {
+                tracing::error!("git-archive: failed to wait for process: {}", err);
+            }
+            Ok(_) => {}
+        }
+    });
+
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_owned()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.{}\"", basename, format.as_str()),
+        ),
+    ];
+    let body = StreamBody::new(ReaderStream::new(stdout));
+
+    Ok((headers, body).into_response())
+}
+
+/// Serve `git archive --remote` requests within the project namespace. The response is streamed
+/// from a blocking task, as the archive is produced.
+/// `POST /<project>.git/git-upload-archive`
+async fn upload_archive_handler(
+    ctx: Context,
+    urn: Urn,
+    request: Bytes,
+) -> Result<Response<BoxBody>, Error> {
+    let namespace = urn.encode_id();
+    let git_dir = ctx.paths.git_dir().canonicalize()?;
+    let (sender, body) = Body::channel();
+    let writer = BodyWriter {
+        sender,
+        runtime: tokio::runtime::Handle::current(),
+    };
+
+    tokio::task::spawn_blocking(move || {
+        let mut writer = io::BufWriter::with_capacity(archive::LARGE_PACKET_MAX, writer);
+        let result = archive::upload_archive(&git_dir, &namespace, &request, &mut writer)
+            .and_then(|()| writer.flush().map_err(Error::from));
+
+        if let Err(err) = result {
+            tracing::error!("git-upload-archive: {}", err);
+        }
+    });
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/x-git-upload-archive-result",
+        )],
+        body,
+    )
+        .into_response())
+}
+
+/// Writes to a streamed response body, from a blocking task.
+struct BodyWriter {
+    sender: hyper::body::Sender,
+    runtime: tokio::runtime::Handle,
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime
+            .block_on(self.sender.send_data(Bytes::copy_from_slice(buf)))
+            .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 async fn git(
@@ -411,9 +747,10 @@ async fn git(
     method: Method,
     headers: HeaderMap,
     mut body: impl Buf,
-    remote: net::SocketAddr,
+    remote: IpAddr,
     urn: Urn,
     peer_id: Option<PeerId>,
+    token: Option<tokens::Token>,
     path: &str,
     query: String,
 ) -> Result<(http::StatusCode, HashMap<String, Vec<String>>, Vec<u8>), Error> {
@@ -424,15 +761,19 @@ async fn git(
         } else {
             ""
         };
-    let authorized_keys = match (path, query.as_str()) {
+    let (authorized_keys, push_token) = match (path, query.as_str()) {
         // Eg. `git push`
         ("git-receive-pack", _) | (_, "service=git-receive-pack") => {
             if !ctx.git_receive_pack {
                 return Err(Error::ServiceUnavailable("git-receive-pack"));
             }
-            ctx.load_authorized_keys()?
+            // Tokens that can't push to this project are only used for reading; the push
+            // must then be signed.
+            let push_token = token.filter(|t| t.can_push(&namespace));
+
+            (ctx.load_authorized_keys()?, push_token)
         }
-        _ => vec![],
+        _ => (vec![], None),
     };
 
     let (name, delegates, default_branch) = ctx.get_meta(&urn).await?;
@@ -487,9 +828,19 @@ async fn git(
     // "The backend process sets GIT_COMMITTER_NAME to $REMOTE_USER and GIT_COMMITTER_EMAIL to
     // ${REMOTE_USER}@http.${REMOTE_ADDR}, ensuring that any reflogs created by git-receive-pack
     // contain some identifying information of the remote user who performed the push."
-    cmd.env("REMOTE_USER", remote.ip().to_string());
+    //
+    // For pushes authenticated with a token, the token id is recorded instead, and the hooks
+    // authorize the ref updates against the token's scope.
+    if let Some(token) = &push_token {
+        cmd.env("RADICLE_TOKEN", &token.id);
+        cmd.env("REMOTE_USER", format!("token-{}", token.id));
+    } else {
+        cmd.env("REMOTE_USER", remote.to_string());
+    }
     cmd.env("REMOTE_ADDR", remote.to_string());
     cmd.env("QUERY_STRING", query);
+    // Read access to private projects is checked before we get here.
+    //
     // "The GIT_HTTP_EXPORT_ALL environmental variable may be passed to git-http-backend to bypass
     // the check for the "git-daemon-export-ok" file in each repository before allowing export of
     // that repository."
@@ -592,6 +943,34 @@ async fn git(
     }
 }
 
+/// Get the bearer token of a request, if any.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+}
+
+/// Get the token secret of a request, if any. The secret is either given as a bearer token, or
+/// as the password of Basic credentials, or as the username if there is no password.
+fn token_secret(headers: &HeaderMap) -> Option<String> {
+    if let Some(token) = bearer_token(headers) {
+        return Some(token.to_owned());
+    }
+    let credentials = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|c| base64::decode(c).ok())
+        .and_then(|c| String::from_utf8(c).ok())?;
+
+    match credentials.split_once(':') {
+        Some((_, password)) if !password.is_empty() => Some(password.to_owned()),
+        Some((username, _)) => Some(username.to_owned()),
+        None => Some(credentials),
+    }
+}
+
 /// Helper method to generate random string for cert nonce;
 fn gen_random_string() -> String {
     let rng = fastrand::Rng::new();
@@ -1,7 +1,10 @@
+use std::num::{NonZeroU32, NonZeroU64};
 use std::path::PathBuf;
+use std::time::Duration;
 use std::{net, process};
 
 use radicle_git_server as server;
+use shared::proxy::{ForwardingHeader, IpNet};
 
 use argh::FromArgs;
 
@@ -39,6 +42,43 @@ pub struct Options {
     /// allow unauthorized keys, ignores gpg certificate verification
     #[argh(switch)]
     pub allow_unauthorized_keys: bool,
+
+    /// git credential store file used when pushing to project mirrors
+    #[argh(option)]
+    pub mirror_credentials: Option<PathBuf>,
+
+    /// bearer token required to access the admin API; the API is disabled if not set
+    #[argh(option)]
+    pub admin_token: Option<String>,
+
+    /// trust the forwarding headers of proxies in this network, eg. 10.0.0.0/8 (may be repeated)
+    #[argh(option)]
+    pub trusted_proxy: Vec<IpNet>,
+
+    /// forwarding header set by the trusted proxies, `x-forwarded-for` or `forwarded`; other
+    /// forwarding headers are ignored (default: x-forwarded-for)
+    #[argh(option, default = "ForwardingHeader::XForwardedFor")]
+    pub proxy_header: ForwardingHeader,
+
+    /// expect a PROXY protocol v2 header from trusted proxies on every connection
+    #[argh(switch)]
+    pub proxy_protocol: bool,
+
+    /// maximum number of requests per minute from a single client address (default: unlimited)
+    #[argh(option)]
+    pub rate_limit: Option<NonZeroU32>,
+
+    /// run as a read-only replica, syncing projects from the primary monorepo at this git URL
+    #[argh(option)]
+    pub replica_of: Option<String>,
+
+    /// public URL of the primary seed, that pushes to a replica are redirected to
+    #[argh(option)]
+    pub primary_url: Option<String>,
+
+    /// interval in seconds between syncs with the primary (default: 60)
+    #[argh(option, default = "NonZeroU64::new(60).unwrap()")]
+    pub replica_interval: NonZeroU64,
 }
 
 impl Options {
@@ -58,6 +98,15 @@ impl From<Options> for server::Options {
             git_receive_pack: other.git_receive_pack,
             cert_nonce_seed: other.cert_nonce_seed,
             allow_unauthorized_keys: other.allow_unauthorized_keys,
+            mirror_credentials: other.mirror_credentials,
+            admin_token: other.admin_token,
+            trusted_proxies: other.trusted_proxy,
+            proxy_header: other.proxy_header,
+            proxy_protocol: other.proxy_protocol,
+            rate_limit: other.rate_limit,
+            replica_of: other.replica_of,
+            primary_url: other.primary_url,
+            replica_interval: Duration::from_secs(other.replica_interval.get()),
         }
     }
 }
@@ -0,0 +1,435 @@
+//! # PUSH MIRRORS
+//!
+//! After a push is accepted, the canonical refs of a project can be forwarded to one or more
+//! external git remotes, eg. for CI integrations on another git host.
+//!
+//! Mirrors are configured per project in the monorepo's git config, under a `mirror` section
+//! named after the project id:
+//!
+//! ```text
+//! [mirror "hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo"]
+//!     url = https://github.com/cloudhead/nakamoto.git
+//!     url = https://gitlab.com/cloudhead/nakamoto.git
+//!     mode = ff-only
+//! ```
+//!
+//! Credentials are never stored in the monorepo. Instead, the git-server can be given the path
+//! to a git credential store file, which is used when pushing to the mirrors.
+//!
+//! Mirrors are pushed to by the git-server, in the background, so that a slow or unreachable
+//! mirror doesn't hold up the original push.
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use git2::Repository;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+use librad::git::Urn;
+
+use crate::error::Error;
+
+/// File in the monorepo git directory where mirror push failures are recorded.
+pub const MIRROR_LOG_FILE: &str = "mirror.log";
+/// Time after which a push to a mirror is aborted.
+pub const MIRROR_PUSH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How refs are updated on a mirror.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorMode {
+    /// Force-update the mirror refs, overwriting any diverging history.
+    Force,
+    /// Only update mirror refs that can be fast-forwarded.
+    FastForwardOnly,
+}
+
+impl Default for MirrorMode {
+    fn default() -> Self {
+        Self::FastForwardOnly
+    }
+}
+
+impl FromStr for MirrorMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "force" => Ok(Self::Force),
+            "ff-only" => Ok(Self::FastForwardOnly),
+            _ => Err(Error::InvalidConfig(
+                "mirror mode must be 'force' or 'ff-only'",
+            )),
+        }
+    }
+}
+
+/// An external remote that a project's canonical refs are pushed to.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    /// Remote URL, without credentials.
+    pub url: String,
+    /// How refs are updated.
+    pub mode: MirrorMode,
+}
+
+impl Mirror {
+    /// Load the mirrors configured for the given namespace.
+    pub fn load(repo: &Repository, namespace: &str) -> Result<Vec<Self>, Error> {
+        let config = repo.config()?.snapshot()?;
+        let mode = match config.get_string(&format!("mirror.{}.mode", namespace)) {
+            Ok(mode) => mode.parse()?,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => MirrorMode::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut mirrors = Vec::new();
+
+        match config.multivar(&format!("mirror.{}.url", namespace), None) {
+            Ok(entries) => {
+                for entry in &entries {
+                    if let Some(url) = entry?.value() {
+                        mirrors.push(Self {
+                            url: url.to_owned(),
+                            mode,
+                        });
+                    }
+                }
+            }
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        Ok(mirrors)
+    }
+
+    /// Get the refspec pushing the canonical branches of the given namespace to this mirror.
+    pub fn refspec(&self, namespace: &str) -> String {
+        let force = if self.mode == MirrorMode::Force {
+            "+"
+        } else {
+            ""
+        };
+        // eg. refs/namespaces/<namespace>/refs/heads/* -> refs/heads/*
+        format!(
+            "{}refs/namespaces/{}/refs/heads/*:refs/heads/*",
+            force, namespace
+        )
+    }
+
+    /// Push the canonical branches of the given namespace to this mirror, aborting the push
+    /// after the given timeout.
+    pub async fn push(
+        &self,
+        git_dir: &Path,
+        namespace: &str,
+        credentials: Option<&Path>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let mut cmd = Command::new("git");
+
+        if let Some(path) = credentials {
+            // Clear any inherited credential helpers, so that only the store is used.
+            cmd.arg("-c").arg("credential.helper=");
+            cmd.arg("-c")
+                .arg(format!("credential.helper=store --file={}", path.display()));
+        }
+        cmd.arg("--git-dir")
+            .arg(git_dir)
+            .arg("push")
+            .arg("--porcelain")
+            .arg(&self.url)
+            .arg(self.refspec(namespace))
+            // Refs are pushed from the whole monorepo, not from within a namespace.
+            .env_remove("GIT_NAMESPACE")
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // The push is killed when the timeout drops it.
+            .kill_on_drop(true);
+
+        let output = match tokio::time::timeout(timeout, cmd.output()).await {
+            Ok(output) => output.map_err(Error::Mirror)?,
+            Err(_) => {
+                return Err(Error::MirrorPush(format!(
+                    "timed out after {}s",
+                    timeout.as_secs()
+                )))
+            }
+        };
+        if output.status.success() {
+            return Ok(());
+        }
+        // Refs rejected by the mirror, eg. non-fast-forwards, are reported on stdout.
+        let rejections = rejections(&String::from_utf8_lossy(&output.stdout));
+        if !rejections.is_empty() {
+            let reason = rejections
+                .iter()
+                .map(|r| format!("{} rejected ({})", r.refname, r.reason))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            return Err(Error::MirrorPush(reason));
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr
+            .lines()
+            .rev()
+            .find(|l| !l.trim().is_empty())
+            .unwrap_or("unknown error")
+            .trim()
+            .to_owned();
+
+        Err(Error::MirrorPush(reason))
+    }
+}
+
+/// A ref rejected by a mirror.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    /// Name of the ref on the mirror.
+    pub refname: String,
+    /// Why the ref was rejected, eg. `non-fast-forward`.
+    pub reason: String,
+}
+
+/// Get the rejected refs from the output of `git push --porcelain`.
+///
+/// Each pushed ref is reported on a line of the form `<flag>\t<from>:<to>\t<summary>`, where the
+/// summary ends with the reason in parentheses, if any, and rejected refs are flagged with `!`.
+pub fn rejections(output: &str) -> Vec<Rejection> {
+    let mut rejections = Vec::new();
+
+    for line in output.lines() {
+        let mut fields = line.splitn(3, '\t');
+        let (flag, refs, summary) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(flag), Some(refs), Some(summary)) => (flag, refs, summary),
+            _ => continue,
+        };
+        if flag != "!" {
+            continue;
+        }
+        let refname = refs.split_once(':').map_or(refs, |(_, to)| to);
+        let reason = match (summary.find('('), summary.rfind(')')) {
+            (Some(start), Some(end)) if start < end => &summary[start + 1..end],
+            _ => summary.trim_matches(|c| c == '[' || c == ']'),
+        };
+
+        rejections.push(Rejection {
+            refname: refname.to_owned(),
+            reason: reason.to_owned(),
+        });
+    }
+    rejections
+}
+
+/// Spawn the mirroring task, returning a channel on which projects to mirror are sent.
+pub fn spawn(git_dir: PathBuf, credentials: Option<PathBuf>) -> mpsc::UnboundedSender<Urn> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Urn>();
+
+    tokio::spawn(async move {
+        while let Some(urn) = rx.recv().await {
+            // Coalesce requests for the same project, since pushes often come in bursts, and
+            // a single push to the mirrors brings them up to date.
+            let mut urns = vec![urn];
+            while let Ok(urn) = rx.try_recv() {
+                if !urns.contains(&urn) {
+                    urns.push(urn);
+                }
+            }
+
+            for urn in urns {
+                push_all(&git_dir, &urn, credentials.as_deref()).await;
+            }
+        }
+    });
+
+    tx
+}
+
+/// Push a project to all its mirrors. Failures are logged and recorded.
+async fn push_all(git_dir: &Path, urn: &Urn, credentials: Option<&Path>) {
+    let namespace = urn.encode_id();
+    let mirrors = {
+        let (git_dir, namespace) = (git_dir.to_owned(), namespace.clone());
+
+        tokio::task::spawn_blocking(move || {
+            let repo = Repository::open_bare(&git_dir)?;
+            Mirror::load(&repo, &namespace)
+        })
+        .await
+        .map_err(|_| Error::Backend)
+        .and_then(|mirrors| mirrors)
+    };
+    let mirrors = match mirrors {
+        Ok(mirrors) => mirrors,
+        Err(err) => {
+            tracing::error!("Failed to load the mirrors of {}: {}", urn, err);
+            return;
+        }
+    };
+
+    for m in mirrors {
+        match m
+            .push(git_dir, &namespace, credentials, MIRROR_PUSH_TIMEOUT)
+            .await
+        {
+            Ok(()) => tracing::info!("Mirror {} of {} updated", m.url, urn),
+            Err(err) => {
+                tracing::warn!("Mirror {} of {} failed: {}", m.url, urn, err);
+
+                let (git_dir, namespace) = (git_dir.to_owned(), namespace.clone());
+                let result = tokio::task::spawn_blocking(move || {
+                    record_failure(&git_dir, &namespace, &m.url, &err)
+                })
+                .await;
+
+                if let Ok(Err(err)) = result {
+                    tracing::error!("Failed to record mirror failure: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Record a mirror push failure in the monorepo's mirror log.
+pub fn record_failure(
+    git_dir: &Path,
+    namespace: &str,
+    url: &str,
+    err: &Error,
+) -> Result<(), Error> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(git_dir.join(MIRROR_LOG_FILE))?;
+
+    writeln!(file, "{} {} {} {}", timestamp, namespace, url, err)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn test_refspec() {
+        let mut mirror = Mirror {
+            url: String::from("https://example.com/nakamoto.git"),
+            mode: MirrorMode::FastForwardOnly,
+        };
+        assert_eq!(
+            mirror.refspec("hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo"),
+            "refs/namespaces/hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo/refs/heads/*:refs/heads/*"
+        );
+
+        mirror.mode = MirrorMode::Force;
+        assert_eq!(
+            mirror.refspec("hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo"),
+            "+refs/namespaces/hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo/refs/heads/*:refs/heads/*"
+        );
+    }
+
+    #[test]
+    fn test_rejections() {
+        let output = "To https://example.com/nakamoto.git\n\
+            \x20\trefs/namespaces/hnrk/refs/heads/dev:refs/heads/dev\t0a1b2c3..4d5e6f7\n\
+            !\trefs/namespaces/hnrk/refs/heads/master:refs/heads/master\t[rejected] (non-fast-forward)\n\
+            !\trefs/namespaces/hnrk/refs/heads/ci:refs/heads/ci\t[remote rejected] (pre-receive hook declined)\n\
+            !\trefs/namespaces/hnrk/refs/heads/old:refs/heads/old\t[remote failure]\n\
+            Done\n";
+
+        assert_eq!(
+            rejections(output),
+            vec![
+                Rejection {
+                    refname: String::from("refs/heads/master"),
+                    reason: String::from("non-fast-forward"),
+                },
+                Rejection {
+                    refname: String::from("refs/heads/ci"),
+                    reason: String::from("pre-receive hook declined"),
+                },
+                Rejection {
+                    refname: String::from("refs/heads/old"),
+                    reason: String::from("remote failure"),
+                },
+            ]
+        );
+        assert!(rejections("Everything up-to-date\n").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path().join("git");
+        let remote = tmp.path().join("mirror.git");
+        let repo = Repository::init_bare(&git_dir).unwrap();
+        Repository::init_bare(&remote).unwrap();
+
+        let sig = git2::Signature::now("radicle", "radicle@localhost").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let head = "refs/namespaces/hnrk/refs/heads/master";
+        let commit = |message: &str, parents: &[&git2::Commit]| {
+            repo.commit(Some(head), &sig, &sig, message, &tree, parents)
+                .unwrap()
+        };
+        let mirror = Mirror {
+            url: remote.display().to_string(),
+            mode: MirrorMode::FastForwardOnly,
+        };
+        let push = |mirror: &Mirror| {
+            let mirror = mirror.clone();
+            let git_dir = git_dir.clone();
+
+            async move {
+                mirror
+                    .push(&git_dir, "hnrk", None, MIRROR_PUSH_TIMEOUT)
+                    .await
+            }
+        };
+        let mirrored = || {
+            let output = Command::new("git")
+                .arg("--git-dir")
+                .arg(&remote)
+                .args(["rev-parse", "refs/heads/master"])
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap().trim().to_owned()
+        };
+
+        let first = commit("First", &[]);
+        push(&mirror).await.unwrap();
+        assert_eq!(mirrored(), first.to_string());
+
+        // Diverging branches are only pushed in `force` mode.
+        repo.find_reference(head).unwrap().delete().unwrap();
+        let diverged = commit("Diverged", &[]);
+
+        match push(&mirror).await {
+            Err(Error::MirrorPush(reason)) => {
+                assert_eq!(reason, "refs/heads/master rejected (non-fast-forward)")
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(mirrored(), first.to_string());
+
+        let mirror = Mirror {
+            mode: MirrorMode::Force,
+            ..mirror
+        };
+        push(&mirror).await.unwrap();
+        assert_eq!(mirrored(), diverged.to_string());
+    }
+}
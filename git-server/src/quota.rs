@@ -0,0 +1,445 @@
+//! Repository quotas.
+//!
+//! Since all projects share a monorepo, a single project can otherwise fill the disk for
+//! everyone. Limits are configured in the monorepo's git config, either as defaults under
+//! `[quota]`, or per project and per peer:
+//!
+//! ```text
+//! [quota]
+//!     maxSize = 1g
+//!     maxPackSize = 100m
+//!     maxRefs = 1000
+//! [quota "project/hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo"]
+//!     maxSize = 5g
+//! [quota "peer/hyypw8z5g7tbui9ceh6tng58i1qk696isjnzix9fq9g41fzgjgqk8g"]
+//!     maxRefs = 100
+//! ```
+//!
+//! Project limits apply to everything stored under a project namespace, while peer limits apply
+//! to the refs pushed by a peer under its remote within a project namespace.
+//!
+//! The usage of each project and peer is cached in the monorepo after every push, along with the
+//! refs it was computed for. As long as a push only creates or fast-forwards refs, only the
+//! objects it adds are then walked, instead of the full history.
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use git2::{ObjectType, Odb, Oid, Repository};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use librad::PeerId;
+
+use crate::error::Error;
+
+/// Directory of the monorepo git dir where usage is cached.
+pub const USAGE_CACHE_DIR: &str = "quota-usage";
+
+/// Limits that apply to a project or peer. `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    /// Maximum total size of the objects reachable from the refs, in bytes.
+    pub max_size: Option<u64>,
+    /// Maximum size of the pack received in a single push, in bytes.
+    pub max_pack_size: Option<u64>,
+    /// Maximum number of refs.
+    pub max_refs: Option<u64>,
+}
+
+impl Limits {
+    /// Load the limits of a project.
+    pub fn project(config: &git2::Config, namespace: &str) -> Result<Self, Error> {
+        Self::load(config, &format!("quota.project/{}", namespace))
+    }
+
+    /// Load the limits of a peer.
+    pub fn peer(config: &git2::Config, peer: &PeerId) -> Result<Self, Error> {
+        Self::load(config, &format!("quota.peer/{}", peer.default_encoding()))
+    }
+
+    /// Whether no limit is set.
+    pub fn is_unlimited(&self) -> bool {
+        self.max_size.is_none() && self.max_pack_size.is_none() && self.max_refs.is_none()
+    }
+
+    /// Check usage against these limits, returning an error describing the first limit
+    /// exceeded.
+    pub fn check(&self, scope: &str, usage: &Usage, pack_size: u64) -> Result<(), Error> {
+        if let Some(max) = self.max_pack_size {
+            if pack_size > max {
+                return Err(Error::QuotaExceeded(format!(
+                    "{} pack size limit exceeded: pushed {} bytes, limit is {} bytes",
+                    scope, pack_size, max
+                )));
+            }
+        }
+        if let Some(max) = self.max_refs {
+            if usage.refs > max {
+                return Err(Error::QuotaExceeded(format!(
+                    "{} ref limit exceeded: {} refs, limit is {}",
+                    scope, usage.refs, max
+                )));
+            }
+        }
+        if let Some(max) = self.max_size {
+            if usage.size > max {
+                return Err(Error::QuotaExceeded(format!(
+                    "{} size limit exceeded: {} bytes, limit is {} bytes",
+                    scope, usage.size, max
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the limits of a config section, falling back to the `[quota]` defaults.
+    fn load(config: &git2::Config, section: &str) -> Result<Self, Error> {
+        Ok(Self {
+            max_size: Self::get(config, section, "maxSize")?,
+            max_pack_size: Self::get(config, section, "maxPackSize")?,
+            max_refs: Self::get(config, section, "maxRefs")?,
+        })
+    }
+
+    fn get(config: &git2::Config, section: &str, key: &str) -> Result<Option<u64>, Error> {
+        for name in &[format!("{}.{}", section, key), format!("quota.{}", key)] {
+            match config.get_i64(name) {
+                Ok(value) => return Ok(Some(value.max(0) as u64)),
+                Err(err) if err.code() == git2::ErrorCode::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Resource usage of a set of refs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    /// Total uncompressed size of the objects reachable from the refs, in bytes.
+    pub size: u64,
+    /// Number of refs.
+    pub refs: u64,
+}
+
+impl Usage {
+    /// Compute the usage of the given refs.
+    pub fn compute(repo: &Repository, refs: &BTreeMap<String, Oid>) -> Result<Self, Error> {
+        Ok(Self {
+            size: reachable_size(repo, refs.values().copied(), |_| false)?,
+            refs: refs.len() as u64,
+        })
+    }
+
+    /// Compute the usage of a scope's refs after a push, given its refs before the push, and
+    /// the object database as it was before the push, ie. without the quarantined objects.
+    ///
+    /// If the usage before the push is cached, and the push only creates or fast-forwards refs,
+    /// only the objects added by the push are walked. Otherwise, or if the objects stored before
+    /// the push can't be told apart, the usage is computed from scratch.
+    pub fn after_push(
+        repo: &Repository,
+        cache: &Cache,
+        scope: &str,
+        before: &BTreeMap<String, Oid>,
+        after: &BTreeMap<String, Oid>,
+        stored: Option<&Odb>,
+    ) -> Result<Self, Error> {
+        let cached = match (cache.get(scope, before), stored) {
+            (Some(usage), Some(stored)) if is_additive(repo, before, after)? => {
+                Some((usage, stored))
+            }
+            _ => None,
+        };
+        match cached {
+            Some((usage, stored)) => {
+                let tips = after
+                    .iter()
+                    .filter(|(name, oid)| before.get(*name) != Some(oid))
+                    .map(|(_, oid)| *oid);
+
+                Ok(Self {
+                    size: usage.size + reachable_size(repo, tips, |oid| stored.exists(oid))?,
+                    refs: after.len() as u64,
+                })
+            }
+            None => Self::compute(repo, after),
+        }
+    }
+}
+
+/// Usage cached by scope, eg. `<namespace>` or `<namespace>-<peer>`, along with a digest of the refs it was
+/// computed for.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cached {
+    refs: String,
+    usage: Usage,
+}
+
+impl Cache {
+    /// Open the cache of a monorepo.
+    pub fn new(git_dir: &Path) -> Self {
+        Self {
+            dir: git_dir.join(USAGE_CACHE_DIR),
+        }
+    }
+
+    /// Get the usage of the given refs, if it was cached for exactly these refs.
+    pub fn get(&self, scope: &str, refs: &BTreeMap<String, Oid>) -> Option<Usage> {
+        let bytes = fs::read(self.path(scope)).ok()?;
+        let cached: Cached = serde_json::from_slice(&bytes).ok()?;
+
+        (cached.refs == digest(refs)).then(|| cached.usage)
+    }
+
+    /// Cache the usage of the given refs.
+    pub fn put(
+        &self,
+        scope: &str,
+        refs: &BTreeMap<String, Oid>,
+        usage: Usage,
+    ) -> Result<(), Error> {
+        let path = self.path(scope);
+        let tmp = path.with_extension("json.tmp");
+        let cached = Cached {
+            refs: digest(refs),
+            usage,
+        };
+        let bytes =
+            serde_json::to_vec(&cached).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        fs::create_dir_all(&self.dir)?;
+        // Write atomically, so that concurrent pushes never see a partial entry. Since entries are
+        // only used if their refs match, a rejected or racing push can't make usage wrong.
+        fs::write(&tmp, bytes)?;
+        fs::rename(tmp, path)?;
+
+        Ok(())
+    }
+
+    fn path(&self, scope: &str) -> PathBuf {
+        self.dir.join(scope).with_extension("json")
+    }
+}
+
+/// Digest of a set of refs, to tell whether they changed.
+fn digest(refs: &BTreeMap<String, Oid>) -> String {
+    let mut hasher = Sha256::new();
+
+    for (name, oid) in refs {
+        hasher.update(name.as_bytes());
+        hasher.update(oid.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Whether going from `before` to `after` only creates or fast-forwards refs, so that every
+/// object reachable before is still reachable after.
+fn is_additive(
+    repo: &Repository,
+    before: &BTreeMap<String, Oid>,
+    after: &BTreeMap<String, Oid>,
+) -> Result<bool, Error> {
+    for (name, old) in before {
+        match after.get(name) {
+            Some(new) if new == old => {}
+            Some(new) => {
+                if !repo.graph_descendant_of(*new, *old)? {
+                    return Ok(false);
+                }
+            }
+            None => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// Get the direct refs under the given prefix, eg. `refs/namespaces/<namespace>/`.
+pub fn refs(repo: &Repository, prefix: &str) -> Result<BTreeMap<String, Oid>, Error> {
+    let mut refs = BTreeMap::new();
+
+    for r in repo.references_glob(&format!("{}*", prefix))? {
+        let r = r?;
+        if let (Some(name), Some(oid)) = (r.name(), r.target()) {
+            refs.insert(name.to_owned(), oid);
+        }
+    }
+    Ok(refs)
+}
+
+/// Total size of the files under a directory, eg. the quarantine directory of a push.
+pub fn dir_size(path: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+
+        if meta.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += meta.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Total uncompressed size of the objects reachable from the given tips. Objects for which
+/// `skip` returns `true` aren't counted, nor walked.
+fn reachable_size(
+    repo: &Repository,
+    tips: impl IntoIterator<Item = Oid>,
+    skip: impl Fn(Oid) -> bool,
+) -> Result<u64, Error> {
+    let odb = repo.odb()?;
+    let mut seen = HashSet::new();
+    let mut stack = tips.into_iter().collect::<Vec<_>>();
+    let mut size = 0;
+
+    while let Some(oid) = stack.pop() {
+        if !seen.insert(oid) || skip(oid) {
+            continue;
+        }
+        let (len, kind) = odb.read_header(oid)?;
+        size += len as u64;
+
+        match kind {
+            ObjectType::Commit => {
+                let commit = repo.find_commit(oid)?;
+
+                stack.push(commit.tree_id());
+                stack.extend(commit.parent_ids());
+            }
+            ObjectType::Tree => {
+                let tree = repo.find_tree(oid)?;
+
+                // Submodule entries point to commits in other repositories.
+                stack.extend(
+                    tree.iter()
+                        .filter(|e| e.kind() != Some(ObjectType::Commit))
+                        .map(|e| e.id()),
+                );
+            }
+            ObjectType::Tag => {
+                stack.push(repo.find_tag(oid)?.target_id());
+            }
+            _ => {}
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn commit(repo: &Repository, parent: Option<Oid>, content: &[u8]) -> Oid {
+        let sig = git2::Signature::now("radicle", "radicle@localhost").unwrap();
+        let blob = repo.blob(content).unwrap();
+        let mut tree = repo.treebuilder(None).unwrap();
+        tree.insert("README", blob, 0o100644).unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        let parents = parent
+            .map(|oid| repo.find_commit(oid).unwrap())
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        repo.commit(
+            None,
+            &sig,
+            &sig,
+            "",
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_usage_after_push() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stored = Repository::init_bare(tmp.path().join("stored")).unwrap();
+        // Received objects are written to a quarantine, which can read the stored objects.
+        let quarantine = tmp.path().join("quarantine");
+        Repository::init_bare(&quarantine).unwrap();
+        fs::create_dir_all(quarantine.join("objects/info")).unwrap();
+        fs::write(
+            quarantine.join("objects/info/alternates"),
+            stored.path().join("objects").to_str().unwrap(),
+        )
+        .unwrap();
+        let quarantine = Repository::open_bare(&quarantine).unwrap();
+        let cache = Cache::new(tmp.path());
+
+        let base = commit(&stored, None, &[b'a'; 512]);
+        let before = vec![("refs/heads/master".to_owned(), base)]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let usage = Usage::compute(&stored, &before).unwrap();
+        cache.put("project", &before, usage).unwrap();
+
+        // A fast-forward and a new ref only count the objects they add.
+        let head = commit(&quarantine, Some(base), &[b'b'; 512]);
+        let after = vec![
+            ("refs/heads/master".to_owned(), head),
+            ("refs/heads/next".to_owned(), head),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+        let expected = Usage::compute(&quarantine, &after).unwrap();
+        let actual = Usage::after_push(
+            &quarantine,
+            &cache,
+            "project",
+            &before,
+            &after,
+            Some(&stored.odb().unwrap()),
+        )
+        .unwrap();
+        assert_eq!(actual, expected);
+        assert!(actual.size > usage.size);
+        assert_eq!(actual.refs, 2);
+
+        // The push is rejected if it goes over the limit.
+        let limits = Limits {
+            max_size: Some(usage.size),
+            ..Limits::default()
+        };
+        assert!(limits.check("project", &usage, 0).is_ok());
+        assert!(matches!(
+            limits.check("project", &actual, 0),
+            Err(Error::QuotaExceeded(_))
+        ));
+
+        // When refs are deleted, the cached usage isn't used.
+        cache.put("project", &before, Usage::default()).unwrap();
+        let after = vec![("refs/heads/next".to_owned(), head)]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let actual = Usage::after_push(
+            &quarantine,
+            &cache,
+            "project",
+            &before,
+            &after,
+            Some(&stored.odb().unwrap()),
+        )
+        .unwrap();
+        assert_eq!(actual, Usage::compute(&quarantine, &after).unwrap());
+
+        // Nor when the refs differ from the ones it was cached for.
+        let other = vec![("refs/heads/master".to_owned(), head)]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(cache.get("project", &other), None);
+    }
+}
@@ -0,0 +1,264 @@
+//! Read-only replica mode.
+//!
+//! A replica follows a primary seed: it periodically fetches the canonical refs and identity refs
+//! of every project from the primary's monorepo, and serves clones from the synced data. Pushes
+//! are rejected, with a hint to push to the primary instead.
+//!
+//! The following refs of each namespace are replicated:
+//!
+//! * `refs/heads/*` and `refs/tags/*`, the canonical refs of the project.
+//! * `refs/rad/*`, ie. the project identity, its signed refs and the identities of its
+//!   delegates.
+//! * `HEAD`, which is re-created as a symbolic ref to the canonical default branch.
+//!
+//! Refs that are deleted on the primary are deleted on the replica.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use axum::body::BoxBody;
+use axum::http::{header, Response, StatusCode};
+use axum::response::IntoResponse;
+use git2::{Direction, FetchOptions, Oid, RemoteCallbacks, Repository};
+
+use shared::notify::{self, Notification, RefUpdate};
+
+use crate::error::Error;
+
+/// Outcome of a sync with the primary.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Synced {
+    /// Number of refs created or updated.
+    pub updated: usize,
+    /// Number of refs deleted.
+    pub deleted: usize,
+}
+
+/// Spawn the task that keeps the monorepo in sync with the primary.
+pub fn spawn(git_dir: PathBuf, url: String, interval: Duration) {
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(interval);
+
+        loop {
+            timer.tick().await;
+
+            let git_dir = git_dir.clone();
+            let url = url.clone();
+            let result = tokio::task::spawn_blocking(move || sync(&git_dir, &url)).await;
+
+            match result {
+                Ok(Ok(synced)) if synced.updated > 0 || synced.deleted > 0 => {
+                    tracing::info!(
+                        "Synced with primary: {} ref(s) updated, {} ref(s) deleted",
+                        synced.updated,
+                        synced.deleted
+                    );
+                }
+                Ok(Ok(_)) => {
+                    tracing::debug!("Synced with primary: up to date");
+                }
+                Ok(Err(err)) => {
+                    tracing::error!("Failed to sync with primary {}: {}", url, err);
+                }
+                Err(err) => {
+                    tracing::error!("Sync task failed: {}", err);
+                }
+            }
+        }
+    });
+}
+
+/// Sync the monorepo at the given path with the primary monorepo at the given URL.
+pub fn sync(git_dir: &Path, url: &str) -> Result<Synced, Error> {
+    let repo = Repository::open_bare(git_dir)?;
+    let mut remote = repo.remote_anonymous(url)?;
+    let mut synced = Synced::default();
+
+    let advertised = {
+        let conn = remote.connect_auth(Direction::Fetch, Some(callbacks()), None)?;
+
+        conn.list()?
+            .iter()
+            .filter(|head| is_replicated(head.name()))
+            .map(|head| (head.name().to_owned(), head.oid()))
+            .collect::<HashMap<_, _>>()
+    };
+
+    // Namespace `HEAD`s are advertised with the oid they point to, and are handled separately.
+    let (heads, refs): (HashMap<_, _>, HashMap<_, _>) = advertised
+        .into_iter()
+        .partition(|(name, _)| name.ends_with("/HEAD"));
+
+    if refs.is_empty() {
+        // Most likely a misconfigured primary; don't wipe the replica.
+        tracing::warn!("Primary {} has no projects, skipping sync", url);
+        return Ok(synced);
+    }
+
+    // Ref updates of each namespace, to notify other services of.
+    let mut updates: BTreeMap<&str, Vec<RefUpdate>> = BTreeMap::new();
+    let mut refspecs = Vec::new();
+
+    for (name, oid) in &refs {
+        let old = repo.refname_to_id(name).unwrap_or_else(|_| Oid::zero());
+
+        if old != *oid {
+            refspecs.push(format!("+{}:{}", name, name));
+            record(&mut updates, name, old, *oid);
+        }
+    }
+
+    if !refspecs.is_empty() {
+        let mut opts = FetchOptions::new();
+        opts.remote_callbacks(callbacks());
+
+        remote.fetch(&refspecs, Some(&mut opts), Some("sync (radicle replica)"))?;
+        synced.updated += refspecs.len();
+    }
+
+    // Delete the refs that no longer exist on the primary.
+    let local = repo
+        .references_glob("refs/namespaces/*")?
+        .filter_map(|r| r.ok())
+        .filter_map(|r| r.name().map(ToOwned::to_owned))
+        .filter(|name| is_replicated(name) && !name.ends_with("/HEAD"))
+        .collect::<Vec<_>>();
+
+    for name in &local {
+        if !refs.contains_key(name) {
+            let mut reference = repo.find_reference(name)?;
+            let old = reference.target().unwrap_or_else(Oid::zero);
+
+            reference.delete()?;
+            record(&mut updates, name, old, Oid::zero());
+            synced.deleted += 1;
+        }
+    }
+
+    // Point each namespace's `HEAD` to the branch it resolves to on the primary.
+    let namespaces = refs
+        .keys()
+        .chain(heads.keys())
+        .filter_map(|name| namespace(name))
+        .collect::<HashSet<_>>();
+
+    for ns in namespaces {
+        let head = format!("refs/namespaces/{}/HEAD", ns);
+        let target = heads.get(&head).and_then(|oid| {
+            let prefix = format!("refs/namespaces/{}/refs/heads/", ns);
+
+            refs.iter()
+                .filter(|(name, target)| name.starts_with(&prefix) && *target == oid)
+                .map(|(name, _)| name.as_str())
+                .min()
+        });
+        let current = repo
+            .find_reference(&head)
+            .ok()
+            .and_then(|r| r.symbolic_target().map(ToOwned::to_owned));
+
+        match (target, current) {
+            (Some(target), Some(current)) if target == current => {}
+            (Some(target), _) => {
+                repo.reference_symbolic(&head, target, true, "set-head (radicle replica)")?;
+                synced.updated += 1;
+            }
+            (None, Some(_)) => {
+                repo.find_reference(&head)?.delete()?;
+                synced.deleted += 1;
+            }
+            (None, None) => {}
+        }
+    }
+
+    for (namespace, updates) in updates {
+        let notification = Notification {
+            namespace: namespace.to_owned(),
+            updates,
+        };
+        if let Err(err) = notify::send(git_dir, &notification) {
+            tracing::warn!("Failed to send notification: {}", err);
+        }
+    }
+
+    Ok(synced)
+}
+
+/// Respond to a push to a replica. The initial ref discovery is redirected to the primary, if
+/// its URL is known, which git follows for the rest of the push.
+pub fn push_response(primary: Option<&str>, project_id: &str, path: &str) -> Response<BoxBody> {
+    match primary {
+        Some(primary) if path == "info/refs" => {
+            let location = format!(
+                "{}/{}/info/refs?service=git-receive-pack",
+                primary, project_id
+            );
+            (
+                StatusCode::TEMPORARY_REDIRECT,
+                [(header::LOCATION, location)],
+            )
+                .into_response()
+        }
+        Some(primary) => (
+            StatusCode::FORBIDDEN,
+            format!(
+                "This seed is a read-only replica, push to {}/{} instead\n",
+                primary, project_id
+            ),
+        )
+            .into_response(),
+        None => (
+            StatusCode::FORBIDDEN,
+            "This seed is a read-only replica, push to its primary instead\n",
+        )
+            .into_response(),
+    }
+}
+
+/// Record the update of a monorepo ref, relative to its namespace.
+fn record<'a>(updates: &mut BTreeMap<&'a str, Vec<RefUpdate>>, name: &'a str, old: Oid, new: Oid) {
+    if let Some((ns, rest)) = name
+        .strip_prefix("refs/namespaces/")
+        .and_then(|r| r.split_once('/'))
+    {
+        updates.entry(ns).or_default().push(RefUpdate {
+            name: rest.to_owned(),
+            old: old.to_string(),
+            new: new.to_string(),
+        });
+    }
+}
+
+/// Whether a monorepo ref is replicated, eg. `refs/namespaces/<namespace>/refs/heads/master`.
+fn is_replicated(name: &str) -> bool {
+    let rest = match name
+        .strip_prefix("refs/namespaces/")
+        .and_then(|r| r.split_once('/'))
+    {
+        Some((_, rest)) => rest,
+        None => return false,
+    };
+
+    rest == "HEAD"
+        || rest.starts_with("refs/heads/")
+        || rest.starts_with("refs/tags/")
+        || rest.starts_with("refs/rad/")
+}
+
+/// Get the namespace of a monorepo ref.
+fn namespace(name: &str) -> Option<&str> {
+    name.strip_prefix("refs/namespaces/")
+        .and_then(|r| r.split_once('/'))
+        .map(|(ns, _)| ns)
+}
+
+/// Callbacks used to connect to the primary. Credentials are taken from the SSH agent, for
+/// primaries that are reached over SSH.
+fn callbacks<'a>() -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+
+    callbacks.credentials(|_url, username, _allowed| {
+        git2::Cred::ssh_key_from_agent(username.unwrap_or("git"))
+    });
+    callbacks
+}
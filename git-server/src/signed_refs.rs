@@ -0,0 +1,74 @@
+//! Signing of the seed's refs.
+//!
+//! Pushed refs end up under the seed's local tree of the project, which must be signed with the
+//! seed's key for the project to be replicated over Radicle Link. Since the hooks don't have
+//! access to the seed's key, signing is done by the git-server, in the background, after each
+//! accepted push.
+use tokio::sync::mpsc;
+
+use librad::crypto::BoxedSigner;
+use librad::git;
+use librad::git::refs::{Refs, Updated};
+use librad::git::Urn;
+use librad::paths::Paths;
+
+use crate::error::Error;
+
+/// Spawn the signing task, returning a channel on which projects to sign are sent.
+pub fn spawn(paths: Paths, signer: BoxedSigner) -> mpsc::UnboundedSender<Urn> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Urn>();
+
+    tokio::spawn(async move {
+        while let Some(urn) = rx.recv().await {
+            // Coalesce requests for the same project, since pushes often come in bursts.
+            let mut urns = vec![urn];
+            while let Ok(urn) = rx.try_recv() {
+                if !urns.contains(&urn) {
+                    urns.push(urn);
+                }
+            }
+
+            let paths = paths.clone();
+            let signer = signer.clone();
+            let result = tokio::task::spawn_blocking(move || sign_all(&paths, signer, urns)).await;
+
+            if let Err(err) = result {
+                tracing::error!("Signing task failed: {}", err);
+            }
+        }
+    });
+
+    tx
+}
+
+fn sign_all(paths: &Paths, signer: BoxedSigner, urns: Vec<Urn>) {
+    let storage = match git::storage::Storage::open(paths, signer) {
+        Ok(storage) => storage,
+        Err(err) => {
+            tracing::error!("Failed to open storage for signing: {}", err);
+            return;
+        }
+    };
+
+    for urn in urns {
+        if let Err(err) = sign(&storage, &urn) {
+            tracing::error!("Failed to sign refs of {}: {}", urn, err);
+        }
+    }
+}
+
+/// Regenerate and sign the `rad/signed_refs` of the seed's tree of the given project.
+pub fn sign(storage: &git::storage::Storage, urn: &Urn) -> Result<(), Error> {
+    match Refs::update(storage, urn)? {
+        Updated::Updated { at, .. } => {
+            tracing::info!("Signed refs of {} updated to {}", urn, at);
+        }
+        Updated::Unchanged { .. } => {
+            tracing::debug!("Signed refs of {} unchanged", urn);
+        }
+        Updated::ConcurrentlyModified => {
+            tracing::warn!("Signed refs of {} were concurrently modified", urn);
+        }
+    }
+    Ok(())
+}
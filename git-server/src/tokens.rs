@@ -0,0 +1,216 @@
+//! Access tokens issued by the seed.
+//!
+//! Tokens are issued via the admin API and presented by clients either as bearer tokens, or as
+//! the password of HTTP Basic credentials. Only a hash of each token is stored, in the monorepo
+//! git directory.
+//!
+//! Tokens that are scoped to a project and a peer, and have ref patterns, can be used to push
+//! without a signed push certificate, eg. from CI. Ref patterns are matched against the refs of
+//! the peer, eg. `heads/main` or `heads/release/*`.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng as _;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use librad::PeerId;
+
+use shared::access::Principal;
+use shared::lock::FileLock;
+
+use crate::error::Error;
+
+/// File in the monorepo git directory where issued tokens are stored.
+pub const TOKENS_FILE: &str = "tokens.json";
+
+/// What a token grants access to.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Scope {
+    /// Project the token grants access to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    /// Peer the token authenticates, granting the access of that peer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<PeerId>,
+    /// Patterns of the peer's refs the token may push to. Tokens without ref patterns can't
+    /// push.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub refs: Vec<String>,
+    /// When the token expires, in seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+/// An issued token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Token {
+    /// Public identifier of the token, used to revoke it.
+    pub id: String,
+    /// SHA-256 hash of the token secret.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+    /// What the token grants access to.
+    #[serde(flatten)]
+    pub scope: Scope,
+    /// When the token was issued, in seconds since the epoch.
+    pub created: u64,
+}
+
+impl Token {
+    /// The token, without its hash, eg. for listing.
+    pub fn redacted(&self) -> Self {
+        Self {
+            hash: String::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the token has expired.
+    pub fn is_expired(&self) -> bool {
+        matches!(self.scope.expires, Some(expires) if expires <= now())
+    }
+
+    /// The principal authenticated by the token, when reading projects.
+    pub fn principal(&self) -> Principal<'_> {
+        match (&self.scope.project, &self.scope.peer) {
+            // Tokens scoped to a project only grant access to that project.
+            (Some(project), _) => Principal::Project(project),
+            (None, Some(peer)) => Principal::Peer(peer),
+            (None, None) => Principal::Anonymous,
+        }
+    }
+
+    /// Whether the token may push to the given project.
+    pub fn can_push(&self, namespace: &str) -> bool {
+        self.scope.project.as_deref() == Some(namespace)
+            && self.scope.peer.is_some()
+            && !self.scope.refs.is_empty()
+    }
+
+    /// Check that the token may update the given ref, eg. `refs/remotes/<peer>/heads/main`.
+    pub fn authorize_ref(&self, refname: &str) -> Result<(), Error> {
+        let (peer_id, rest) =
+            crate::parse_ref(refname).map_err(|_| Error::InvalidRefPushed(refname.to_owned()))?;
+
+        if self.scope.peer != Some(peer_id) {
+            return Err(Error::Unauthorized("token is not valid for this peer"));
+        }
+        // Identity refs can only be updated with a signed push.
+        if rest.starts_with("rad/") {
+            return Err(Error::Unauthorized("token can't update identity refs"));
+        }
+        if !self.scope.refs.iter().any(|p| matches(p, &rest)) {
+            return Err(Error::InvalidRefPushed(refname.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// Load the issued tokens.
+pub fn load(git_dir: &Path) -> Result<Vec<Token>, Error> {
+    match fs::read(git_dir.join(TOKENS_FILE)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|_| Error::InvalidConfig("tokens file is corrupted")),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Find the unexpired token matching a secret presented by a client.
+pub fn find(git_dir: &Path, secret: &str) -> Result<Option<Token>, Error> {
+    let hash = hash(secret);
+
+    Ok(load(git_dir)?
+        .into_iter()
+        .find(|t| t.hash == hash && !t.is_expired()))
+}
+
+/// Get an unexpired token by id.
+pub fn get(git_dir: &Path, id: &str) -> Result<Option<Token>, Error> {
+    Ok(load(git_dir)?
+        .into_iter()
+        .find(|t| t.id == id && !t.is_expired()))
+}
+
+/// Issue a new token, returning it along with its secret. The secret is not stored, and can't
+/// be recovered. The tokens file is locked while it is updated, so that concurrent updates
+/// aren't lost.
+pub fn issue(git_dir: &Path, scope: Scope) -> Result<(Token, String), Error> {
+    let mut rng = rand::thread_rng();
+    let id = format!("{:016x}", rng.gen::<u64>());
+    let secret = (0..4)
+        .map(|_| format!("{:016x}", rng.gen::<u64>()))
+        .collect::<String>();
+    let token = Token {
+        id,
+        hash: hash(&secret),
+        scope,
+        created: now(),
+    };
+    let _lock = FileLock::exclusive(&git_dir.join(TOKENS_FILE))?;
+    let mut tokens = load(git_dir)?;
+
+    tokens.push(token.clone());
+    write(git_dir, &tokens)?;
+
+    Ok((token, secret))
+}
+
+/// Revoke a token, given its id.
+pub fn revoke(git_dir: &Path, id: &str) -> Result<Token, Error> {
+    let _lock = FileLock::exclusive(&git_dir.join(TOKENS_FILE))?;
+    let mut tokens = load(git_dir)?;
+    let index = tokens
+        .iter()
+        .position(|t| t.id == id)
+        .ok_or(Error::NotFound)?;
+    let token = tokens.remove(index);
+
+    write(git_dir, &tokens)?;
+
+    Ok(token)
+}
+
+/// Match a ref against a pattern, where `*` matches any sequence of characters.
+fn matches(pattern: &str, refname: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == refname,
+        Some((prefix, rest)) => {
+            let refname = match refname.strip_prefix(prefix) {
+                Some(refname) => refname,
+                None => return false,
+            };
+            (0..=refname.len())
+                .filter(|i| refname.is_char_boundary(*i))
+                .any(|i| matches(rest, &refname[i..]))
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+fn write(git_dir: &Path, tokens: &[Token]) -> Result<(), Error> {
+    let path = git_dir.join(TOKENS_FILE);
+    let tmp = path.with_extension("json.tmp");
+    let bytes =
+        serde_json::to_vec_pretty(tokens).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    fs::write(&tmp, bytes)?;
+    fs::rename(tmp, path)?;
+
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use axum::http::{header, StatusCode};
+use git2::{Oid, Repository};
+use tempfile::TempDir;
+
+use radicle_git_server::replica;
+
+const NAMESPACE: &str = "hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo";
+const PEER: &str = "hyypw8z5g7tbui9ceh6tng58i1qk696isjnzix9fq9g41fzgjgqk8g";
+
+/// Create an empty bare repository in a temporary directory, which is removed once dropped.
+fn monorepo() -> (TempDir, Repository) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = Repository::init_bare(dir.path()).unwrap();
+
+    (dir, repo)
+}
+
+/// Create a commit on its own.
+fn commit(repo: &Repository, message: &str) -> Oid {
+    let sig = git2::Signature::now("radicle", "radicle@localhost").unwrap();
+    let tree = repo
+        .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+        .unwrap();
+
+    repo.commit(None, &sig, &sig, message, &tree, &[]).unwrap()
+}
+
+fn target(repo: &Repository, name: &str) -> Option<Oid> {
+    repo.find_reference(&format!("refs/namespaces/{}/{}", NAMESPACE, name))
+        .ok()
+        .and_then(|r| r.target())
+}
+
+#[test]
+fn test_sync() {
+    let (primary_tmp, primary) = monorepo();
+    let (replica_tmp, replica) = monorepo();
+    let replica_dir = replica_tmp.path();
+    let url = primary_tmp.path().to_str().unwrap();
+    let refs = [
+        "refs/heads/master",
+        "refs/heads/feature",
+        "refs/tags/v1",
+        "refs/rad/id",
+        "refs/rad/signed_refs",
+    ];
+
+    for name in refs {
+        let oid = commit(&primary, name);
+        let name = format!("refs/namespaces/{}/{}", NAMESPACE, name);
+        primary.reference(&name, oid, true, "").unwrap();
+    }
+    let remote = format!("refs/remotes/{}/heads/master", PEER);
+    let oid = commit(&primary, &remote);
+    primary
+        .reference(
+            &format!("refs/namespaces/{}/{}", NAMESPACE, remote),
+            oid,
+            true,
+            "",
+        )
+        .unwrap();
+    primary
+        .reference_symbolic(
+            &format!("refs/namespaces/{}/HEAD", NAMESPACE),
+            &format!("refs/namespaces/{}/refs/heads/master", NAMESPACE),
+            true,
+            "",
+        )
+        .unwrap();
+
+    let synced = replica::sync(replica_dir, url).unwrap();
+    assert_eq!(synced.updated, refs.len() + 1);
+    assert_eq!(synced.deleted, 0);
+
+    // The canonical and identity refs are copied, but not the refs of remotes.
+    for name in refs {
+        assert_eq!(target(&replica, name), target(&primary, name), "{}", name);
+    }
+    assert_eq!(target(&replica, &remote), None);
+
+    let head = replica
+        .find_reference(&format!("refs/namespaces/{}/HEAD", NAMESPACE))
+        .unwrap();
+    assert_eq!(
+        head.symbolic_target(),
+        Some(format!("refs/namespaces/{}/refs/heads/master", NAMESPACE).as_str())
+    );
+
+    // Syncing again is a no-op.
+    let synced = replica::sync(replica_dir, url).unwrap();
+    assert_eq!(synced.updated + synced.deleted, 0);
+
+    // Refs deleted on the primary are deleted on the replica.
+    primary
+        .find_reference(&format!("refs/namespaces/{}/refs/heads/feature", NAMESPACE))
+        .unwrap()
+        .delete()
+        .unwrap();
+
+    let synced = replica::sync(replica_dir, url).unwrap();
+    assert_eq!(synced.deleted, 1);
+    assert_eq!(target(&replica, "refs/heads/feature"), None);
+    assert!(target(&replica, "refs/heads/master").is_some());
+}
+
+#[tokio::test]
+async fn test_push_response() {
+    let project = format!("{}.git", NAMESPACE);
+
+    // Ref discovery for a push is redirected to the primary.
+    let response = replica::push_response(Some("https://seed.example.com"), &project, "info/refs");
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        response.headers()[header::LOCATION],
+        format!(
+            "https://seed.example.com/{}/info/refs?service=git-receive-pack",
+            project
+        )
+    );
+
+    // Pushes that reach the replica anyway are rejected, with a hint.
+    let response = replica::push_response(
+        Some("https://seed.example.com"),
+        &project,
+        "git-receive-pack",
+    );
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        body,
+        format!(
+            "This seed is a read-only replica, push to https://seed.example.com/{} instead\n",
+            project
+        )
+    );
+
+    let response = replica::push_response(None, &project, "info/refs");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
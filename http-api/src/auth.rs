@@ -1,37 +1,188 @@
 use std::convert::TryFrom;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 
+use axum::async_trait;
+use axum::extract::{FromRequest, RequestParts};
+use axum::http::header::{AUTHORIZATION, COOKIE};
+use axum::http::uri::{Authority, Uri};
+use axum::http::HeaderMap;
 use chrono::{DateTime, Utc};
-use ethers_core::types::{Signature, H160};
+use ethers_core::types::{Bytes, H160};
+use ethers_core::utils::{hex, to_checksum};
+use rand::rngs::OsRng;
+use rand::RngCore as _;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use librad::git::Urn;
 
 use crate::error::Error;
+use crate::{Context, SessionId};
+
+/// Name of the cookie holding the session token, when sessions are sent as cookies.
+pub const SESSION_COOKIE: &str = "radicle-session";
+/// Log of changes made on behalf of signed-in users, in the monorepo git directory.
+pub const ATTRIBUTION_LOG_FILE: &str = "attributions.log";
+/// Default lifetime of signed-in sessions.
+pub const DEFAULT_SESSION_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Sign-In with Ethereum settings.
+#[derive(Debug, Clone)]
+pub struct SiweConfig {
+    /// Domains that messages may be signed for. Signing in fails if there are none.
+    pub domains: Vec<Authority>,
+    /// URIs that messages may be signed for. Any URI is accepted if empty.
+    pub uris: Vec<String>,
+    /// Chain IDs that messages may be signed for. Any chain is accepted if empty.
+    pub chain_ids: Vec<u64>,
+    /// Statement that messages must include, if any.
+    pub statement: Option<String>,
+    /// Maximum lifetime of a signed-in session, before it has to be refreshed.
+    pub session_lifetime: Duration,
+    /// Ethereum JSON-RPC endpoint used to verify contract wallet signatures, if any.
+    pub eth_rpc_url: Option<Uri>,
+}
+
+impl Default for SiweConfig {
+    fn default() -> Self {
+        Self {
+            domains: Vec::new(),
+            uris: Vec::new(),
+            chain_ids: Vec::new(),
+            statement: None,
+            session_lifetime: DEFAULT_SESSION_LIFETIME,
+            eth_rpc_url: None,
+        }
+    }
+}
+
+impl SiweConfig {
+    /// Check that a message was signed for this seed.
+    pub fn check(&self, message: &siwe::Message) -> Result<(), SigninError> {
+        if !self.domains.contains(&message.domain) {
+            return Err(SigninError::DomainNotAccepted);
+        }
+        let uri = message.uri.to_string();
+        if !self.uris.is_empty()
+            && !self
+                .uris
+                .iter()
+                .any(|u| u.trim_end_matches('/') == uri.trim_end_matches('/'))
+        {
+            return Err(SigninError::UriNotAccepted);
+        }
+        if !self.chain_ids.is_empty() && !self.chain_ids.contains(&message.chain_id) {
+            return Err(SigninError::ChainNotAccepted);
+        }
+        if let Some(statement) = &self.statement {
+            if message.statement.as_deref() != Some(statement.as_str()) {
+                return Err(SigninError::StatementMismatch);
+            }
+        }
+        Ok(())
+    }
+
+    /// Lifetime of signed-in sessions.
+    pub fn session_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::from_std(self.session_lifetime)
+            .unwrap_or_else(|_| chrono::Duration::max_value())
+    }
+}
+
+/// Reason for rejecting a sign-in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum SigninError {
+    #[error("the message nonce does not match the session")]
+    InvalidNonce,
+    #[error("the message domain is not accepted")]
+    DomainNotAccepted,
+    #[error("the message URI is not accepted")]
+    UriNotAccepted,
+    #[error("the message chain ID is not accepted")]
+    ChainNotAccepted,
+    #[error("the message statement does not match")]
+    StatementMismatch,
+    #[error("the message signature is invalid")]
+    InvalidSignature,
+    #[error("the message is expired or not yet valid")]
+    NotCurrentlyValid,
+    #[error("the session is already signed in")]
+    AlreadySignedIn,
+}
+
+impl SigninError {
+    /// Error code returned to clients.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidNonce => "invalidNonce",
+            Self::DomainNotAccepted => "domainNotAccepted",
+            Self::UriNotAccepted => "uriNotAccepted",
+            Self::ChainNotAccepted => "chainNotAccepted",
+            Self::StatementMismatch => "statementMismatch",
+            Self::InvalidSignature => "invalidSignature",
+            Self::NotCurrentlyValid => "notCurrentlyValid",
+            Self::AlreadySignedIn => "alreadySignedIn",
+        }
+    }
+}
 
 #[derive(Deserialize, Serialize)]
 pub struct AuthRequest {
     pub message: String,
+    /// Signature of the message. Contract wallet signatures may be of any length.
     #[serde(deserialize_with = "deserialize_signature")]
-    pub signature: Signature,
+    pub signature: Bytes,
 }
 
-fn deserialize_signature<'de, D>(deserializer: D) -> Result<Signature, D::Error>
+fn deserialize_signature<'de, D>(deserializer: D) -> Result<Bytes, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
     let buf = String::deserialize(deserializer)?;
-    Signature::from_str(&buf).map_err(serde::de::Error::custom)
+    hex::decode(buf.trim_start_matches("0x"))
+        .map(Bytes::from)
+        .map_err(serde::de::Error::custom)
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
 pub enum AuthState {
-    Authorized(Session),
+    /// The session was signed in, and is valid until `expires_at`.
+    #[serde(rename_all = "camelCase")]
+    Authorized {
+        session: Session,
+        expires_at: DateTime<Utc>,
+        /// Hash of the secret of the session's token, see [`Token`]. Sessions stored without
+        /// one can't be used.
+        #[serde(default)]
+        secret_hash: String,
+    },
+    #[serde(rename_all = "camelCase")]
     Unauthorized {
         nonce: String,
         expiration_time: DateTime<Utc>,
     },
 }
 
+impl AuthState {
+    /// Whether the session expired at the given time.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        match self {
+            AuthState::Authorized { expires_at, .. } => now >= *expires_at,
+            AuthState::Unauthorized {
+                expiration_time, ..
+            } => now >= *expiration_time,
+        }
+    }
+}
+
 // We copy the implementation of siwe::Message here to derive Serialization and Debug
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Session {
     pub domain: String,
@@ -46,6 +197,17 @@ pub struct Session {
     pub resources: Vec<String>,
 }
 
+impl Session {
+    /// When a session signed in or refreshed at the given time expires: after the given
+    /// lifetime, but never later than the expiration time of the signed message.
+    pub fn expiry(&self, now: DateTime<Utc>, lifetime: chrono::Duration) -> DateTime<Utc> {
+        match self.expiration_time {
+            Some(exp) => exp.min(now + lifetime),
+            None => now + lifetime,
+        }
+    }
+}
+
 impl TryFrom<siwe::Message> for Session {
     type Error = Error;
 
@@ -67,8 +229,269 @@ impl TryFrom<siwe::Message> for Session {
     }
 }
 
+/// The credential of a signed-in session, given to the client when signing in, as
+/// `<id>.<secret>`.
+///
+/// Session ids appear in URLs, and so in request logs, so they can't be used as credentials
+/// by themselves. Only a hash of the secret is stored, so that stored sessions can't be used
+/// either.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub id: SessionId,
+    pub secret: String,
+}
+
+impl Token {
+    /// Generate a new token for the given session.
+    pub fn generate(id: SessionId) -> Self {
+        Self {
+            id,
+            secret: random_hex(32),
+        }
+    }
+
+    /// Hash of the secret, as stored with the session.
+    pub fn secret_hash(&self) -> String {
+        hex::encode(Sha256::digest(self.secret.as_bytes()))
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.id, self.secret)
+    }
+}
+
+impl FromStr for Token {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('.') {
+            Some((id, secret)) if !id.is_empty() && !secret.is_empty() => Ok(Self {
+                id: id.to_owned(),
+                secret: secret.to_owned(),
+            }),
+            _ => Err(Error::Unauthorized("Invalid session token")),
+        }
+    }
+}
+
+/// Generate a hex-encoded random value of the given number of bytes, eg. for session ids.
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    OsRng.fill_bytes(&mut bytes);
+
+    hex::encode(bytes)
+}
+
+/// An authorized session, required by handlers with this extractor.
+///
+/// The session token is taken from the `Authorization: Bearer <token>` header, or from the
+/// session cookie.
+pub struct AuthSession {
+    pub id: SessionId,
+    pub token: Token,
+    pub session: Session,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for AuthSession {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let ctx = req
+            .extensions()
+            .get::<Context>()
+            .cloned()
+            .expect("context extension is set");
+        let token: Token = session_token(req.headers())
+            .ok_or(Error::Unauthorized("Missing session"))?
+            .parse()?;
+
+        match ctx.sessions.get(&token.id).await? {
+            Some(AuthState::Authorized { secret_hash, .. })
+                if secret_hash != token.secret_hash() =>
+            {
+                Err(Error::Unauthorized("Invalid session"))
+            }
+            Some(AuthState::Authorized {
+                session,
+                expires_at,
+                ..
+            }) if Utc::now() < expires_at => Ok(Self {
+                id: token.id.clone(),
+                token,
+                session,
+                expires_at,
+            }),
+            Some(AuthState::Authorized { .. }) => Err(Error::Unauthorized("Session expired")),
+            Some(AuthState::Unauthorized { .. }) => {
+                Err(Error::Unauthorized("Session not signed in"))
+            }
+            None => Err(Error::Unauthorized("Invalid session")),
+        }
+    }
+}
+
+impl AuthSession {
+    /// The signed-in account, as a CAIP-10 account id, eg. `eip155:1:0xab16…`.
+    pub fn account(&self) -> String {
+        format!(
+            "eip155:{}:{}",
+            self.session.chain_id,
+            to_checksum(&self.session.address, None)
+        )
+    }
+}
+
+/// Attribution of a change made by the seed on behalf of a signed-in user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribution {
+    /// CAIP-10 id of the signed-in account.
+    pub account: String,
+    /// Radicle person linked to the account, if any.
+    pub person: Option<Urn>,
+}
+
+impl Attribution {
+    /// Trailer holding the account id.
+    const ON_BEHALF_OF: &'static str = "On-Behalf-Of: ";
+    /// Trailer holding the linked person, if any.
+    const PERSON: &'static str = "Person: ";
+
+    /// Append the attribution to a text, as trailers. Texts holding attribution trailers of their
+    /// own are rejected, so that the trailers of a text written by the seed can't be forged.
+    pub fn sign(&self, text: &str) -> Result<String, Error> {
+        let forged = text.lines().any(|l| {
+            let l = l.trim_start();
+            l.starts_with(Self::ON_BEHALF_OF.trim_end()) || l.starts_with(Self::PERSON.trim_end())
+        });
+        if forged {
+            return Err(Error::BadRequest(String::from(
+                "text must not contain attribution trailers",
+            )));
+        }
+        let mut signed = format!(
+            "{}\n\n{}{}",
+            text.trim_end(),
+            Self::ON_BEHALF_OF,
+            self.account
+        );
+
+        if let Some(person) = &self.person {
+            signed.push_str(&format!("\n{}{}", Self::PERSON, person));
+        }
+        Ok(signed)
+    }
+
+    /// Get the attribution of a text signed with [`Attribution::sign`]. Only the trailers of the
+    /// last paragraph are considered. They can only be trusted if the seed wrote the text.
+    pub fn parse(text: &str) -> Option<Self> {
+        let (_, trailers) = text.rsplit_once("\n\n")?;
+        let mut lines = trailers.lines();
+        let account = lines.next()?.strip_prefix(Self::ON_BEHALF_OF)?.to_owned();
+        let person = match lines.next() {
+            Some(line) => Some(Urn::from_str(line.strip_prefix(Self::PERSON)?).ok()?),
+            None => None,
+        };
+        if lines.next().is_some() {
+            return None;
+        }
+        Some(Self { account, person })
+    }
+
+    /// Record a change made on behalf of the signed-in user in the monorepo's attribution log,
+    /// for changes that don't hold a text to sign, such as state changes.
+    pub fn record(
+        &self,
+        git_dir: &Path,
+        project: &Urn,
+        object: &str,
+        action: &str,
+    ) -> Result<(), std::io::Error> {
+        let entry = serde_json::json!({
+            "timestamp": Utc::now().timestamp(),
+            "project": project.to_string(),
+            "object": object,
+            "action": action,
+            "account": self.account,
+            "person": self.person.as_ref().map(|p| p.to_string()),
+        });
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(git_dir.join(ATTRIBUTION_LOG_FILE))?;
+
+        writeln!(file, "{}", entry)
+    }
+}
+
+/// Get the session token of a request, from its `Authorization` header or session cookie.
+fn session_token(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+
+    if let Some(id) = bearer {
+        return Some(id.trim());
+    }
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, id)| id)
+}
+
 #[cfg(test)]
 mod test {
+    #[test]
+    fn test_token() {
+        use super::Token;
+
+        let token = Token::generate(super::random_hex(32));
+        let parsed: Token = token.to_string().parse().unwrap();
+
+        assert_eq!(parsed, token);
+        assert_eq!(parsed.secret_hash(), token.secret_hash());
+        assert_ne!(Token::generate(token.id.clone()), token);
+        assert!(token.id.parse::<Token>().is_err());
+        assert!(format!("{}.", token.id).parse::<Token>().is_err());
+    }
+
+    #[test]
+    fn test_attribution() {
+        use super::Attribution;
+
+        let attribution = Attribution {
+            account: String::from("eip155:1:0xe5A12547fe4E872D192E3eCecb76F2Ce1aeA4946"),
+            person: Some(
+                "rad:git:hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo"
+                    .parse()
+                    .unwrap(),
+            ),
+        };
+        let signed = attribution.sign("Broken link.\n").unwrap();
+
+        assert_eq!(Attribution::parse(&signed), Some(attribution.clone()));
+        assert_eq!(Attribution::parse("Broken link."), None);
+
+        // Texts can't add trailers of their own.
+        assert!(attribution
+            .sign("Broken link.\n\nOn-Behalf-Of: eip155:1:0x0")
+            .is_err());
+        assert!(attribution
+            .sign("Broken link.\n Person: rad:git:hnrk")
+            .is_err());
+
+        // Only the last paragraph is considered.
+        assert_eq!(Attribution::parse(&format!("{}\n\nThanks!", signed)), None);
+    }
+
     #[test]
     fn test_auth_request_de() {
         let json = serde_json::json!({
@@ -78,4 +501,40 @@ mod test {
 
         let _req: super::AuthRequest = serde_json::from_value(json).unwrap();
     }
+
+    #[test]
+    fn test_siwe_config_check() {
+        use super::{SigninError, SiweConfig};
+
+        let message: siwe::Message = "service.org wants you to sign in with your Ethereum account:
+0xe5A12547fe4E872D192E3eCecb76F2Ce1aeA4946
+
+I accept the ServiceOrg Terms of Service: https://service.org/tos
+
+URI: https://service.org/login
+Version: 1
+Chain ID: 1
+Nonce: 32891756
+Issued At: 2021-09-30T16:25:24Z"
+            .parse()
+            .unwrap();
+        let mut config = SiweConfig {
+            domains: vec!["service.org".parse().unwrap()],
+            ..SiweConfig::default()
+        };
+        assert_eq!(config.check(&message), Ok(()));
+
+        config.chain_ids = vec![5];
+        assert_eq!(config.check(&message), Err(SigninError::ChainNotAccepted));
+
+        config.chain_ids = vec![1, 5];
+        config.uris = vec![String::from("https://service.org/login/")];
+        assert_eq!(config.check(&message), Ok(()));
+
+        config.statement = Some(String::from("Sign in"));
+        assert_eq!(config.check(&message), Err(SigninError::StatementMismatch));
+
+        config.domains = vec!["seed.service.org".parse().unwrap()];
+        assert_eq!(config.check(&message), Err(SigninError::DomainNotAccepted));
+    }
 }
@@ -0,0 +1,367 @@
+//! Code search within a project revision.
+//!
+//! The files of a revision's directory are searched line by line and in path order, for a
+//! literal string or a regular expression, until a page of matching files is found. Binary files
+//! and files larger than [`MAX_FILE_SIZE`] are skipped.
+//!
+//! Searches of the head of a project's default branch use a trigram index: a file can only
+//! contain a literal if it contains all of its three-byte sequences, so only those files are
+//! searched. Indexes are kept on disk, one per project, and rebuilt once the head has moved.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use either::Either;
+use librad::git::Urn;
+use radicle_source::surf::file_system::{Directory, DirectoryContents};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Directory of the trigram indexes, in the monorepo.
+pub const INDEX_DIR: &str = "code-search";
+/// Files larger than this many bytes aren't searched.
+pub const MAX_FILE_SIZE: usize = 1024 * 1024;
+/// Maximum number of matching lines returned per file.
+pub const MAX_MATCHES_PER_FILE: usize = 100;
+/// Number of context lines around matches, if not given.
+pub const DEFAULT_CONTEXT: usize = 2;
+/// Maximum number of context lines around matches.
+pub const MAX_CONTEXT: usize = 10;
+/// Number of indexes kept in memory.
+pub const CACHE_CAPACITY: usize = 16;
+/// Maximum size of a compiled regular expression, so that queries stay cheap.
+const REGEX_SIZE_LIMIT: usize = 1024 * 1024;
+/// Files with a NUL byte in this many first bytes are binary, like for git.
+const BINARY_CHECK_SIZE: usize = 8000;
+
+/// A search query.
+#[derive(Debug)]
+pub struct Query {
+    regex: Regex,
+    /// The query, if it is a literal string.
+    literal: Option<String>,
+    /// Only search files under this path.
+    path: Option<String>,
+    /// Number of context lines around matches.
+    context: usize,
+}
+
+impl Query {
+    /// Create a query for a literal string, or a regular expression.
+    pub fn new(
+        q: &str,
+        regex: bool,
+        path: Option<&str>,
+        context: Option<usize>,
+    ) -> Result<Self, Error> {
+        if q.is_empty() {
+            return Err(Error::BadRequest(String::from("query is empty")));
+        }
+        let pattern = if regex {
+            q.to_owned()
+        } else {
+            regex::escape(q)
+        };
+        let compiled = RegexBuilder::new(&pattern)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .build()
+            .map_err(|e| Error::BadRequest(format!("invalid regex: {}", e)))?;
+        // Regular expressions without special characters are literals too.
+        let literal = (!regex || regex::escape(q) == q).then(|| q.to_owned());
+
+        Ok(Self {
+            regex: compiled,
+            literal,
+            path: path
+                .map(|p| p.trim_start_matches('/').to_owned())
+                .filter(|p| !p.is_empty()),
+            context: context.unwrap_or(DEFAULT_CONTEXT).min(MAX_CONTEXT),
+        })
+    }
+
+    /// Trigrams that matching files must contain, if known.
+    fn trigrams(&self) -> Option<HashSet<u32>> {
+        match &self.literal {
+            Some(literal) if literal.len() >= 3 => Some(trigrams(literal.as_bytes())),
+            _ => None,
+        }
+    }
+
+    /// Whether any line of a file matches.
+    fn is_match(&self, text: &str) -> bool {
+        text.lines().any(|line| self.regex.is_match(line))
+    }
+
+    /// Find the matching lines of a file.
+    fn matches(&self, text: &str) -> Vec<LineMatch> {
+        let lines = text.lines().collect::<Vec<_>>();
+
+        lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| self.regex.is_match(line))
+            .take(MAX_MATCHES_PER_FILE)
+            .map(|(ix, line)| LineMatch {
+                line: ix + 1,
+                text: (*line).to_owned(),
+                before: lines[ix.saturating_sub(self.context)..ix]
+                    .iter()
+                    .map(|l| (*l).to_owned())
+                    .collect(),
+                after: lines[ix + 1..(ix + 1 + self.context).min(lines.len())]
+                    .iter()
+                    .map(|l| (*l).to_owned())
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+/// A file with matching lines.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileMatch {
+    pub path: String,
+    pub matches: Vec<LineMatch>,
+}
+
+/// A matching line, with the lines around it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LineMatch {
+    /// Line number, starting at `1`.
+    pub line: usize,
+    pub text: String,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+/// Search the files of a revision's directory, only searching the files selected by the index,
+/// if given. Files are returned by path, skipping the first `skip` matching files, and the search
+/// stops once `take` files match.
+pub fn search(
+    root: &Directory,
+    query: &Query,
+    index: Option<&TrigramIndex>,
+    skip: usize,
+    take: usize,
+) -> Vec<FileMatch> {
+    let candidates = match (index, query.trigrams()) {
+        (Some(index), Some(trigrams)) => Some(index.candidates(&trigrams)),
+        _ => None,
+    };
+    let mut files = Vec::new();
+    let mut skipped = 0;
+
+    if take == 0 {
+        return files;
+    }
+    walk(root, "", &mut |path, content| {
+        if matches!(&candidates, Some(c) if !c.contains(path.as_str()))
+            || matches!(&query.path, Some(prefix) if !path.starts_with(prefix.as_str()))
+            || !is_searchable(content)
+        {
+            return true;
+        }
+        let text = String::from_utf8_lossy(content);
+
+        // Files of the previous pages only need to match.
+        if skipped < skip {
+            if query.is_match(&text) {
+                skipped += 1;
+            }
+            return true;
+        }
+        let matches = query.matches(&text);
+
+        if !matches.is_empty() {
+            files.push(FileMatch { path, matches });
+        }
+        files.len() < take
+    });
+
+    files
+}
+
+/// Index of the trigrams of a revision's files.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrigramIndex {
+    commit: String,
+    /// Path of the indexed files.
+    files: Vec<String>,
+    /// Files containing each trigram.
+    trigrams: HashMap<u32, Vec<u32>>,
+}
+
+impl TrigramIndex {
+    /// Index the files of a commit's directory.
+    pub fn build(root: &Directory, commit: &str) -> Self {
+        let mut files = Vec::new();
+        let mut index: HashMap<u32, Vec<u32>> = HashMap::new();
+
+        walk(root, "", &mut |path, content| {
+            if is_searchable(content) {
+                let id = files.len() as u32;
+
+                for trigram in trigrams(content) {
+                    index.entry(trigram).or_default().push(id);
+                }
+                files.push(path);
+            }
+            true
+        });
+
+        Self {
+            commit: commit.to_owned(),
+            files,
+            trigrams: index,
+        }
+    }
+
+    /// Get the paths of the files containing all the given trigrams.
+    fn candidates(&self, trigrams: &HashSet<u32>) -> HashSet<&str> {
+        let mut ids: Option<HashSet<u32>> = None;
+
+        for trigram in trigrams {
+            let files = self
+                .trigrams
+                .get(trigram)
+                .map(|files| files.iter().copied().collect::<HashSet<_>>())
+                .unwrap_or_default();
+
+            ids = Some(match ids {
+                None => files,
+                Some(ids) => ids.intersection(&files).copied().collect(),
+            });
+        }
+        ids.unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| self.files.get(id as usize))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// Trigram indexes, stored in a directory and cached in memory.
+#[derive(Debug)]
+pub struct Indexes {
+    dir: PathBuf,
+    cache: Mutex<HashMap<String, Arc<TrigramIndex>>>,
+}
+
+impl Indexes {
+    /// Keep indexes in the given directory, which is created when the first index is stored.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            cache: Default::default(),
+        }
+    }
+
+    /// Get the index of a project's head, given its directory, building the index if it's
+    /// missing or out of date.
+    pub fn get(&self, root: &Directory, urn: &Urn, head: &str) -> Arc<TrigramIndex> {
+        let id = urn.encode_id();
+
+        if let Some(index) = self.lock().get(&id) {
+            if index.commit == head {
+                return index.clone();
+            }
+        }
+        let path = self.dir.join(&id).with_extension("json");
+        let index = match load(&path) {
+            Some(index) if index.commit == head => index,
+            _ => {
+                let index = TrigramIndex::build(root, head);
+                if let Err(err) = store(&path, &index) {
+                    tracing::warn!("Failed to store code search index of {}: {}", urn, err);
+                }
+                index
+            }
+        };
+        let index = Arc::new(index);
+        let mut cache = self.lock();
+
+        if cache.len() >= CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(id, index.clone());
+
+        index
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<TrigramIndex>>> {
+        self.cache.lock().expect("cache lock is not poisoned")
+    }
+}
+
+/// Load an index, if it exists and is readable.
+fn load(path: &Path) -> Option<TrigramIndex> {
+    let bytes = fs::read(path).ok()?;
+
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Store an index atomically.
+fn store(path: &Path, index: &TrigramIndex) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+
+    fs::write(&tmp, serde_json::to_vec(index)?)?;
+    fs::rename(&tmp, path)
+}
+
+/// Whether a file is searched.
+fn is_searchable(content: &[u8]) -> bool {
+    content.len() <= MAX_FILE_SIZE && !content.iter().take(BINARY_CHECK_SIZE).any(|b| *b == 0)
+}
+
+/// Visit the files of a directory in path order, until the visitor returns `false`. Returns
+/// whether all files were visited.
+fn walk<F>(dir: &Directory, prefix: &str, visit: &mut F) -> bool
+where
+    F: FnMut(String, &[u8]) -> bool,
+{
+    let mut entries = dir
+        .iter()
+        .filter_map(|entry| match entry {
+            DirectoryContents::File { name, file } => Some((name.to_string(), Either::Left(file))),
+            DirectoryContents::Directory(dir) => Some((dir.name().to_string(), Either::Right(dir))),
+            DirectoryContents::Repo => None,
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    for (name, entry) in entries {
+        let path = format!("{}{}", prefix, name);
+        let more = match entry {
+            Either::Left(file) => visit(path, &file.contents),
+            Either::Right(dir) => walk(&dir, &format!("{}/", path), visit),
+        };
+        if !more {
+            return false;
+        }
+    }
+    true
+}
+
+/// Get the case-insensitive trigrams of some text.
+fn trigrams(bytes: &[u8]) -> HashSet<u32> {
+    bytes
+        .windows(3)
+        .map(|w| {
+            u32::from_be_bytes([
+                0,
+                w[0].to_ascii_lowercase(),
+                w[1].to_ascii_lowercase(),
+                w[2].to_ascii_lowercase(),
+            ])
+        })
+        .collect()
+}
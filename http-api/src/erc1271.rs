@@ -0,0 +1,253 @@
+//! Verification of contract wallet signatures, as specified by ERC-1271.
+//!
+//! Contract wallets, eg. multisigs, can't produce ECDSA signatures of their own. Instead, a
+//! signature is valid if the wallet's `isValidSignature(bytes32,bytes)` function returns its
+//! selector when called with the message hash and signature. The call is made through an
+//! Ethereum JSON-RPC endpoint.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ethers_core::abi::{self, Token};
+use ethers_core::types::{H160, H256};
+use ethers_core::utils::{hash_message, hex};
+use hyper::client::HttpConnector;
+use hyper::http::{header, Method, Request, Uri};
+use hyper::{Body, Client};
+use hyper_rustls::HttpsConnector;
+use serde_json::{json, Value};
+
+/// Selector of `isValidSignature(bytes32,bytes)`, returned by wallets when a signature is valid.
+pub const MAGIC_VALUE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+/// How long the result of a verification is kept.
+pub const CACHE_EXPIRATION: Duration = Duration::from_secs(60);
+/// Time after which a JSON-RPC call is abandoned.
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A JSON-RPC error.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Http(#[from] hyper::Error),
+
+    #[error(transparent)]
+    Request(#[from] hyper::http::Error),
+
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+
+    #[error("request timed out")]
+    Timeout,
+}
+
+/// A verification result, cached by message nonce.
+struct Cached {
+    address: H160,
+    /// Hash of the signed message, so that other messages with the same nonce are verified.
+    hash: H256,
+    signature: Vec<u8>,
+    valid: bool,
+    time: Instant,
+}
+
+/// Verifies contract wallet signatures through a JSON-RPC endpoint.
+#[derive(Clone)]
+pub struct Verifier {
+    url: Uri,
+    client: Client<HttpsConnector<HttpConnector>>,
+    cache: Arc<Mutex<HashMap<String, Cached>>>,
+}
+
+impl Verifier {
+    /// Create a verifier using the given JSON-RPC endpoint.
+    pub fn new(url: Uri) -> Self {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Self {
+            url,
+            client: Client::builder().build(https),
+            cache: Default::default(),
+        }
+    }
+
+    /// Check that a message with the given nonce was signed by the contract wallet at the given
+    /// address.
+    pub async fn verify(
+        &self,
+        address: H160,
+        message: &str,
+        nonce: &str,
+        signature: &[u8],
+    ) -> Result<bool, Error> {
+        let hash = hash_message(message);
+        {
+            let mut cache = self.cache.lock().expect("cache lock is not poisoned");
+            cache.retain(|_, c| c.time.elapsed() < CACHE_EXPIRATION);
+
+            if let Some(cached) = cache.get(nonce) {
+                if cached.address == address && cached.hash == hash && cached.signature == signature
+                {
+                    return Ok(cached.valid);
+                }
+            }
+        }
+        let data = [
+            &MAGIC_VALUE[..],
+            &abi::encode(&[
+                Token::FixedBytes(hash.as_bytes().to_vec()),
+                Token::Bytes(signature.to_vec()),
+            ]),
+        ]
+        .concat();
+        let valid = match self.call(address, &data).await? {
+            Ok(result) => result.starts_with(&MAGIC_VALUE),
+            // Calls revert when the address isn't a wallet, or the signature isn't valid.
+            Err(err) => {
+                tracing::debug!("ERC-1271 call to {:?} failed: {}", address, err);
+                false
+            }
+        };
+
+        self.cache
+            .lock()
+            .expect("cache lock is not poisoned")
+            .insert(
+                nonce.to_owned(),
+                Cached {
+                    address,
+                    hash,
+                    signature: signature.to_vec(),
+                    valid,
+                    time: Instant::now(),
+                },
+            );
+        Ok(valid)
+    }
+
+    /// Call a contract with `eth_call`, returning the call result, or the error returned by the
+    /// node.
+    async fn call(&self, to: H160, data: &[u8]) -> Result<Result<Vec<u8>, String>, Error> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_call",
+            "params": [
+                { "to": format!("{:?}", to), "data": format!("0x{}", hex::encode(data)) },
+                "latest"
+            ],
+        });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))?;
+        let response = tokio::time::timeout(RPC_TIMEOUT, async {
+            let response = self.client.request(request).await?;
+            hyper::body::to_bytes(response.into_body()).await
+        })
+        .await
+        .map_err(|_| Error::Timeout)??;
+        let response: Value =
+            serde_json::from_slice(&response).map_err(|e| Error::InvalidResponse(e.to_string()))?;
+
+        if let Some(err) = response.get("error") {
+            return Ok(Err(err.to_string()));
+        }
+        let result = response
+            .get("result")
+            .and_then(Value::as_str)
+            .ok_or_else(|| Error::InvalidResponse(String::from("missing result")))?;
+        let result = hex::decode(result.trim_start_matches("0x"))
+            .map_err(|e| Error::InvalidResponse(e.to_string()))?;
+
+        Ok(Ok(result))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::routing::post;
+    use axum::{Extension, Json, Router};
+
+    use super::*;
+
+    const WALLET: &str = "0xe5a12547fe4e872d192e3ececb76f2ce1aea4946";
+
+    /// Mock JSON-RPC node, where only `WALLET` is a contract wallet, accepting any signature.
+    async fn node(calls: Arc<AtomicUsize>) -> SocketAddr {
+        async fn handler(
+            Extension(calls): Extension<Arc<AtomicUsize>>,
+            Json(request): Json<Value>,
+        ) -> Json<Value> {
+            calls.fetch_add(1, Ordering::SeqCst);
+
+            let to = request["params"][0]["to"].as_str().unwrap_or_default();
+            if to == WALLET {
+                Json(json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": format!("0x{}{}", hex::encode(MAGIC_VALUE), "0".repeat(56)),
+                }))
+            } else {
+                Json(json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": { "code": -32000, "message": "execution reverted" },
+                }))
+            }
+        }
+        let app = Router::new()
+            .route("/", post(handler))
+            .layer(Extension(calls));
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let addr = server.local_addr();
+
+        tokio::spawn(server);
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let addr = node(calls.clone()).await;
+        let verifier = Verifier::new(format!("http://{}/", addr).parse().unwrap());
+        let wallet: H160 = WALLET.parse().unwrap();
+        let signature = [1; 65];
+
+        assert!(verifier
+            .verify(wallet, "message", "nonce", &signature)
+            .await
+            .unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // The result is cached for the nonce.
+        assert!(verifier
+            .verify(wallet, "message", "nonce", &signature)
+            .await
+            .unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Other messages with the same nonce are verified again.
+        assert!(verifier
+            .verify(wallet, "other message", "nonce", &signature)
+            .await
+            .unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Addresses that aren't wallets don't verify.
+        assert!(!verifier
+            .verify(H160::zero(), "message", "other", &signature)
+            .await
+            .unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
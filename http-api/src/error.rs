@@ -41,6 +41,26 @@ pub enum Error {
     #[error("could not authenticate: {0}")]
     Auth(&'static str),
 
+    /// The request requires an authorized session.
+    #[error("unauthorized: {0}")]
+    Unauthorized(&'static str),
+
+    /// The signed-in user isn't allowed to do this.
+    #[error("forbidden: {0}")]
+    Forbidden(&'static str),
+
+    /// The request is invalid.
+    #[error("invalid request: {0}")]
+    BadRequest(String),
+
+    /// A sign-in was rejected.
+    #[error("could not sign in: {0}")]
+    Signin(#[from] crate::auth::SigninError),
+
+    /// An error occurred while verifying a contract wallet signature.
+    #[error("could not verify contract wallet signature: {0}")]
+    Erc1271(#[from] crate::erc1271::Error),
+
     /// An error occurred while verifying the siwe message.
     #[error(transparent)]
     SiweVerification(#[from] siwe::VerificationError),
@@ -61,6 +81,14 @@ pub enum Error {
     #[error(transparent)]
     Storage(#[from] librad::git::storage::Error),
 
+    /// An error occurred with the alias registry.
+    #[error(transparent)]
+    Alias(#[from] shared::alias::Error),
+
+    /// An error occurred with the access settings of a project.
+    #[error(transparent)]
+    Access(#[from] shared::access::Error),
+
     /// An error occurred with the storage pool.
     #[error("{0}")]
     Pool(String),
@@ -69,6 +97,10 @@ pub enum Error {
     #[error(transparent)]
     Project(#[from] radicle_common::project::Error),
 
+    /// An error occurred while computing a diff.
+    #[error("diff: {0}")]
+    Diff(String),
+
     /// An error occurred with radicle storage.
     #[error("{0}: {1}")]
     Io(&'static str, std::io::Error),
@@ -104,10 +136,19 @@ pub enum Error {
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
+        let reason = match &self {
+            Error::Signin(err) => Some(err.code()),
+            _ => None,
+        };
         let (status, msg) = match &self {
             Error::NotFound => (StatusCode::NOT_FOUND, None),
             Error::NoHead(msg) => (StatusCode::NOT_FOUND, Some(msg.to_string())),
             Error::Auth(msg) => (StatusCode::BAD_REQUEST, Some(msg.to_string())),
+            Error::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, Some(msg.to_string())),
+            Error::Signin(err) => (StatusCode::BAD_REQUEST, Some(err.to_string())),
+            Error::Forbidden(msg) => (StatusCode::FORBIDDEN, Some(msg.to_string())),
+            Error::BadRequest(msg) => (StatusCode::BAD_REQUEST, Some(msg.clone())),
+            Error::Erc1271(err) => (StatusCode::BAD_GATEWAY, Some(err.to_string())),
             Error::SiweParse(msg) => (StatusCode::BAD_REQUEST, Some(msg.to_string())),
             Error::SiweVerification(msg) => (StatusCode::BAD_REQUEST, Some(msg.to_string())),
             Error::Git(e) => (
@@ -121,11 +162,14 @@ impl IntoResponse for Error {
             }
         };
 
-        let body = Json(json!({
+        let mut body = json!({
             "error": msg.or_else(|| status.canonical_reason().map(|r| r.to_string())),
             "code": status.as_u16()
-        }));
+        });
+        if let Some(reason) = reason {
+            body["reason"] = reason.into();
+        }
 
-        (status, body).into_response()
+        (status, Json(body)).into_response()
     }
 }
@@ -0,0 +1,344 @@
+//! Seed activity events.
+//!
+//! Events are derived from the ref change notifications sent by the git-server, broadcast to the
+//! clients of the `/events` streams, and appended to a bounded on-disk log, so that clients can
+//! resume a stream after reconnecting. The log file is written by a separate thread.
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead as _, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use librad::git::Urn;
+use librad::PeerId;
+
+use shared::notify::{Notification, RefUpdate};
+
+/// Default file in the monorepo git directory where events are logged.
+pub const EVENTS_LOG_FILE: &str = "events.log";
+/// Number of events kept in the log.
+pub const EVENTS_LOG_CAPACITY: usize = 1024;
+/// Number of events buffered for each stream.
+pub const EVENTS_CHANNEL_CAPACITY: usize = 256;
+
+/// Type name of issue collaborative objects.
+const ISSUE_TYPENAME: &str = "xyz.radicle.issue";
+/// Type name of patch collaborative objects.
+const PATCH_TYPENAME: &str = "xyz.radicle.patch";
+
+/// A seed activity event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    /// Sequence number of the event, used to resume streams.
+    pub id: u64,
+    /// When the event occured, in seconds since the epoch.
+    pub timestamp: i64,
+    /// Project the event relates to.
+    pub project: String,
+    /// Peer that caused the event, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<PeerId>,
+    #[serde(flatten)]
+    pub kind: Kind,
+}
+
+/// The type of an event, and its details.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Kind {
+    /// A ref was created, updated or deleted.
+    RefUpdated {
+        name: String,
+        old: String,
+        new: String,
+    },
+    /// A new project was published.
+    ProjectCreated,
+    /// The identity of a project was updated.
+    IdentityUpdated { revision: String },
+    /// An issue was opened.
+    IssueCreated { id: String },
+    /// An issue was changed, eg. commented on.
+    IssueUpdated { id: String },
+    /// A patch was proposed.
+    PatchCreated { id: String },
+    /// A patch was changed, eg. revised or reviewed.
+    PatchUpdated { id: String },
+}
+
+impl Kind {
+    /// Name of the event type, as used in streams and filters.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RefUpdated { .. } => "refUpdated",
+            Self::ProjectCreated => "projectCreated",
+            Self::IdentityUpdated { .. } => "identityUpdated",
+            Self::IssueCreated { .. } => "issueCreated",
+            Self::IssueUpdated { .. } => "issueUpdated",
+            Self::PatchCreated { .. } => "patchCreated",
+            Self::PatchUpdated { .. } => "patchUpdated",
+        }
+    }
+}
+
+/// Derive the events of a ref change notification.
+pub fn from_notification(
+    notification: &Notification,
+    created: bool,
+) -> Vec<(Option<PeerId>, Kind)> {
+    let mut events = Vec::new();
+
+    if created {
+        events.push((None, Kind::ProjectCreated));
+    }
+    for update in &notification.updates {
+        events.extend(from_update(update));
+    }
+    events
+}
+
+fn from_update(update: &RefUpdate) -> Vec<(Option<PeerId>, Kind)> {
+    let is_zero = |oid: &str| oid.bytes().all(|b| b == b'0');
+    let (peer, rest) = match update
+        .name
+        .strip_prefix("refs/remotes/")
+        .and_then(|r| r.split_once('/'))
+    {
+        Some((peer, rest)) => match PeerId::from_default_encoding(peer) {
+            Ok(peer) => (Some(peer), rest),
+            Err(_) => return Vec::new(),
+        },
+        None => (
+            None,
+            update.name.strip_prefix("refs/").unwrap_or(&update.name),
+        ),
+    };
+    let mut events = vec![(
+        peer,
+        Kind::RefUpdated {
+            name: update.name.clone(),
+            old: update.old.clone(),
+            new: update.new.clone(),
+        },
+    )];
+
+    // Deletions only result in a ref update.
+    if is_zero(&update.new) {
+        return events;
+    }
+    let created = is_zero(&update.old);
+
+    if rest == "rad/id" && !created {
+        events.push((
+            peer,
+            Kind::IdentityUpdated {
+                revision: update.new.clone(),
+            },
+        ));
+    } else if let Some((typename, id)) = rest.strip_prefix("cobs/").and_then(|r| r.split_once('/'))
+    {
+        let id = id.to_owned();
+        let kind = match (typename, created) {
+            (ISSUE_TYPENAME, true) => Kind::IssueCreated { id },
+            (ISSUE_TYPENAME, false) => Kind::IssueUpdated { id },
+            (PATCH_TYPENAME, true) => Kind::PatchCreated { id },
+            (PATCH_TYPENAME, false) => Kind::PatchUpdated { id },
+            _ => return events,
+        };
+        events.push((peer, kind));
+    }
+    events
+}
+
+/// Event broadcast and log.
+#[derive(Clone)]
+pub struct Events {
+    log: Arc<Mutex<Log>>,
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    /// Open the event log at the given path, creating it if necessary.
+    pub fn open(path: PathBuf) -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CHANNEL_CAPACITY);
+        let file = match LogFile::load(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                tracing::error!("Failed to load event log {:?}: {}", path, err);
+
+                LogFile::new(path)
+            }
+        };
+        let (writer, receiver) = mpsc::channel();
+        let log = Log {
+            events: file.events.clone(),
+            next: file.next,
+            writer,
+        };
+
+        // The log file is written by its own thread, so that publishing never blocks on I/O.
+        if let Err(err) = thread::Builder::new()
+            .name(String::from("events-log"))
+            .spawn(move || file.run(receiver))
+        {
+            tracing::error!("Failed to start event log writer: {}", err);
+        }
+
+        Self {
+            log: Arc::new(Mutex::new(log)),
+            sender,
+        }
+    }
+
+    /// Publish an event for a project.
+    pub fn publish(&self, project: &Urn, peer: Option<PeerId>, kind: Kind) -> Event {
+        let mut log = self.log.lock().expect("event log lock is not poisoned");
+        let event = Event {
+            id: log.next,
+            timestamp: Utc::now().timestamp(),
+            project: project.to_string(),
+            peer,
+            kind,
+        };
+        log.next = event.id + 1;
+        push(&mut log.events, event.clone());
+
+        // Sent while holding the lock, so that events are written in order.
+        if log.writer.send(event.clone()).is_err() {
+            tracing::error!("Failed to append to event log: writer has stopped");
+        }
+        // Sending fails if there are no subscribers, which is fine.
+        self.sender.send(event.clone()).ok();
+
+        event
+    }
+
+    /// Subscribe to events, returning the logged events that came after the given event id, if
+    /// any, followed by a receiver of new events.
+    pub fn subscribe(&self, after: Option<u64>) -> (Vec<Event>, broadcast::Receiver<Event>) {
+        let log = self.log.lock().expect("event log lock is not poisoned");
+        // Subscribe while holding the lock, so that no event is missed between the two.
+        let receiver = self.sender.subscribe();
+        let backlog = match after {
+            Some(after) => log
+                .events
+                .iter()
+                .filter(|e| e.id > after)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+
+        (backlog, receiver)
+    }
+}
+
+/// In-memory event log, of the events that can be resumed from.
+struct Log {
+    /// Most recent events, oldest first.
+    events: VecDeque<Event>,
+    /// Id of the next event.
+    next: u64,
+    /// Sender of the events to append to the log file.
+    writer: mpsc::Sender<Event>,
+}
+
+/// Add an event to a bounded list of the most recent events.
+fn push(events: &mut VecDeque<Event>, event: Event) {
+    events.push_back(event);
+
+    while events.len() > EVENTS_LOG_CAPACITY {
+        events.pop_front();
+    }
+}
+
+/// Bounded on-disk event log, stored as JSON lines.
+struct LogFile {
+    path: PathBuf,
+    /// Most recent events, oldest first, kept to compact the file.
+    events: VecDeque<Event>,
+    /// Number of lines in the log file.
+    lines: usize,
+    /// Id of the event following the logged ones.
+    next: u64,
+}
+
+impl LogFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            events: VecDeque::new(),
+            lines: 0,
+            next: 1,
+        }
+    }
+
+    fn load(path: &Path) -> io::Result<Self> {
+        let mut log = Self::new(path.to_path_buf());
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(log),
+            Err(err) => return Err(err),
+        };
+
+        for line in io::BufReader::new(file).lines() {
+            let line = line?;
+            log.lines += 1;
+
+            // Skip lines that can't be parsed, eg. a partially written last line.
+            if let Ok(event) = serde_json::from_str::<Event>(&line) {
+                log.next = log.next.max(event.id + 1);
+                push(&mut log.events, event);
+            }
+        }
+        Ok(log)
+    }
+
+    /// Append the received events, until all senders are dropped.
+    fn run(mut self, receiver: mpsc::Receiver<Event>) {
+        for event in receiver {
+            if let Err(err) = self.append(event) {
+                tracing::error!("Failed to append to event log: {}", err);
+            }
+        }
+    }
+
+    fn append(&mut self, event: Event) -> io::Result<()> {
+        self.next = event.id + 1;
+        push(&mut self.events, event.clone());
+
+        // Compact the file once it holds twice as many events as we keep.
+        if self.lines >= EVENTS_LOG_CAPACITY * 2 {
+            return self.compact();
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        writeln!(file, "{}", serde_json::to_string(&event)?)?;
+        self.lines += 1;
+
+        Ok(())
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp = self.path.with_extension("log.tmp");
+        let mut file = fs::File::create(&tmp)?;
+
+        for event in &self.events {
+            writeln!(file, "{}", serde_json::to_string(event)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.lines = self.events.len();
+
+        Ok(())
+    }
+}
@@ -1,28 +1,39 @@
 #![allow(clippy::if_same_then_else)]
 mod auth;
 mod axum_extra;
+mod code_search;
 mod commit;
+mod erc1271;
 mod error;
+mod events;
+mod listing;
+mod mergeability;
 mod project;
+mod search;
+mod sessions;
+mod timeline;
 mod v1;
 
 #[cfg(test)]
 mod test_extra;
 
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto as _};
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{self, Duration};
 use std::{env, net};
 
 use axum::body::BoxBody;
-use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
-use axum::http::Method;
+use axum::extract::{self, RequestParts};
+use axum::http::header::{HeaderName, AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderValue, Method};
 use axum::response::{IntoResponse, Json};
 use axum::routing::get;
-use axum::{Extension, Router};
+use axum::{middleware, Extension, Router};
 use axum_server::tls_rustls::RustlsConfig;
 use chrono::Utc;
 use hyper::http::{Request, Response};
@@ -45,14 +56,26 @@ use librad::PeerId;
 use radicle_common::{cobs, keys, person};
 use radicle_source::surf::vcs::git;
 
-use crate::auth::AuthState;
+use shared::access::{self, Access, Principal};
+use shared::alias::{Aliases, Cached};
+use shared::notify;
+use shared::proxy::{self, ClientAddr, ForwardingHeader, IpNet, ProxyListener, TrustedProxies};
+use shared::ratelimit::{self, RateLimiter};
+
+use crate::auth::{Attribution, AuthSession};
+use crate::events::{Events, EVENTS_LOG_FILE};
 use crate::project::{Info, PeerInfo};
+use crate::sessions::Sessions;
 
 use error::Error;
 
+pub use crate::auth::{SiweConfig, DEFAULT_SESSION_LIFETIME};
+pub use crate::sessions::Backend as SessionBackend;
+
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const POPULATE_FINGERPRINTS_INTERVAL: time::Duration = time::Duration::from_secs(180);
 pub const CLEANUP_SESSIONS_INTERVAL: time::Duration = time::Duration::from_secs(60);
+pub const RECONCILE_INDEX_INTERVAL: time::Duration = time::Duration::from_secs(60);
 pub const STORAGE_POOL_SIZE: usize = 10;
 
 #[derive(Debug, Clone)]
@@ -63,6 +86,16 @@ pub struct Options {
     pub tls_cert: Option<PathBuf>,
     pub tls_key: Option<PathBuf>,
     pub theme: String,
+    pub trusted_proxies: Vec<IpNet>,
+    pub proxy_header: ForwardingHeader,
+    pub proxy_protocol: bool,
+    pub rate_limit: Option<NonZeroU32>,
+    pub events_log: Option<PathBuf>,
+    pub session_store: SessionBackend,
+    pub session_cookie: bool,
+    pub allowed_origins: Vec<HeaderValue>,
+    pub siwe: SiweConfig,
+    pub cob_proxy: bool,
 }
 
 /// SSH Key fingerprint.
@@ -78,9 +111,21 @@ pub struct Context {
     theme: String,
     pool: Pool<Storage>,
     peer_id: PeerId,
-    aliases: Arc<RwLock<HashMap<String, Urn>>>,
+    aliases: Arc<RwLock<Aliases>>,
     projects: Arc<RwLock<HashMap<Urn, Fingerprints>>>,
-    sessions: Arc<RwLock<HashMap<SessionId, AuthState>>>,
+    sessions: Sessions,
+    /// Whether sessions are also sent as cookies.
+    session_cookie: bool,
+    siwe: Arc<SiweConfig>,
+    /// Contract wallet signature verifier, if enabled.
+    erc1271: Option<erc1271::Verifier>,
+    /// Whether signed-in users without a linked person may write collaborative objects through
+    /// the seed's identity.
+    cob_proxy: bool,
+    mergeability: Arc<mergeability::Cache>,
+    search: Arc<search::Index>,
+    code_search: Arc<code_search::Indexes>,
+    events: Events,
 }
 
 impl Context {
@@ -92,13 +137,23 @@ impl Context {
         );
 
         Self {
+            aliases: Arc::new(RwLock::new(Aliases::new(paths.git_dir()))),
+            events: Events::open(paths.git_dir().join(EVENTS_LOG_FILE)),
+            code_search: Arc::new(code_search::Indexes::new(
+                paths.git_dir().join(code_search::INDEX_DIR),
+            )),
             paths,
             pool,
             theme,
             peer_id,
-            aliases: Default::default(),
             projects: Default::default(),
-            sessions: Default::default(),
+            sessions: Sessions::default(),
+            session_cookie: false,
+            siwe: Default::default(),
+            erc1271: None,
+            cob_proxy: false,
+            mergeability: Default::default(),
+            search: Default::default(),
         }
     }
 
@@ -109,80 +164,161 @@ impl Context {
             .map_err(|e| Error::Pool(e.to_string()))
     }
 
-    /// Populates alias map with unique projects' names and their urns
-    async fn populate_aliases(&self, map: &mut HashMap<String, Urn>) -> Result<(), Error> {
-        use librad::git::identities::SomeIdentity::Project;
-
+    /// Resolve a project alias.
+    async fn resolve_alias(&self, alias: &str) -> Result<Urn, Error> {
         let storage = self.storage().await?;
-        let identities = identities::any::list(storage.read_only())?;
+        // Known aliases are resolved under a read lock, and only unknown ones refresh the
+        // registry.
+        let cached = self
+            .aliases
+            .read()
+            .await
+            .resolve_cached(storage.read_only(), alias)?;
+        match cached {
+            Cached::Found(urn) => return Ok(urn),
+            Cached::Missing => return Err(Error::NotFound),
+            Cached::Unknown => {}
+        }
+        let mut aliases = self.aliases.write().await;
 
-        for identity in identities.flatten() {
-            if let Project(project) = identity {
-                let urn = project.urn();
-                let name = project.payload().subject.name.to_string();
+        aliases
+            .resolve(storage.read_only(), alias)?
+            .ok_or(Error::NotFound)
+    }
 
-                if let Entry::Vacant(e) = map.entry(name.clone()) {
-                    e.insert(urn);
-                }
-            }
-        }
+    /// Get the namespaces of private projects. Since the HTTP API doesn't authenticate readers,
+    /// private projects are hidden from it altogether.
+    async fn private_projects(&self) -> Result<HashSet<String>, Error> {
+        let config = self.paths.git_dir().join("config");
 
-        Ok(())
+        tokio::task::spawn_blocking(move || {
+            let config = git2::Config::open(&config)?.snapshot()?;
+            access::private(&config).map_err(Error::from)
+        })
+        .await?
     }
 
-    fn cleanup_sessions(&self, map: &mut HashMap<SessionId, AuthState>) -> Result<(), Error> {
-        let mut to_remove: Vec<SessionId> = Vec::new();
-
-        for (key, value) in map.iter() {
-            let current_time = Utc::now();
-            match value {
-                AuthState::Authorized(auth) => {
-                    if let Some(exp_time) = auth.expiration_time {
-                        if current_time >= exp_time {
-                            to_remove.push(key.clone());
-                        }
-                    }
-                }
-                AuthState::Unauthorized {
-                    expiration_time, ..
-                } => {
-                    if current_time >= *expiration_time {
-                        to_remove.push(key.clone())
-                    }
-                }
-            }
+    /// Check that a project may be read. Private projects are reported as not found, so that
+    /// their existence isn't leaked.
+    async fn authorize_read(&self, urn: &Urn) -> Result<(), Error> {
+        let namespace = urn.encode_id();
+        let config = self.paths.git_dir().join("config");
+        let access = {
+            let namespace = namespace.clone();
+
+            tokio::task::spawn_blocking(move || {
+                let config = git2::Config::open(&config)?;
+                Access::load(&config, &namespace).map_err(Error::from)
+            })
+            .await??
+        };
+
+        if access.can_read(&namespace, &[], Principal::Anonymous) {
+            Ok(())
+        } else {
+            Err(Error::NotFound)
         }
+    }
 
-        for key in to_remove {
-            map.remove(&key);
-        }
+    /// Get the attribution of changes made on behalf of a signed-in user. Users may only make
+    /// changes if their account is linked to a person, or if the seed acts as a proxy for them.
+    ///
+    /// Accounts are linked to persons in the monorepo's git config:
+    ///
+    /// ```text
+    /// [account "eip155:1:0xe5A12547fe4E872D192E3eCecb76F2Ce1aeA4946"]
+    ///     person = rad:git:hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo
+    /// ```
+    fn attribution(&self, auth: &AuthSession) -> Result<Attribution, Error> {
+        let account = auth.account();
+        let config = git2::Config::open(&self.paths.git_dir().join("config"))?;
+        let person = match config.get_string(&format!("account.{}.person", account)) {
+            Ok(urn) => match Urn::from_str(&urn) {
+                Ok(urn) => Some(urn),
+                Err(_) => {
+                    tracing::warn!("Invalid person {:?} linked to account {}", urn, account);
+                    None
+                }
+            },
+            Err(err) if err.code() == git2::ErrorCode::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
 
-        Ok(())
+        if person.is_none() && !self.cob_proxy {
+            return Err(Error::Forbidden("Account is not linked to a person"));
+        }
+        Ok(Attribution { account, person })
     }
 
-    /// Populate a map between SSH fingerprints and their peer identities
+    /// Populate a map between SSH fingerprints and their peer identities. Projects that no
+    /// longer exist are dropped from the map.
     fn populate_fingerprints(
         map: &mut tokio::sync::RwLockWriteGuard<HashMap<Urn, Fingerprints>>,
         storage: deadpool::managed::Object<Storage, InitError>,
     ) -> Result<(), Error> {
         let identities = identities::any::list(storage.read_only())?;
+        let mut projects = HashMap::new();
 
         for identity in identities.flatten() {
             if let SomeIdentity::Project(project) = identity {
                 let meta = project::Metadata::try_from(project)?;
-                let fingerprints = map.entry(meta.urn.clone()).or_default();
-                let tracked = project::tracked(&meta, storage.read_only())?;
+                let fingerprints = Self::fingerprints(&meta, storage.read_only())?;
 
-                for peer in tracked {
-                    let fp = keys::to_ssh_fingerprint(&peer.id).expect("Conversion cannot fail");
-                    fingerprints.insert(fp, peer);
-                }
+                projects.insert(meta.urn, fingerprints);
             }
         }
+        **map = projects;
+
+        Ok(())
+    }
+
+    /// Get the SSH fingerprints of the peers tracked for a project.
+    fn fingerprints(
+        meta: &project::Metadata,
+        storage: &storage::ReadOnly,
+    ) -> Result<Fingerprints, Error> {
+        let mut fingerprints = Fingerprints::new();
+
+        for peer in project::tracked(meta, storage)? {
+            let fp = keys::to_ssh_fingerprint(&peer.id).expect("Conversion cannot fail");
+            fingerprints.insert(fp, peer);
+        }
+        Ok(fingerprints)
+    }
+
+    /// Refresh the cached data of a project after its refs changed. The entries of deleted
+    /// projects are dropped.
+    async fn refresh_project(&self, urn: &Urn) -> Result<(), Error> {
+        let storage = self.storage().await?;
 
+        self.aliases
+            .write()
+            .await
+            .update(storage.read_only(), urn)?;
+
+        let mut projects = self.projects.write().await;
+        match identities::any::get(storage.read_only(), urn)? {
+            Some(SomeIdentity::Project(project)) => {
+                let meta = project::Metadata::try_from(project)?;
+                let fingerprints = Self::fingerprints(&meta, storage.read_only())?;
+
+                projects.insert(urn.clone(), fingerprints);
+            }
+            _ => {
+                projects.remove(urn);
+            }
+        }
         Ok(())
     }
 
+    /// Re-index a project for search, after its refs or collaborative objects changed.
+    async fn index_project(&self, urn: &Urn) -> Result<(), Error> {
+        let storage = self.storage().await?;
+        let (ctx, urn) = (self.clone(), urn.clone());
+
+        tokio::task::spawn_blocking(move || ctx.search.update(&storage, &ctx.paths, &urn)).await?
+    }
+
     /// From a commit hash, return the signer's fingerprint, if any.
     fn commit_ssh_fingerprint(&self, sha1: &str) -> Result<Option<String>, Error> {
         radicle_common::git::commit_ssh_fingerprint(self.paths.git_dir(), sha1)
@@ -216,11 +352,50 @@ impl Context {
     }
 }
 
+/// Middleware that hides private projects from the `/projects/:project` routes, resolving
+/// project aliases first. Requires the [`Context`] as an extension.
+async fn authorize_project<B: Send>(
+    req: Request<B>,
+    next: middleware::Next<B>,
+) -> Result<Response<BoxBody>, Error> {
+    let mut parts = RequestParts::new(req);
+    let params = parts
+        .extract::<extract::Path<HashMap<String, String>>>()
+        .await;
+    let ctx = parts.extensions().get::<Context>().cloned();
+
+    if let (Ok(extract::Path(params)), Some(ctx)) = (params, ctx) {
+        if let Some(project) = params.get("project") {
+            let urn = match Urn::from_str(project) {
+                Ok(urn) => urn,
+                Err(_) => ctx.resolve_alias(project).await?,
+            };
+            ctx.authorize_read(&urn).await?;
+        }
+    }
+    let req = parts
+        .try_into_request()
+        .expect("The request body is never extracted");
+
+    Ok(next.run(req).await)
+}
+
 /// Run the HTTP API.
 pub async fn run(options: Options) -> anyhow::Result<()> {
     let (_, profile, signer) = shared::profile(options.root, options.passphrase)?;
     let paths = profile.paths();
-    let ctx = Context::new(paths.clone(), signer, options.theme);
+    let mut ctx = Context::new(paths.clone(), signer, options.theme);
+    if let Some(path) = options.events_log {
+        ctx.events = Events::open(path);
+    }
+    ctx.sessions = options.session_store.open()?;
+    ctx.session_cookie = options.session_cookie;
+    if options.siwe.domains.is_empty() {
+        tracing::warn!("No sign-in domain configured, signing in is disabled");
+    }
+    ctx.erc1271 = options.siwe.eth_rpc_url.clone().map(erc1271::Verifier::new);
+    ctx.siwe = Arc::new(options.siwe);
+    ctx.cob_proxy = options.cob_proxy;
     let peer_id = ctx.peer_id;
 
     // Populate fingerprints
@@ -228,9 +403,27 @@ pub async fn run(options: Options) -> anyhow::Result<()> {
         ctx.clone(),
         POPULATE_FINGERPRINTS_INTERVAL,
     ));
+    // Build the search index, and keep it up to date
+    tokio::spawn(index_job(ctx.clone(), RECONCILE_INDEX_INTERVAL));
+    // Refresh projects on ref changes
+    tokio::spawn(notifications_job(ctx.clone()));
     // Cleanup sessions
     tokio::spawn(cleanup_sessions_job(ctx.clone(), CLEANUP_SESSIONS_INTERVAL));
 
+    let cors = CorsLayer::new()
+        .max_age(Duration::from_secs(86400))
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([CONTENT_TYPE, AUTHORIZATION])
+        .expose_headers([HeaderName::from_static(listing::X_TOTAL_COUNT)]);
+    // Credentials, eg. session cookies, can only be allowed for specific origins.
+    let cors = if options.allowed_origins.is_empty() {
+        cors.allow_origin(cors::Any)
+    } else {
+        cors.allow_origin(cors::AllowOrigin::list(options.allowed_origins))
+            .allow_credentials(true)
+    };
+    let proxies = TrustedProxies::new(options.trusted_proxies.clone(), options.proxy_header);
+    let limiter = RateLimiter::new(options.rate_limit);
     let root_router = Router::new()
         .route("/", get(root_handler))
         .layer(Extension(peer_id));
@@ -238,18 +431,21 @@ pub async fn run(options: Options) -> anyhow::Result<()> {
     let app = Router::new()
         .merge(root_router)
         .merge(v1::router(ctx.clone()))
-        .layer(
-            CorsLayer::new()
-                .max_age(Duration::from_secs(86400))
-                .allow_origin(cors::Any)
-                .allow_methods([Method::GET, Method::POST, Method::PUT])
-                .allow_headers([CONTENT_TYPE, AUTHORIZATION]),
-        )
+        .layer(middleware::from_fn(ratelimit::middleware))
+        .layer(Extension(limiter))
+        .layer(cors)
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &Request<Body>| {
+                    let client = request
+                        .extensions()
+                        .get::<ClientAddr>()
+                        .map(|ClientAddr(addr)| addr.to_string())
+                        .unwrap_or_default();
+
                     tracing::info_span!(
                         "request",
+                        client = %client,
                         method = %request.method(),
                         uri = %request.uri(),
                         status = tracing::field::Empty,
@@ -264,20 +460,32 @@ pub async fn run(options: Options) -> anyhow::Result<()> {
                         tracing::info!("Processed");
                     },
                 ),
-        );
+        )
+        .layer(middleware::from_fn(proxy::middleware))
+        .layer(Extension(proxies.clone()))
+        .into_make_service_with_connect_info::<SocketAddr>();
 
     if let (Some(cert), Some(key)) = (options.tls_cert, options.tls_key) {
+        if options.proxy_protocol {
+            anyhow::bail!("The PROXY protocol is not supported with TLS");
+        }
         let config = RustlsConfig::from_pem_file(cert, key).await.unwrap();
 
         tracing::info!("listening on https://{}", options.listen);
         axum_server::bind_rustls(options.listen, config)
-            .serve(app.into_make_service())
+            .serve(app)
             .await?;
+    } else if options.proxy_protocol {
+        if proxies.is_empty() {
+            anyhow::bail!("The PROXY protocol requires at least one trusted proxy");
+        }
+        let listener = ProxyListener::bind(options.listen, proxies).await?;
+
+        tracing::info!("listening on http://{} (PROXY protocol)", options.listen);
+        axum::Server::builder(listener).serve(app).await?;
     } else {
         tracing::info!("listening on http://{}", options.listen);
-        axum::Server::bind(&options.listen)
-            .serve(app.into_make_service())
-            .await?;
+        axum::Server::bind(&options.listen).serve(app).await?;
     }
 
     Ok(())
@@ -289,9 +497,10 @@ async fn cleanup_sessions_job(ctx: Context, interval: time::Duration) {
     loop {
         timer.tick().await; // Returns immediately the first time.
 
-        let mut sessions = ctx.sessions.write().await;
-        if let Err(err) = ctx.cleanup_sessions(&mut sessions) {
-            tracing::error!("Failed to cleanup sessions: {}", err);
+        match ctx.sessions.cleanup(Utc::now()).await {
+            Ok(0) => {}
+            Ok(n) => tracing::debug!("Removed {} expired session(s)", n),
+            Err(err) => tracing::error!("Failed to cleanup sessions: {}", err),
         }
     }
 }
@@ -312,6 +521,77 @@ async fn populate_fingerprints_job(ctx: Context, interval: time::Duration) -> Re
     }
 }
 
+/// Index all projects for search. From then on, projects are re-indexed as their refs change,
+/// and the index is reconciled with the monorepo periodically, for changes that aren't notified.
+async fn index_job(ctx: Context, interval: time::Duration) {
+    let mut timer = tokio::time::interval(interval);
+
+    loop {
+        timer.tick().await; // Returns immediately the first time.
+
+        let storage = match ctx.storage().await {
+            Ok(storage) => storage,
+            Err(err) => {
+                tracing::error!("Failed to reconcile search index: {}", err);
+                continue;
+            }
+        };
+        let c = ctx.clone();
+        match tokio::task::spawn_blocking(move || c.search.reconcile(&storage, &c.paths)).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(n)) => tracing::debug!("Re-indexed {} project(s)", n),
+            Ok(Err(err)) => tracing::error!("Failed to reconcile search index: {}", err),
+            Err(err) => tracing::error!("Failed to reconcile search index: {}", err),
+        }
+    }
+}
+
+/// Refresh the cached data of projects as notifications of ref changes are received from the
+/// git-server, and publish the resulting events.
+async fn notifications_job(ctx: Context) {
+    let listener = match notify::Listener::bind(ctx.paths.git_dir()) {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("Failed to bind notification socket: {}", err);
+            return;
+        }
+    };
+
+    loop {
+        let notification = match listener.recv().await {
+            Ok(notification) => notification,
+            Err(err) => {
+                tracing::error!("Failed to receive notification: {}", err);
+                continue;
+            }
+        };
+        let urn = match Urn::try_from_id(&notification.namespace) {
+            Ok(urn) => urn,
+            Err(_) => {
+                tracing::warn!(
+                    "Invalid namespace in notification: {}",
+                    notification.namespace
+                );
+                continue;
+            }
+        };
+        tracing::debug!("Refs of {} changed, refreshing", urn);
+
+        let known = ctx.projects.read().await.contains_key(&urn);
+        if let Err(err) = ctx.refresh_project(&urn).await {
+            tracing::error!("Failed to refresh project {}: {}", urn, err);
+        }
+        let created = !known && ctx.projects.read().await.contains_key(&urn);
+        if let Err(err) = ctx.index_project(&urn).await {
+            tracing::error!("Failed to index project {}: {}", urn, err);
+        }
+
+        for (peer, kind) in events::from_notification(&notification, created) {
+            ctx.events.publish(&urn, peer, kind);
+        }
+    }
+}
+
 async fn root_handler(Extension(peer_id): Extension<PeerId>) -> impl IntoResponse {
     let response = json!({
         "message": "Welcome!",
@@ -330,6 +610,11 @@ async fn root_handler(Extension(peer_id): Extension<PeerId>) -> impl IntoRespons
                 "rel": "peer",
                 "type": "GET"
             },
+            {
+                "href": "/v1/search",
+                "rel": "search",
+                "type": "GET"
+            },
             {
                 "href": "/v1/delegates/:urn/projects",
                 "rel": "projects",
@@ -0,0 +1,275 @@
+//! Filtering, sorting and pagination of issue and patch listings.
+//!
+//! Listings are served from entries kept in the search index, which are updated as projects are
+//! re-indexed, so that only the issues and patches of the returned page are loaded and resolved.
+//! The total number of matches is returned in the `X-Total-Count` header.
+use std::str::FromStr;
+
+use axum::http::header::HeaderName;
+use axum::http::{HeaderMap, HeaderValue};
+use librad::collaborative_objects::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use radicle_common::cobs::issue::{self, Issue};
+use radicle_common::cobs::patch::{self, Patch};
+use radicle_common::cobs::shared::{Author, Label, Timestamp};
+
+use crate::error::Error;
+use crate::search::Kind;
+
+/// Header holding the number of matches, across all pages.
+pub const X_TOTAL_COUNT: &str = "x-total-count";
+/// Number of items per page, if not given.
+pub const DEFAULT_PER_PAGE: usize = 30;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListingQueryString {
+    /// `open`, `closed`, or for patches, `merged`.
+    pub state: Option<String>,
+    /// URN or peer id of the author.
+    pub author: Option<String>,
+    /// Only list items with this label.
+    pub label: Option<String>,
+    /// Only list items whose title or description contains this text, ignoring case.
+    pub q: Option<String>,
+    pub sort: Option<Sort>,
+    pub order: Option<Order>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+/// Field to sort by.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Sort {
+    /// When the item was opened.
+    Created,
+    /// When the item was last commented on, revised, reviewed or merged.
+    Updated,
+    Title,
+}
+
+/// Sort direction.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// State of an item, as filtered by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Open,
+    Closed,
+    Merged,
+}
+
+impl FromStr for State {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(Self::Open),
+            "closed" => Ok(Self::Closed),
+            "merged" => Ok(Self::Merged),
+            _ => Err(Error::BadRequest(format!("invalid state {:?}", s))),
+        }
+    }
+}
+
+/// An item of a listing.
+pub trait Listable {
+    fn state(&self) -> State;
+    fn author(&self) -> &Author;
+    fn labels(&self) -> Vec<Label>;
+    fn title(&self) -> &str;
+    fn description(&self) -> &str;
+    fn created(&self) -> Timestamp;
+    fn updated(&self) -> Timestamp;
+}
+
+impl Listable for Issue {
+    fn state(&self) -> State {
+        match self.state {
+            issue::State::Open => State::Open,
+            issue::State::Closed { .. } => State::Closed,
+        }
+    }
+
+    fn author(&self) -> &Author {
+        &self.author
+    }
+
+    fn labels(&self) -> Vec<Label> {
+        self.labels.iter().cloned().collect()
+    }
+
+    fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn description(&self) -> &str {
+        &self.comment.body
+    }
+
+    fn created(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    fn updated(&self) -> Timestamp {
+        self.discussion
+            .iter()
+            .flat_map(|c| std::iter::once(c.timestamp).chain(c.replies.iter().map(|r| r.timestamp)))
+            .fold(self.timestamp, std::cmp::max)
+    }
+}
+
+impl Listable for Patch {
+    fn state(&self) -> State {
+        if self.revisions.iter().any(|r| !r.merges.is_empty()) {
+            State::Merged
+        } else if self.state == patch::State::Proposed {
+            State::Open
+        } else {
+            State::Closed
+        }
+    }
+
+    fn author(&self) -> &Author {
+        &self.author
+    }
+
+    fn labels(&self) -> Vec<Label> {
+        self.labels.iter().cloned().collect()
+    }
+
+    fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn description(&self) -> &str {
+        &self.revisions.last().comment.body
+    }
+
+    fn created(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    fn updated(&self) -> Timestamp {
+        self.revisions
+            .iter()
+            .flat_map(|r| {
+                std::iter::once(r.timestamp)
+                    .chain(r.discussion.iter().map(|c| c.timestamp))
+                    .chain(r.reviews.values().map(|v| v.timestamp))
+                    .chain(r.merges.iter().map(|m| m.timestamp))
+            })
+            .fold(self.timestamp, std::cmp::max)
+    }
+}
+
+/// What is filtered and sorted by of an issue or patch.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub id: ObjectId,
+    pub kind: Kind,
+    pub state: State,
+    /// URN of the author.
+    pub author: String,
+    /// Peer id of the author.
+    pub peer: String,
+    pub labels: Vec<Label>,
+    pub title: String,
+    /// Title and description, in lowercase.
+    pub text: String,
+    pub created: Timestamp,
+    pub updated: Timestamp,
+}
+
+impl Entry {
+    pub fn new<T: Listable>(id: ObjectId, kind: Kind, item: &T) -> Self {
+        Self {
+            id,
+            kind,
+            state: item.state(),
+            author: item.author().urn.to_string(),
+            peer: item.author().peer.to_string(),
+            labels: item.labels(),
+            title: item.title().to_owned(),
+            text: format!("{}\n{}", item.title(), item.description()).to_lowercase(),
+            created: item.created(),
+            updated: item.updated(),
+        }
+    }
+}
+
+impl ListingQueryString {
+    /// Filter, sort and paginate entries, returning the ids of the page and the total number of
+    /// matches.
+    pub fn apply<'a>(
+        &self,
+        entries: impl IntoIterator<Item = &'a Entry>,
+    ) -> Result<(Vec<ObjectId>, usize), Error> {
+        let state = self.state.as_deref().map(State::from_str).transpose()?;
+        let label = self
+            .label
+            .as_deref()
+            .map(|l| {
+                Label::from_str(l).map_err(|_| Error::BadRequest(format!("invalid label {:?}", l)))
+            })
+            .transpose()?;
+        let q = self.q.as_deref().map(str::to_lowercase);
+
+        let mut matches = entries
+            .into_iter()
+            .filter(|e| state.map_or(true, |s| e.state == s))
+            .filter(|e| {
+                self.author
+                    .as_deref()
+                    .map_or(true, |a| e.author == a || e.peer == a)
+            })
+            .filter(|e| label.as_ref().map_or(true, |l| e.labels.contains(l)))
+            .filter(|e| q.as_deref().map_or(true, |q| e.text.contains(q)))
+            .collect::<Vec<_>>();
+
+        // Newest first, unless asked otherwise.
+        let sort = self.sort.unwrap_or(Sort::Created);
+        let order = self.order.unwrap_or(match sort {
+            Sort::Title => Order::Asc,
+            Sort::Created | Sort::Updated => Order::Desc,
+        });
+        match sort {
+            Sort::Created => matches.sort_by_key(|e| e.created),
+            Sort::Updated => matches.sort_by_key(|e| e.updated),
+            Sort::Title => matches.sort_by_cached_key(|e| e.title.to_lowercase()),
+        }
+        if order == Order::Desc {
+            matches.reverse();
+        }
+
+        let total = matches.len();
+        let page = self.page.unwrap_or(0);
+        let per_page = self.per_page.unwrap_or(DEFAULT_PER_PAGE);
+        let page = matches
+            .into_iter()
+            .skip(page * per_page)
+            .take(per_page)
+            .map(|e| e.id)
+            .collect();
+
+        Ok((page, total))
+    }
+}
+
+/// Headers of a listing response.
+pub fn headers(total: usize) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        HeaderName::from_static(X_TOTAL_COUNT),
+        HeaderValue::from(total),
+    );
+
+    headers
+}
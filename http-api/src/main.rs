@@ -1,8 +1,13 @@
-use std::net;
+use std::num::NonZeroU32;
 use std::path::PathBuf;
-use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, net, process};
 
+use hyper::http::uri::{Authority, Uri};
+use hyper::http::HeaderValue;
 use radicle_http_api as api;
+use shared::proxy::{ForwardingHeader, IpNet};
 
 use argh::FromArgs;
 
@@ -32,11 +37,87 @@ pub struct Options {
     /// syntax highlight theme
     #[argh(option, default = r#"String::from("base16-ocean.dark")"#)]
     pub theme: String,
+
+    /// trust the forwarding headers of proxies in this network, eg. 10.0.0.0/8 (may be repeated)
+    #[argh(option)]
+    pub trusted_proxy: Vec<IpNet>,
+
+    /// forwarding header set by the trusted proxies, `x-forwarded-for` or `forwarded`; other
+    /// forwarding headers are ignored (default: x-forwarded-for)
+    #[argh(option, default = "ForwardingHeader::XForwardedFor")]
+    pub proxy_header: ForwardingHeader,
+
+    /// expect a PROXY protocol v2 header from trusted proxies on every connection
+    #[argh(switch)]
+    pub proxy_protocol: bool,
+
+    /// maximum number of requests per minute from a single client address (default: unlimited)
+    #[argh(option)]
+    pub rate_limit: Option<NonZeroU32>,
+
+    /// event log path, used to resume event streams (default: events.log in the monorepo)
+    #[argh(option)]
+    pub events_log: Option<PathBuf>,
+
+    /// where to keep sessions: `memory`, or `file:<directory>` to keep them across restarts
+    /// (default: memory)
+    #[argh(option, default = "api::SessionBackend::Memory")]
+    pub session_store: api::SessionBackend,
+
+    /// also send sessions as `HttpOnly; Secure; SameSite=Strict` cookies once signed in
+    #[argh(switch)]
+    pub session_cookie: bool,
+
+    /// allow cross-origin requests with credentials, eg. session cookies, from this origin, eg.
+    /// https://app.example.com (may be repeated; default: any origin, without credentials)
+    #[argh(option)]
+    pub allowed_origin: Vec<HeaderValue>,
+
+    /// accept sign-in messages for this domain (may be repeated; default: $RADICLE_DOMAIN)
+    #[argh(option)]
+    pub siwe_domain: Vec<Authority>,
+
+    /// only accept sign-in messages for this URI (may be repeated)
+    #[argh(option)]
+    pub siwe_uri: Vec<String>,
+
+    /// only accept sign-in messages for this chain ID, eg. 1 for mainnet (may be repeated)
+    #[argh(option)]
+    pub siwe_chain_id: Vec<u64>,
+
+    /// only accept sign-in messages with this statement
+    #[argh(option)]
+    pub siwe_statement: Option<String>,
+
+    /// maximum lifetime of signed-in sessions, in seconds, before they have to be refreshed
+    /// (default: 604800)
+    #[argh(option, default = "api::DEFAULT_SESSION_LIFETIME.as_secs()")]
+    pub session_lifetime: u64,
+
+    /// Ethereum JSON-RPC endpoint, used to verify the signatures of contract wallets, eg. multisigs
+    #[argh(option)]
+    pub eth_rpc_url: Option<Uri>,
+
+    /// let signed-in users whose account isn't linked to a person open and comment on issues
+    /// through the seed's identity
+    #[argh(switch)]
+    pub cob_proxy: bool,
 }
 
 impl Options {
     pub fn from_env() -> Self {
-        argh::from_env()
+        let mut options: Self = argh::from_env();
+
+        // Fall back to the domain of the seed, for compatibility.
+        if options.siwe_domain.is_empty() {
+            if let Some(domain) = env::var("RADICLE_DOMAIN")
+                .ok()
+                .and_then(|d| Authority::from_str(&d).ok())
+            {
+                options.siwe_domain.push(domain);
+            }
+        }
+        options
     }
 }
 
@@ -49,6 +130,23 @@ impl From<Options> for api::Options {
             tls_key: other.tls_key,
             listen: other.listen,
             theme: other.theme,
+            trusted_proxies: other.trusted_proxy,
+            proxy_header: other.proxy_header,
+            proxy_protocol: other.proxy_protocol,
+            rate_limit: other.rate_limit,
+            events_log: other.events_log,
+            session_store: other.session_store,
+            session_cookie: other.session_cookie,
+            allowed_origins: other.allowed_origin,
+            siwe: api::SiweConfig {
+                domains: other.siwe_domain,
+                uris: other.siwe_uri,
+                chain_ids: other.siwe_chain_id,
+                statement: other.siwe_statement,
+                session_lifetime: Duration::from_secs(other.session_lifetime),
+                eth_rpc_url: other.eth_rpc_url,
+            },
+            cob_proxy: other.cob_proxy,
         }
     }
 }
@@ -0,0 +1,158 @@
+//! Mergeability of patch revisions.
+//!
+//! A revision is mergeable if merging it into the head of the project's default branch doesn't
+//! result in conflicts. Test merges are done in memory, and cached by `(base, head)`, since
+//! neither side of a merge changes once it is known.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use git2::Oid;
+use serde::Serialize;
+
+/// Number of results kept in the cache.
+pub const CACHE_CAPACITY: usize = 4096;
+
+/// Whether a revision can be merged into the default branch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mergeability {
+    /// Head of the default branch the revision was merged into.
+    pub base: String,
+    /// Whether the revision merges without conflicts.
+    pub mergeable: bool,
+    /// Paths of the conflicting files.
+    pub conflicts: Vec<String>,
+    /// Number of commits of the default branch that the revision doesn't have.
+    pub behind_by: usize,
+}
+
+impl Mergeability {
+    /// Merge `head` into `base` in memory.
+    pub fn compute(repo: &git2::Repository, base: Oid, head: Oid) -> Result<Self, git2::Error> {
+        let (_, behind_by) = repo.graph_ahead_behind(head, base)?;
+        let index = repo.merge_commits(&repo.find_commit(base)?, &repo.find_commit(head)?, None)?;
+        let mut conflicts = Vec::new();
+
+        if index.has_conflicts() {
+            for conflict in index.conflicts()? {
+                let conflict = conflict?;
+                let entry = conflict.our.or(conflict.their).or(conflict.ancestor);
+
+                if let Some(entry) = entry {
+                    conflicts.push(String::from_utf8_lossy(&entry.path).into_owned());
+                }
+            }
+        }
+
+        Ok(Self {
+            base: base.to_string(),
+            mergeable: conflicts.is_empty(),
+            conflicts,
+            behind_by,
+        })
+    }
+}
+
+/// Mergeability results, by `(base, head)`.
+#[derive(Debug, Default)]
+pub struct Cache {
+    results: Mutex<HashMap<(Oid, Oid), Mergeability>>,
+}
+
+impl Cache {
+    /// Get the mergeability of `head` into `base`, computing it if it isn't cached.
+    pub fn get(
+        &self,
+        repo: &git2::Repository,
+        base: Oid,
+        head: Oid,
+    ) -> Result<Mergeability, git2::Error> {
+        if let Some(result) = self.lock().get(&(base, head)) {
+            return Ok(result.clone());
+        }
+        let result = Mergeability::compute(repo, base, head)?;
+        let mut results = self.lock();
+
+        // The default branch moves forward, so older results are rarely needed again.
+        if results.len() >= CACHE_CAPACITY {
+            results.clear();
+        }
+        results.insert((base, head), result.clone());
+
+        Ok(result)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(Oid, Oid), Mergeability>> {
+        self.results.lock().expect("cache lock is not poisoned")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Commit the given files on top of `parent`, keeping the parent's other files.
+    fn commit(repo: &git2::Repository, parent: Option<Oid>, files: &[(&str, &str)]) -> Oid {
+        let sig = git2::Signature::now("radicle", "radicle@localhost").unwrap();
+        let parent = parent.map(|oid| repo.find_commit(oid).unwrap());
+        let base = parent.as_ref().map(|c| c.tree().unwrap());
+        let mut tree = repo.treebuilder(base.as_ref()).unwrap();
+
+        for (path, content) in files {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            tree.insert(*path, blob, 0o100644).unwrap();
+        }
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+
+        repo.commit(
+            None,
+            &sig,
+            &sig,
+            "",
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_mergeability() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path()).unwrap();
+        let root = commit(&repo, None, &[("README", "Hello.\n"), ("LICENSE", "MIT\n")]);
+        let main = commit(&repo, Some(root), &[("README", "Hello, world.\n")]);
+        let main = commit(&repo, Some(main), &[("CHANGELOG", "0.1.0\n")]);
+
+        // Changes to other files merge cleanly.
+        let clean = commit(&repo, Some(root), &[("LICENSE", "MIT or Apache-2.0\n")]);
+        let result = Mergeability::compute(&repo, main, clean).unwrap();
+
+        assert!(result.mergeable);
+        assert!(result.conflicts.is_empty());
+        assert_eq!(result.behind_by, 2);
+        assert_eq!(result.base, main.to_string());
+
+        // Changes to the same lines conflict.
+        let conflicting = commit(&repo, Some(root), &[("README", "Goodbye.\n")]);
+        let result = Mergeability::compute(&repo, main, conflicting).unwrap();
+
+        assert!(!result.mergeable);
+        assert_eq!(result.conflicts, vec![String::from("README")]);
+        assert_eq!(result.behind_by, 2);
+
+        // Revisions based on the head aren't behind.
+        let rebased = commit(&repo, Some(main), &[("README", "Goodbye.\n")]);
+        let result = Mergeability::compute(&repo, main, rebased).unwrap();
+
+        assert!(result.mergeable);
+        assert_eq!(result.behind_by, 0);
+
+        // Results are cached, and unknown revisions fail to merge.
+        let cache = Cache::default();
+        assert_eq!(
+            cache.get(&repo, main, rebased).unwrap(),
+            cache.get(&repo, main, rebased).unwrap()
+        );
+        assert!(cache.get(&repo, main, Oid::zero()).is_err());
+    }
+}
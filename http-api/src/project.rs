@@ -45,7 +45,7 @@ pub fn tracked<S: AsRef<ReadOnly>>(meta: &Metadata, storage: &S) -> Result<Vec<P
     Ok(result)
 }
 
-mod option {
+pub mod option {
     use std::fmt::Display;
 
     use serde::Serializer;
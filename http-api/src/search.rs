@@ -0,0 +1,559 @@
+//! Seed-wide search of projects, issues and patches.
+//!
+//! Searchable text is kept in an inverted index, mapping each term to the documents it appears
+//! in. Documents are indexed per project: when the refs of a project change, its documents are
+//! rebuilt, and the rest of the index is left untouched.
+//!
+//! Projects are re-indexed as the git-server notifies of their changes, but collaborative objects
+//! can also be replicated from peers, without notification. The index is thus reconciled with the
+//! monorepo periodically: the refs of each project are hashed, and projects whose refs changed since
+//! they were indexed are re-indexed.
+//!
+//! The index also keeps the listing entries of each project's issues and patches, so that
+//! listings don't have to load every collaborative object. Private projects aren't indexed.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use librad::collaborative_objects::ObjectId;
+use librad::git::identities::{self, SomeIdentity};
+use librad::git::{Storage, Urn};
+use librad::paths::Paths;
+
+use radicle_common::{cobs, person};
+
+use shared::access::{self, Access};
+
+use crate::error::Error;
+use crate::listing::{Entry, ListingQueryString};
+use crate::project::{self, Delegate};
+
+/// Weight of terms in titles and project names.
+const TITLE_WEIGHT: u32 = 4;
+/// Weight of terms in delegate names.
+const DELEGATE_WEIGHT: u32 = 2;
+/// Weight of terms in descriptions, bodies and comments.
+const TEXT_WEIGHT: u32 = 1;
+
+/// Searchable text of a document, with its weight.
+type Fields = Vec<(String, u32)>;
+
+/// Kind of a search result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Kind {
+    Project,
+    Issue,
+    Patch,
+}
+
+/// A searchable document.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Document {
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    /// Project the document belongs to.
+    pub project: Urn,
+    /// Id of the issue or patch.
+    pub id: Option<String>,
+    /// Project name, or issue or patch title.
+    pub title: String,
+}
+
+/// A ranked search result.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hit {
+    #[serde(flatten)]
+    pub document: Document,
+    pub score: f64,
+}
+
+/// Identifies a document within the index.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    project: String,
+    id: Option<String>,
+}
+
+impl From<&Document> for Key {
+    fn from(doc: &Document) -> Self {
+        Self {
+            project: doc.project.to_string(),
+            id: doc.id.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    documents: HashMap<Key, Document>,
+    /// Weighted number of occurences of each term, by document.
+    postings: BTreeMap<String, HashMap<Key, u32>>,
+    /// Terms of each document, to remove them when the document is re-indexed.
+    terms: HashMap<Key, HashSet<String>>,
+    /// Listing entries of the issues and patches of each indexed project.
+    listings: HashMap<String, Vec<Entry>>,
+    /// Version of each namespace when it was last indexed, see [`versions`].
+    versions: HashMap<String, String>,
+}
+
+/// Inverted index of projects, issues and patches.
+#[derive(Debug, Default)]
+pub struct Index {
+    inner: RwLock<Inner>,
+}
+
+impl Index {
+    /// Index all projects of the monorepo, replacing the index.
+    pub fn build(&self, storage: &Storage, paths: &Paths) -> Result<(), Error> {
+        let mut urns = Vec::new();
+
+        for identity in identities::any::list(storage.read_only())?.flatten() {
+            if let SomeIdentity::Project(project) = identity {
+                urns.push(project.urn());
+            }
+        }
+        *self.write() = Inner::default();
+
+        for urn in urns {
+            if let Err(err) = self.update(storage, paths, &urn) {
+                tracing::warn!("Failed to index project {}: {}", urn, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-index a project, its issues and its patches. Projects that no longer exist are removed
+    /// from the index. Projects that fail to be indexed are only retried once their refs change.
+    pub fn update(&self, storage: &Storage, paths: &Paths, urn: &Urn) -> Result<(), Error> {
+        let namespace = urn.encode_id();
+        let version = versions(paths, Some(&namespace))?.remove(&namespace);
+        let result = documents(storage, paths, urn);
+
+        match version {
+            Some(version) => self.write().versions.insert(namespace, version),
+            None => self.write().versions.remove(&namespace),
+        };
+        let (documents, entries) = result?;
+        self.replace(urn, documents, entries);
+
+        Ok(())
+    }
+
+    /// Re-index the projects whose refs changed since they were indexed, and remove the projects
+    /// that no longer exist. Returns the number of namespaces that were re-indexed.
+    pub fn reconcile(&self, storage: &Storage, paths: &Paths) -> Result<usize, Error> {
+        let current = versions(paths, None)?;
+        let stale = {
+            let inner = self.read();
+            let mut stale = current
+                .iter()
+                .filter(|(namespace, version)| inner.versions.get(*namespace) != Some(version))
+                .map(|(namespace, _)| namespace.clone())
+                .collect::<Vec<_>>();
+
+            stale.extend(
+                inner
+                    .versions
+                    .keys()
+                    .filter(|namespace| !current.contains_key(*namespace))
+                    .cloned(),
+            );
+            stale
+        };
+
+        for namespace in &stale {
+            let urn = match Urn::try_from_id(namespace) {
+                Ok(urn) => urn,
+                Err(_) => continue,
+            };
+            if let Err(err) = self.update(storage, paths, &urn) {
+                tracing::warn!("Failed to index project {}: {}", urn, err);
+            }
+        }
+        Ok(stale.len())
+    }
+
+    /// Replace the documents and listing entries of a project. Projects without documents no
+    /// longer exist.
+    fn replace(&self, urn: &Urn, documents: Vec<(Document, Fields)>, entries: Vec<Entry>) {
+        let project = urn.to_string();
+        let mut inner = self.write();
+
+        if documents.is_empty() {
+            inner.listings.remove(&project);
+        } else {
+            inner.listings.insert(project.clone(), entries);
+        }
+        let stale = inner
+            .documents
+            .keys()
+            .filter(|k| k.project == project)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for key in stale {
+            inner.remove(&key);
+        }
+        for (doc, fields) in documents {
+            let key = Key::from(&doc);
+            let mut terms = HashSet::new();
+
+            for (text, weight) in fields {
+                for term in tokenize(&text) {
+                    *inner
+                        .postings
+                        .entry(term.clone())
+                        .or_default()
+                        .entry(key.clone())
+                        .or_default() += weight;
+                    terms.insert(term);
+                }
+            }
+            inner.terms.insert(key.clone(), terms);
+            inner.documents.insert(key, doc);
+        }
+    }
+
+    /// Search the index. Documents must match every term of the query, either exactly or by
+    /// prefix, and are ranked by the weighted frequency of the terms, rarer terms counting more.
+    pub fn search(&self, query: &str, kind: Option<Kind>) -> Vec<Hit> {
+        let inner = self.read();
+        let total = inner.documents.len() as f64;
+        let mut scores: Option<HashMap<&Key, f64>> = None;
+
+        for token in tokenize(query) {
+            let mut matches: HashMap<&Key, f64> = HashMap::new();
+
+            for (term, postings) in inner
+                .postings
+                .range(token.clone()..)
+                .take_while(|(term, _)| term.starts_with(&token))
+            {
+                // Prefix matches count half as much as exact ones.
+                let exact = if *term == token { 1. } else { 0.5 };
+                let idf = (total / postings.len() as f64).ln() + 1.;
+
+                for (key, weight) in postings {
+                    *matches.entry(key).or_default() += exact * idf * *weight as f64;
+                }
+            }
+            scores = Some(match scores {
+                None => matches,
+                Some(scores) => scores
+                    .into_iter()
+                    .filter_map(|(key, score)| matches.get(key).map(|s| (key, score + s)))
+                    .collect(),
+            });
+        }
+
+        let mut hits = scores
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(key, score)| {
+                let document = inner.documents.get(key)?;
+                if kind.map_or(true, |k| k == document.kind) {
+                    Some(Hit {
+                        document: document.clone(),
+                        score,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        hits.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| a.document.title.cmp(&b.document.title))
+        });
+        hits
+    }
+
+    /// Filter, sort and paginate the issues or patches of a project, returning the ids of the
+    /// requested page and the total number of matches. Projects that weren't indexed yet, eg.
+    /// while the index is being built, are indexed first.
+    pub fn list(
+        &self,
+        storage: &Storage,
+        paths: &Paths,
+        urn: &Urn,
+        kind: Kind,
+        qs: &ListingQueryString,
+    ) -> Result<(Vec<ObjectId>, usize), Error> {
+        if !self.read().listings.contains_key(&urn.to_string()) {
+            self.update(storage, paths, urn)?;
+        }
+        self.listing(urn, kind, qs)
+    }
+
+    /// Filter, sort and paginate the indexed issues or patches of a project.
+    fn listing(
+        &self,
+        urn: &Urn,
+        kind: Kind,
+        qs: &ListingQueryString,
+    ) -> Result<(Vec<ObjectId>, usize), Error> {
+        let inner = self.read();
+        let entries = inner
+            .listings
+            .get(&urn.to_string())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        qs.apply(entries.iter().filter(|e| e.kind == kind))
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Inner> {
+        self.inner.read().expect("index lock is not poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Inner> {
+        self.inner.write().expect("index lock is not poisoned")
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &Key) {
+        self.documents.remove(key);
+
+        for term in self.terms.remove(key).unwrap_or_default() {
+            if let Some(postings) = self.postings.get_mut(&term) {
+                postings.remove(key);
+
+                if postings.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+}
+
+/// Split text into lowercase alphanumeric terms.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Get the version of each namespace of the monorepo, or of a single namespace: a hash of its refs
+/// and of its visibility, which changes whenever something that is indexed does.
+fn versions(paths: &Paths, namespace: Option<&str>) -> Result<HashMap<String, String>, Error> {
+    let repo = git2::Repository::open_bare(paths.git_dir())?;
+    let config = git2::Config::open(&paths.git_dir().join("config"))?.snapshot()?;
+    let private = access::private(&config)?;
+    let mut refs: HashMap<String, BTreeMap<String, git2::Oid>> = HashMap::new();
+
+    for r in repo.references_glob(&format!("refs/namespaces/{}/*", namespace.unwrap_or("*")))? {
+        let r = r?;
+        let (name, target) = match (r.name(), r.target()) {
+            (Some(name), Some(target)) => (name, target),
+            // Symbolic refs, such as `HEAD`, point to refs that are hashed already.
+            _ => continue,
+        };
+        if let Some((ns, _)) = name
+            .strip_prefix("refs/namespaces/")
+            .and_then(|n| n.split_once('/'))
+        {
+            refs.entry(ns.to_owned())
+                .or_default()
+                .insert(name.to_owned(), target);
+        }
+    }
+
+    Ok(refs
+        .into_iter()
+        .map(|(ns, refs)| {
+            let mut hasher = Sha256::new();
+
+            for (name, target) in refs {
+                hasher.update(name.as_bytes());
+                hasher.update(target.as_bytes());
+            }
+            hasher.update([private.contains(&ns) as u8]);
+
+            (ns, format!("{:x}", hasher.finalize()))
+        })
+        .collect())
+}
+
+/// Get the documents of a project, with their weighted text, and the listing entries of its
+/// issues and patches.
+fn documents(
+    storage: &Storage,
+    paths: &Paths,
+    urn: &Urn,
+) -> Result<(Vec<(Document, Fields)>, Vec<Entry>), Error> {
+    let mut documents = Vec::new();
+    let mut entries = Vec::new();
+
+    // Private projects are hidden from the API, so they aren't indexed.
+    let config = git2::Config::open(&paths.git_dir().join("config"))?;
+    if Access::load(&config, &urn.encode_id())?.is_private() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+    let project = match identities::project::get(storage, urn).map_err(Error::Identities)? {
+        Some(project) => project,
+        None => return Ok((Vec::new(), Vec::new())),
+    };
+    let meta: project::Metadata = project.try_into().map_err(Error::Project)?;
+
+    let mut fields = vec![
+        (meta.name.clone(), TITLE_WEIGHT),
+        (meta.description.clone(), TEXT_WEIGHT),
+    ];
+    for delegate in &meta.delegates {
+        if let Delegate::Indirect { urn, .. } = delegate {
+            if let Some(person) =
+                identities::person::get(storage, urn).map_err(Error::Identities)?
+            {
+                fields.push((person.payload().subject.name.to_string(), DELEGATE_WEIGHT));
+            }
+        }
+    }
+    documents.push((
+        Document {
+            kind: Kind::Project,
+            project: urn.clone(),
+            id: None,
+            title: meta.name.clone(),
+        },
+        fields,
+    ));
+
+    let whoami = person::local(storage).map_err(Error::LocalIdentity)?;
+    let store = cobs::Store::new(whoami, paths, storage);
+
+    for (id, issue) in store.issues().all(urn).map_err(Error::Cobs)? {
+        entries.push(Entry::new(id, Kind::Issue, &issue));
+
+        let mut fields = vec![
+            (issue.title.clone(), TITLE_WEIGHT),
+            (issue.comment.body.clone(), TEXT_WEIGHT),
+        ];
+        for comment in &issue.discussion {
+            fields.push((comment.body.clone(), TEXT_WEIGHT));
+            fields.extend(
+                comment
+                    .replies
+                    .iter()
+                    .map(|r| (r.body.clone(), TEXT_WEIGHT)),
+            );
+        }
+        documents.push((
+            Document {
+                kind: Kind::Issue,
+                project: urn.clone(),
+                id: Some(id.to_string()),
+                title: issue.title,
+            },
+            fields,
+        ));
+    }
+
+    for (id, patch) in store.patches().all(urn).map_err(Error::Cobs)? {
+        entries.push(Entry::new(id, Kind::Patch, &patch));
+
+        let mut fields = vec![(patch.title.clone(), TITLE_WEIGHT)];
+
+        for revision in patch.revisions.iter() {
+            fields.push((revision.comment.body.clone(), TEXT_WEIGHT));
+            fields.extend(
+                revision
+                    .discussion
+                    .iter()
+                    .map(|c| (c.body.clone(), TEXT_WEIGHT)),
+            );
+            fields.extend(
+                revision
+                    .reviews
+                    .values()
+                    .map(|r| (r.comment.body.clone(), TEXT_WEIGHT)),
+            );
+        }
+        documents.push((
+            Document {
+                kind: Kind::Patch,
+                project: urn.clone(),
+                id: Some(id.to_string()),
+                title: patch.title,
+            },
+            fields,
+        ));
+    }
+    Ok((documents, entries))
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn document(project: &Urn, id: Option<&str>, title: &str, body: &str) -> (Document, Fields) {
+        let kind = if id.is_some() {
+            Kind::Issue
+        } else {
+            Kind::Project
+        };
+        let doc = Document {
+            kind,
+            project: project.clone(),
+            id: id.map(ToOwned::to_owned),
+            title: title.to_owned(),
+        };
+        let fields = vec![
+            (title.to_owned(), TITLE_WEIGHT),
+            (body.to_owned(), TEXT_WEIGHT),
+        ];
+        (doc, fields)
+    }
+
+    #[test]
+    fn test_search() {
+        let urn = Urn::from_str("rad:git:hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo").unwrap();
+        let index = Index::default();
+
+        index.replace(
+            &urn,
+            vec![
+                document(
+                    &urn,
+                    None,
+                    "nakamoto",
+                    "Privacy-preserving Bitcoin light-client",
+                ),
+                document(&urn, Some("1"), "Bitcoin fees are wrong", "See the client."),
+                document(&urn, Some("2"), "Crash on startup", "The client crashes."),
+            ],
+            Vec::new(),
+        );
+
+        // Titles rank higher than bodies.
+        let hits = index.search("bitcoin", None);
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].document.id.as_deref(), Some("1"));
+
+        // Every term must match, exactly or by prefix.
+        let hits = index.search("CLIENT crash", None);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].document.id.as_deref(), Some("2"));
+
+        let hits = index.search("client", Some(Kind::Project));
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].document.title, "nakamoto");
+
+        // Re-indexing a project replaces its documents.
+        index.replace(&urn, vec![document(&urn, None, "nakamoto", "")], Vec::new());
+        assert!(index.search("bitcoin", None).is_empty());
+        assert!(index.read().postings.get("crash").is_none());
+        assert!(index.search("", None).is_empty());
+    }
+}
@@ -0,0 +1,327 @@
+//! Storage of SIWE sessions.
+//!
+//! Sessions are kept in memory by default, and are lost on restart. With the file store, each
+//! session is stored in its own file, so that sessions survive restarts and can be shared by
+//! several instances of the API using the same directory.
+//!
+//! Since stores may do file I/O, they are accessed through [`Sessions`], from blocking tasks.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write as _};
+use std::os::unix::fs::{DirBuilderExt as _, OpenOptionsExt as _};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Utc};
+
+use crate::auth::AuthState;
+use crate::error::Error;
+use crate::SessionId;
+
+/// A store of sessions.
+pub trait SessionStore: Send + Sync {
+    /// Get a session.
+    fn get(&self, id: &str) -> Result<Option<AuthState>, Error>;
+    /// Insert or replace a session.
+    fn insert(&self, id: &str, state: AuthState) -> Result<(), Error>;
+    /// Remove a session, returning it if it existed.
+    fn remove(&self, id: &str) -> Result<Option<AuthState>, Error>;
+    /// Remove the sessions that expired at the given time, returning how many were removed.
+    fn cleanup(&self, now: DateTime<Utc>) -> Result<usize, Error>;
+}
+
+/// Sessions, whose store is accessed from blocking tasks.
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStore::default()))
+    }
+}
+
+impl Sessions {
+    pub fn new(store: Arc<dyn SessionStore>) -> Self {
+        Self { store }
+    }
+
+    /// Get a session.
+    pub async fn get(&self, id: &str) -> Result<Option<AuthState>, Error> {
+        let (store, id) = (self.store.clone(), id.to_owned());
+
+        tokio::task::spawn_blocking(move || store.get(&id)).await?
+    }
+
+    /// Insert or replace a session.
+    pub async fn insert(&self, id: &str, state: AuthState) -> Result<(), Error> {
+        let (store, id) = (self.store.clone(), id.to_owned());
+
+        tokio::task::spawn_blocking(move || store.insert(&id, state)).await?
+    }
+
+    /// Remove a session, returning it if it existed.
+    pub async fn remove(&self, id: &str) -> Result<Option<AuthState>, Error> {
+        let (store, id) = (self.store.clone(), id.to_owned());
+
+        tokio::task::spawn_blocking(move || store.remove(&id)).await?
+    }
+
+    /// Remove the sessions that expired at the given time, returning how many were removed.
+    pub async fn cleanup(&self, now: DateTime<Utc>) -> Result<usize, Error> {
+        let store = self.store.clone();
+
+        tokio::task::spawn_blocking(move || store.cleanup(now)).await?
+    }
+}
+
+/// Session store backend, as selected on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// Keep sessions in memory.
+    Memory,
+    /// Keep sessions in files, in the given directory.
+    File(PathBuf),
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::Memory
+    }
+}
+
+impl Backend {
+    /// Open the session store.
+    pub fn open(&self) -> io::Result<Sessions> {
+        match self {
+            Self::Memory => Ok(Sessions::default()),
+            Self::File(dir) => Ok(Sessions::new(Arc::new(FileStore::open(dir.clone())?))),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "memory" => Ok(Self::Memory),
+            Some(("file", path)) if !path.is_empty() => Ok(Self::File(PathBuf::from(path))),
+            _ => Err(String::from(
+                "session store must be 'memory' or 'file:<directory>'",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Memory => write!(f, "memory"),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// Sessions kept in memory.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: RwLock<HashMap<SessionId, AuthState>>,
+}
+
+impl SessionStore for MemoryStore {
+    fn get(&self, id: &str) -> Result<Option<AuthState>, Error> {
+        let sessions = self.sessions.read().expect("session lock is not poisoned");
+
+        Ok(sessions.get(id).cloned())
+    }
+
+    fn insert(&self, id: &str, state: AuthState) -> Result<(), Error> {
+        let mut sessions = self.sessions.write().expect("session lock is not poisoned");
+        sessions.insert(id.to_owned(), state);
+
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<Option<AuthState>, Error> {
+        let mut sessions = self.sessions.write().expect("session lock is not poisoned");
+
+        Ok(sessions.remove(id))
+    }
+
+    fn cleanup(&self, now: DateTime<Utc>) -> Result<usize, Error> {
+        let mut sessions = self.sessions.write().expect("session lock is not poisoned");
+        let count = sessions.len();
+
+        sessions.retain(|_, state| !state.is_expired(now));
+
+        Ok(count - sessions.len())
+    }
+}
+
+/// Sessions kept in a directory, one JSON file per session. Session ids are secret, as they name
+/// the files, so the directory and files are only accessible to their owner.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Open the store in the given directory, creating it if necessary.
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf, Error> {
+        // Session ids are hex-encoded; anything else could escape the directory.
+        if !is_session_id(id) {
+            return Err(Error::NotFound);
+        }
+        Ok(self.dir.join(id).with_extension("json"))
+    }
+}
+
+/// Whether a string is a valid session id.
+fn is_session_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Whether a file in the store directory holds a session, ie. is named `<id>.json`.
+fn is_session_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".json"))
+        .map_or(false, is_session_id)
+}
+
+impl SessionStore for FileStore {
+    fn get(&self, id: &str) -> Result<Option<AuthState>, Error> {
+        let path = match self.path(id) {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| Error::Io("failed to parse session", e.into())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::Io("failed to read session", err)),
+        }
+    }
+
+    fn insert(&self, id: &str, state: AuthState) -> Result<(), Error> {
+        let path = self.path(id)?;
+        let tmp = path.with_extension("json.tmp");
+        let bytes = serde_json::to_vec(&state)
+            .map_err(|e| Error::Io("failed to serialize session", e.into()))?;
+
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut file| file.write_all(&bytes))
+            .map_err(|e| Error::Io("failed to write session", e))?;
+        fs::rename(&tmp, &path).map_err(|e| Error::Io("failed to write session", e))?;
+
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<Option<AuthState>, Error> {
+        let state = self.get(id)?;
+
+        if state.is_some() {
+            match fs::remove_file(self.path(id)?) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(Error::Io("failed to remove session", err)),
+            }
+        }
+        Ok(state)
+    }
+
+    fn cleanup(&self, now: DateTime<Utc>) -> Result<usize, Error> {
+        let entries =
+            fs::read_dir(&self.dir).map_err(|e| Error::Io("failed to read sessions", e))?;
+        let mut removed = 0;
+
+        for entry in entries {
+            let path = entry
+                .map_err(|e| Error::Io("failed to read sessions", e))?
+                .path();
+            // Other files, eg. sessions being written, are left alone.
+            if !is_session_file(&path) {
+                continue;
+            }
+            let expired = match fs::read(&path) {
+                Ok(bytes) => match serde_json::from_slice::<AuthState>(&bytes) {
+                    Ok(state) => state.is_expired(now),
+                    // Partially written or corrupted sessions are of no use.
+                    Err(_) => true,
+                },
+                Err(_) => continue,
+            };
+            if expired && fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unauthorized(expiration_time: DateTime<Utc>) -> AuthState {
+        AuthState::Unauthorized {
+            nonce: String::from("nonce"),
+            expiration_time,
+        }
+    }
+
+    #[test]
+    fn test_backend_from_str() {
+        assert_eq!("memory".parse(), Ok(Backend::Memory));
+        assert_eq!(
+            "file:/var/lib/sessions".parse(),
+            Ok(Backend::File(PathBuf::from("/var/lib/sessions")))
+        );
+        assert!("file:".parse::<Backend>().is_err());
+        assert!("redis".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn test_file_store_cleanup() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("sessions");
+        let store = FileStore::open(dir.clone()).unwrap();
+        let now = Utc::now();
+
+        store
+            .insert("aa", unauthorized(now - chrono::Duration::seconds(1)))
+            .unwrap();
+        store
+            .insert("bb", unauthorized(now + chrono::Duration::seconds(60)))
+            .unwrap();
+
+        fs::write(dir.join("notes.txt"), "Not a session.").unwrap();
+
+        assert!(store.insert("../aa", unauthorized(now)).is_err());
+        assert_eq!(store.cleanup(now).unwrap(), 1);
+        assert!(store.get("aa").unwrap().is_none());
+        assert!(dir.join("notes.txt").exists());
+        assert!(store.remove("bb").unwrap().is_some());
+        assert!(store.get("bb").unwrap().is_none());
+
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+}
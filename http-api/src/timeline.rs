@@ -0,0 +1,239 @@
+//! Timelines of issues and patches.
+//!
+//! A timeline lists everything that happened to an issue or patch as one chronologically ordered
+//! list of events. Collaborative objects only record the current state and labels, not when they
+//! changed, so these are listed last, without a timestamp.
+//!
+//! Comments are numbered like in the rest of the API: the issue description, or the description
+//! of a patch revision, is comment `0`, and the comments that follow count from `1`.
+use librad::git::Storage;
+use serde::{Deserialize, Serialize};
+
+use radicle_common::cobs::issue::Issue;
+use radicle_common::cobs::patch::{self, Patch, RevisionIx};
+use radicle_common::cobs::shared::{Author, Comment, CommentId, Label, Replies, Timestamp};
+
+use crate::project::{Metadata, PeerInfo};
+
+/// Number of events per page, if not given.
+pub const DEFAULT_PER_PAGE: usize = 30;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct TimelineQueryString {
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+/// Who caused an event.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Actor {
+    /// Author of a comment, review or of the object itself.
+    Author(Author),
+    /// Peer that pushed a revision or recorded a merge.
+    Peer(PeerInfo),
+}
+
+/// An event of a timeline.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub actor: Option<Actor>,
+    /// When the event happened, if known.
+    pub timestamp: Option<Timestamp>,
+    #[serde(flatten)]
+    pub kind: Kind,
+}
+
+/// Kind of event, with its details.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Kind {
+    /// The issue or patch was opened.
+    Opened { title: String, body: String },
+    /// A comment, or a reply to one when `parent` is set. Patch comments are on a revision, and
+    /// numbered within it.
+    Comment {
+        id: CommentId,
+        parent: Option<CommentId>,
+        revision: Option<RevisionIx>,
+        body: String,
+    },
+    /// A revision of a patch was pushed.
+    Revision {
+        revision: RevisionIx,
+        oid: String,
+        base: String,
+        body: String,
+    },
+    /// A revision of a patch was reviewed.
+    Review {
+        revision: RevisionIx,
+        verdict: Option<patch::Verdict>,
+        body: String,
+    },
+    /// A revision of a patch was merged.
+    Merge {
+        revision: RevisionIx,
+        commit: String,
+    },
+    /// Current state of the issue or patch.
+    State { state: serde_json::Value },
+    /// Current labels of the issue or patch.
+    Label { labels: Vec<Label> },
+}
+
+impl Event {
+    fn new(actor: Actor, timestamp: Timestamp, kind: Kind) -> Self {
+        Self {
+            actor: Some(actor),
+            timestamp: Some(timestamp),
+            kind,
+        }
+    }
+
+    fn current(kind: Kind) -> Self {
+        Self {
+            actor: None,
+            timestamp: None,
+            kind,
+        }
+    }
+}
+
+/// Build the timeline of an issue, whose identities are resolved.
+pub fn issue(issue: &Issue) -> Vec<Event> {
+    let mut events = vec![Event::new(
+        Actor::Author(issue.author.clone()),
+        issue.timestamp,
+        Kind::Opened {
+            title: issue.title.clone(),
+            body: issue.comment.body.clone(),
+        },
+    )];
+    // The issue description is comment `0`.
+    for (ix, comment) in issue.discussion.iter().enumerate() {
+        comments(&mut events, ix + 1, comment, None);
+    }
+    events.push(Event::current(Kind::State {
+        state: serde_json::to_value(&issue.state).unwrap_or_default(),
+    }));
+    if !issue.labels.is_empty() {
+        events.push(Event::current(Kind::Label {
+            labels: issue.labels.iter().cloned().collect(),
+        }));
+    }
+    sort(events)
+}
+
+/// Build the timeline of a patch, whose identities are resolved.
+pub fn patch(patch: &Patch, meta: &Metadata, storage: &Storage) -> Vec<Event> {
+    let mut events = vec![Event::new(
+        Actor::Author(patch.author.clone()),
+        patch.timestamp,
+        Kind::Opened {
+            title: patch.title.clone(),
+            body: String::new(),
+        },
+    )];
+
+    for (ix, revision) in patch.revisions.iter().enumerate() {
+        events.push(Event::new(
+            Actor::Peer(PeerInfo::get(&revision.peer, meta, storage)),
+            revision.timestamp,
+            Kind::Revision {
+                revision: ix,
+                oid: revision.oid.to_string(),
+                base: revision.base.to_string(),
+                body: revision.comment.body.clone(),
+            },
+        ));
+        // The revision description is comment `0`.
+        for (id, comment) in revision.discussion.iter().enumerate() {
+            comments(&mut events, id + 1, comment, Some(ix));
+        }
+        for review in revision.reviews.values() {
+            events.push(Event::new(
+                Actor::Author(review.author.clone()),
+                review.timestamp,
+                Kind::Review {
+                    revision: ix,
+                    verdict: review.verdict.clone(),
+                    body: review.comment.body.clone(),
+                },
+            ));
+        }
+        for merge in revision.merges.iter() {
+            events.push(Event::new(
+                Actor::Peer(PeerInfo::get(&merge.peer, meta, storage)),
+                merge.timestamp,
+                Kind::Merge {
+                    revision: ix,
+                    commit: merge.commit.to_string(),
+                },
+            ));
+        }
+    }
+    events.push(Event::current(Kind::State {
+        state: serde_json::to_value(&patch.state).unwrap_or_default(),
+    }));
+    if !patch.labels.is_empty() {
+        events.push(Event::current(Kind::Label {
+            labels: patch.labels.iter().cloned().collect(),
+        }));
+    }
+    sort(events)
+}
+
+/// Add a comment and its replies to a timeline.
+fn comments(
+    events: &mut Vec<Event>,
+    id: CommentId,
+    comment: &Comment<Replies>,
+    revision: Option<RevisionIx>,
+) {
+    events.push(Event::new(
+        Actor::Author(comment.author.clone()),
+        comment.timestamp,
+        Kind::Comment {
+            id,
+            parent: None,
+            revision,
+            body: comment.body.clone(),
+        },
+    ));
+    for (ix, reply) in comment.replies.iter().enumerate() {
+        events.push(Event::new(
+            Actor::Author(reply.author.clone()),
+            reply.timestamp,
+            Kind::Comment {
+                id: ix,
+                parent: Some(id),
+                revision,
+                body: reply.body.clone(),
+            },
+        ));
+    }
+}
+
+/// Order events chronologically, keeping events without a timestamp last.
+fn sort(mut events: Vec<Event>) -> Vec<Event> {
+    // The sort is stable, so that events with the same timestamp keep their order.
+    events.sort_by_key(|e| (e.timestamp.is_none(), e.timestamp));
+    events
+}
+
+/// Get a page of a timeline, along with the total number of events.
+pub fn paginate(events: Vec<Event>, qs: TimelineQueryString) -> (usize, Vec<Event>) {
+    let page = qs.page.unwrap_or(0);
+    let per_page = qs.per_page.unwrap_or(DEFAULT_PER_PAGE);
+    let total = events.len();
+    let events = events
+        .into_iter()
+        .skip(page * per_page)
+        .take(per_page)
+        .collect();
+
+    (total, events)
+}
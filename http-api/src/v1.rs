@@ -1,6 +1,8 @@
 mod delegates;
+mod events;
 mod peer;
 mod projects;
+mod search;
 mod sessions;
 mod stats;
 
@@ -13,7 +15,9 @@ pub fn router(ctx: Context) -> Router {
         .merge(peer::router(ctx.clone()))
         .merge(stats::router(ctx.clone()))
         .merge(projects::router(ctx.clone()))
+        .merge(search::router(ctx.clone()))
         .merge(sessions::router(ctx.clone()))
+        .merge(events::router(ctx.clone()))
         .merge(delegates::router(ctx));
 
     Router::new().nest("/v1", routes)
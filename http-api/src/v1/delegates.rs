@@ -22,7 +22,7 @@ pub fn router(ctx: Context) -> Router {
         .layer(Extension(ctx))
 }
 
-/// List all projects that delegate is a part of.
+/// List all public projects that delegate is a part of.
 /// `GET /delegates/:delegate/projects`
 async fn delegates_projects_handler(
     Extension(ctx): Extension<Context>,
@@ -33,6 +33,7 @@ async fn delegates_projects_handler(
     let page = page.unwrap_or(0);
     let per_page = per_page.unwrap_or(10);
 
+    let private = ctx.private_projects().await?;
     let storage = ctx.storage().await?;
     let repo = git2::Repository::open_bare(&ctx.paths.git_dir()).map_err(Error::from)?;
     let whoami = person::local(&*storage).map_err(Error::LocalIdentity)?;
@@ -52,6 +53,9 @@ async fn delegates_projects_handler(
                     }) {
                         return None;
                     }
+                    if private.contains(&project.urn().encode_id()) {
+                        return None;
+                    }
 
                     let meta: project::Metadata = project.try_into().ok()?;
                     let head =
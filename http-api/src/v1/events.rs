@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+
+use axum::http::HeaderMap;
+use axum::response::sse::{self, KeepAlive, Sse};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{middleware, Extension, Router};
+use futures::future;
+use futures::stream::{self, Stream, StreamExt as _};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use librad::git::Urn;
+use librad::PeerId;
+
+use crate::axum_extra::{Path, Query};
+use crate::events::Event;
+use crate::{authorize_project, Context, Error};
+
+pub fn router(ctx: Context) -> Router {
+    Router::new()
+        .route("/events", get(events_handler))
+        .route("/projects/:project/events", get(project_events_handler))
+        .route_layer(middleware::from_fn(authorize_project))
+        .layer(Extension(ctx))
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventsQuery {
+    /// Comma-separated event types to include, eg. `refUpdated,patchCreated`.
+    pub types: Option<String>,
+    /// Only include events caused by this peer.
+    pub peer: Option<PeerId>,
+}
+
+/// Event filter of a stream.
+#[derive(Debug, Clone)]
+struct Filter {
+    project: Option<String>,
+    types: Option<Vec<String>>,
+    peer: Option<PeerId>,
+    /// Private projects, as of when the stream started, whose events are never streamed.
+    private: HashSet<String>,
+}
+
+impl Filter {
+    fn new(project: Option<&Urn>, query: EventsQuery, private: HashSet<String>) -> Self {
+        Self {
+            project: project.map(|urn| urn.to_string()),
+            types: query
+                .types
+                .map(|types| types.split(',').map(|t| t.trim().to_owned()).collect()),
+            peer: query.peer,
+            private: private
+                .iter()
+                .filter_map(|namespace| Urn::try_from_id(namespace).ok())
+                .map(|urn| urn.to_string())
+                .collect(),
+        }
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        if self.private.contains(&event.project) {
+            return false;
+        }
+        if matches!(&self.project, Some(project) if *project != event.project) {
+            return false;
+        }
+        if matches!(&self.types, Some(types) if !types.iter().any(|t| t == event.kind.name())) {
+            return false;
+        }
+        if self.peer.is_some() && self.peer != event.peer {
+            return false;
+        }
+        true
+    }
+}
+
+/// Stream seed activity events. Events of private projects are left out.
+/// `GET /events`
+async fn events_handler(
+    Extension(ctx): Extension<Context>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let filter = Filter::new(None, query, ctx.private_projects().await?);
+    let stream = stream(&ctx, last_event_id(&headers), filter);
+
+    Ok::<_, Error>(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Stream the activity events of a project.
+/// `GET /projects/:project/events`
+async fn project_events_handler(
+    Extension(ctx): Extension<Context>,
+    Path(project): Path<Urn>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let filter = Filter::new(Some(&project), query, ctx.private_projects().await?);
+    let stream = stream(&ctx, last_event_id(&headers), filter);
+
+    Ok::<_, Error>(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Get the id of the last event received by a reconnecting client.
+fn last_event_id(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get("last-event-id")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|id| id.trim().parse().ok())
+}
+
+/// Stream the logged events after the given id, followed by new events, that match the filter.
+fn stream(
+    ctx: &Context,
+    after: Option<u64>,
+    filter: Filter,
+) -> impl Stream<Item = Result<sse::Event, Infallible>> {
+    let (backlog, receiver) = ctx.events.subscribe(after);
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(event) => Some((event, receiver)),
+            // End lagging streams instead of skipping events: clients then reconnect with the id
+            // of the last event they received, and get the events they missed from the log.
+            Err(RecvError::Lagged(n)) => {
+                tracing::warn!("Event stream lagged by {} event(s), ending it", n);
+                None
+            }
+            Err(RecvError::Closed) => None,
+        }
+    });
+
+    stream::iter(backlog)
+        .chain(live)
+        .filter(move |event| future::ready(filter.matches(event)))
+        .map(|event| {
+            Ok(sse::Event::default()
+                .id(event.id.to_string())
+                .event(event.kind.name())
+                .data(serde_json::to_string(&event).unwrap_or_default()))
+        })
+}
+
+#[cfg(test)]
+mod routes {
+    use axum::body::{Body, HttpBody as _};
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::events::Kind;
+    use crate::test_extra::setup;
+
+    const THEME: &str = "base16-ocean.dark";
+
+    #[tokio::test]
+    async fn test_events_resume_route() {
+        let (profile, signer, project, _) = setup::env();
+        let ctx = Context::new(profile.paths().to_owned(), signer, THEME.to_string());
+        let first = ctx
+            .events
+            .publish(&project.urn(), None, Kind::ProjectCreated);
+        ctx.events.publish(
+            &project.urn(),
+            None,
+            Kind::PatchCreated {
+                id: String::from("patch"),
+            },
+        );
+        let app = router(ctx);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!(
+                        "/projects/{}/events?types=patchCreated",
+                        project.urn()
+                    ))
+                    .header("Last-Event-ID", first.id - 1)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut body = response.into_body();
+        let chunk = body.data().await.unwrap().unwrap();
+        let chunk = std::str::from_utf8(&chunk).unwrap();
+
+        assert!(chunk.contains("event: patchCreated"));
+        assert!(chunk.contains(&format!("id: {}", first.id + 1)));
+    }
+}
@@ -5,11 +5,11 @@ use std::str::FromStr;
 use axum::handler::Handler;
 use axum::http::{header, HeaderValue};
 use axum::response::IntoResponse;
-use axum::routing::get;
-use axum::{Extension, Json, Router};
+use axum::routing::{get, post, put};
+use axum::{middleware, Extension, Json, Router};
 use hyper::StatusCode;
 use librad::identities::Project;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tower_http::set_header::SetResponseHeaderLayer;
 
@@ -21,6 +21,7 @@ use librad::git::Urn;
 use librad::paths::Paths;
 use librad::PeerId;
 
+use radicle_common::cobs::shared::{CommentId, Label, Reaction};
 use radicle_common::cobs::{self, issue, patch, Store};
 use radicle_common::person;
 use radicle_source as source;
@@ -28,10 +29,17 @@ use radicle_source::commit::Stats;
 use radicle_source::surf::vcs::git;
 use radicle_surf::diff;
 
+use crate::auth::{Attribution, AuthSession};
 use crate::axum_extra::{Path, Query};
+use crate::code_search;
 use crate::commit::{Commit, CommitContext, CommitTeaser, CommitsQueryString, Committer};
+use crate::events::Kind;
+use crate::listing::{self, ListingQueryString};
+use crate::mergeability::Mergeability;
 use crate::project::{self, Info};
-use crate::{get_head_commit, Context, Error};
+use crate::search;
+use crate::timeline::{self, TimelineQueryString};
+use crate::{authorize_project, get_head_commit, Context, Error};
 
 const CACHE_1_HOUR: &str = "public, max-age=3600, must-revalidate";
 
@@ -55,10 +63,59 @@ pub fn router(ctx: Context) -> Router {
         .route("/projects/:project/remotes/:peer", get(remote_handler))
         .route("/projects/:project/blob/:sha/*path", get(blob_handler))
         .route("/projects/:project/readme/:sha", get(readme_handler))
+        .route("/projects/:project/search/:sha", get(code_search_handler))
         .route("/projects/:project/patches", get(patches_handler))
         .route("/projects/:project/patches/:id", get(patch_handler))
-        .route("/projects/:project/issues", get(issues_handler))
+        .route(
+            "/projects/:project/patches/:id/mergeability",
+            get(patch_mergeability_handler),
+        )
+        .route(
+            "/projects/:project/patches/:id/interdiff",
+            get(patch_interdiff_handler),
+        )
+        .route(
+            "/projects/:project/patches/:id/timeline",
+            get(patch_timeline_handler),
+        )
+        .route(
+            "/projects/:project/patches/:id/comments",
+            post(patch_comment_handler),
+        )
+        .route(
+            "/projects/:project/patches/:id/reviews",
+            post(patch_review_handler),
+        )
+        .route(
+            "/projects/:project/patches/:id/merges",
+            post(patch_merge_handler),
+        )
+        .route(
+            "/projects/:project/issues",
+            get(issues_handler).post(issue_create_handler),
+        )
         .route("/projects/:project/issues/:id", get(issue_handler))
+        .route(
+            "/projects/:project/issues/:id/timeline",
+            get(issue_timeline_handler),
+        )
+        .route(
+            "/projects/:project/issues/:id/comments",
+            post(issue_comment_handler),
+        )
+        .route(
+            "/projects/:project/issues/:id/state",
+            put(issue_state_handler),
+        )
+        .route(
+            "/projects/:project/issues/:id/labels",
+            post(issue_labels_handler),
+        )
+        .route(
+            "/projects/:project/issues/:id/reactions",
+            post(issue_react_handler),
+        )
+        .route_layer(middleware::from_fn(authorize_project))
         .layer(Extension(ctx))
 }
 
@@ -105,6 +162,7 @@ async fn get_projects_info(
     let page = page.unwrap_or(0);
     let per_page = per_page.unwrap_or(10);
 
+    let private = ctx.private_projects().await?;
     let storage = ctx.storage().await?;
     let projects: Vec<Project> = identities::any::list(storage.read_only())?
         .map(|res| match res {
@@ -112,7 +170,9 @@ async fn get_projects_info(
             Err(err) => Err(Error::from(err)),
         })
         .filter_map(|id_result| match id_result {
-            Ok(SomeIdentity::Project(project)) => Some(Ok(project)),
+            Ok(SomeIdentity::Project(project)) if !private.contains(&project.urn().encode_id()) => {
+                Some(Ok(project))
+            }
             Err(err) => Some(Err(err)),
             _ => None,
         })
@@ -146,7 +206,7 @@ async fn get_projects_info(
     Ok(infos)
 }
 
-/// List all projects.
+/// List all public projects.
 /// `GET /projects`
 async fn project_root_handler(
     Extension(ctx): Extension<Context>,
@@ -341,14 +401,7 @@ async fn project_alias_or_urn_handler(
     let urn = if let Ok(urn) = urn {
         urn
     } else {
-        let alias = urn_or_alias;
-        let mut aliases = ctx.aliases.write().await;
-        if !aliases.contains_key(&alias) {
-            // If the alias does not exist, rebuild the cache.
-            ctx.populate_aliases(&mut aliases).await?;
-        }
-
-        aliases.get(&alias).cloned().ok_or(Error::NotFound)?
+        ctx.resolve_alias(&urn_or_alias).await?
     };
 
     let info = ctx.project_info(urn).await?;
@@ -492,11 +545,147 @@ async fn readme_handler(
     Ok::<_, Error>(Json(blob))
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CodeSearchQuery {
+    q: String,
+    /// Only search files under this path.
+    path: Option<String>,
+    /// Whether `q` is a regular expression.
+    #[serde(default)]
+    regex: bool,
+    /// Number of lines shown around matches.
+    context: Option<usize>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+/// Search the files of a project revision.
+/// `GET /projects/:project/search/:sha?q=<query>&path=<path>&regex=<bool>&page=<page>`
+async fn code_search_handler(
+    Extension(ctx): Extension<Context>,
+    Path((urn, sha)): Path<(Urn, One)>,
+    Query(qs): Query<CodeSearchQuery>,
+) -> impl IntoResponse {
+    let query = code_search::Query::new(&qs.q, qs.regex, qs.path.as_deref(), qs.context)?;
+    let page = qs.page.unwrap_or(0);
+    let per_page = qs.per_page.unwrap_or(listing::DEFAULT_PER_PAGE);
+    let meta: project::Metadata = {
+        let storage = ctx.storage().await?;
+        let project = identities::project::get(storage.as_ref(), &urn)
+            .map_err(Error::Identities)?
+            .ok_or(Error::NotFound)?;
+        project.try_into().map_err(Error::Project)?
+    };
+    // Only the head of the default branch is indexed.
+    let indexed = {
+        let repo = git2::Repository::open_bare(ctx.paths.git_dir())?;
+        matches!(
+            get_head_commit(&repo, &urn, &meta.default_branch, &meta.delegates),
+            Ok(head) if head.id.to_string() == sha.as_str()
+        )
+    };
+    let reference = Reference::head(Namespace::from(urn.clone()), None, sha.clone());
+
+    let files = tokio::task::spawn_blocking(move || {
+        browse_sync(reference, ctx.paths.clone(), |browser| {
+            let root = browser.get_directory()?;
+            let index = indexed.then(|| ctx.code_search.get(&root, &urn, sha.as_str()));
+
+            Ok(code_search::search(
+                &root,
+                &query,
+                index.as_deref(),
+                page * per_page,
+                per_page,
+            ))
+        })
+    })
+    .await??;
+
+    Ok::<_, Error>(Json(files))
+}
+
 async fn patch_handler(
     Extension(ctx): Extension<Context>,
     Path((urn, patch_id)): Path<(Urn, ObjectId)>,
 ) -> impl IntoResponse {
-    let repo = git::Repository::new(ctx.paths.git_dir()).map_err(Error::from)?;
+    let storage = ctx.storage().await?;
+    let project = identities::project::get(storage.as_ref(), &urn)
+        .map_err(Error::Identities)?
+        .ok_or(Error::NotFound)?;
+    let meta: project::Metadata = project.try_into().map_err(Error::Project)?;
+
+    // Scoped, so that the patch store and browser aren't held across the `await` below.
+    let mut patch = {
+        let repo = git::Repository::new(ctx.paths.git_dir()).map_err(Error::from)?;
+        let whoami = person::local(&*storage).map_err(Error::LocalIdentity)?;
+        let store = Store::new(whoami, &ctx.paths, &storage);
+        let patches = patch::PatchStore::new(&store);
+        let mut patch = patches
+            .get(&urn, &patch_id)
+            .map_err(Error::from)?
+            .ok_or(Error::NotFound)?;
+        if let Err(e) = patch
+            .resolve(storage.as_ref())
+            .map_err(Error::IdentityResolve)
+        {
+            tracing::warn!("Failed to resolve identities in patch {}: {}", patch_id, e);
+        }
+
+        let mut browser = git::Browser::new_with_namespace(
+            &repo,
+            &git::Namespace::try_from(urn.encode_id().as_str())
+                .map_err(|_| Error::MissingNamespace)?,
+            remote_branch(meta.default_branch.as_str(), &patch.author.peer),
+        )
+        .map_err(Error::from)?;
+
+        resolve_revisions(patch, &mut browser, &meta, storage.as_ref(), true)
+    };
+
+    let oids = patch
+        .revisions
+        .iter()
+        .filter(|revision| revision.changeset.is_some())
+        .map(|revision| *revision.oid)
+        .collect::<Vec<_>>();
+    let mergeability = tokio::task::spawn_blocking(move || {
+        let git = git2::Repository::open_bare(ctx.paths.git_dir())?;
+        let mut results = HashMap::new();
+
+        // Test merges are only possible if the default branch head is known.
+        if let Ok(head) = get_head_commit(&git, &urn, &meta.default_branch, &meta.delegates) {
+            for oid in oids {
+                match ctx.mergeability.get(&git, head.id, oid) {
+                    Ok(mergeability) => {
+                        results.insert(oid, mergeability);
+                    }
+                    Err(e) => tracing::warn!("Failed to test merge of revision {}: {}", oid, e),
+                }
+            }
+        }
+        Ok::<_, Error>(results)
+    })
+    .await??;
+
+    for revision in patch.revisions.iter_mut() {
+        if let Some(changeset) = &mut revision.changeset {
+            changeset.mergeability = mergeability.get(&*revision.oid).cloned();
+        }
+    }
+
+    Ok::<_, Error>(Json(Cob::new(patch_id, patch)))
+}
+
+/// Get the timeline of a patch: its revisions, comments, reviews and merges, and its current
+/// state and labels.
+/// `GET /projects/:project/patches/:id/timeline`
+async fn patch_timeline_handler(
+    Extension(ctx): Extension<Context>,
+    Path((urn, patch_id)): Path<(Urn, ObjectId)>,
+    Query(qs): Query<TimelineQueryString>,
+) -> impl IntoResponse {
     let storage = ctx.storage().await?;
     let project = identities::project::get(storage.as_ref(), &urn)
         .map_err(Error::Identities)?
@@ -516,77 +705,544 @@ async fn patch_handler(
     {
         tracing::warn!("Failed to resolve identities in patch {}: {}", patch_id, e);
     }
+    let events = timeline::patch(&patch, &meta, storage.as_ref());
+    let (total, page) = timeline::paginate(events, qs);
+
+    Ok::<_, Error>((listing::headers(total), Json(page)))
+}
+
+/// Get whether the revisions of a patch can be merged into the default branch.
+/// `GET /projects/:project/patches/:id/mergeability`
+async fn patch_mergeability_handler(
+    Extension(ctx): Extension<Context>,
+    Path((urn, patch_id)): Path<(Urn, ObjectId)>,
+) -> impl IntoResponse {
+    let storage = ctx.storage().await?;
+    let project = identities::project::get(storage.as_ref(), &urn)
+        .map_err(Error::Identities)?
+        .ok_or(Error::NotFound)?;
+    let meta: project::Metadata = project.try_into().map_err(Error::Project)?;
+
+    // Scoped, so that the patch store isn't held across the `await` below.
+    let oids = {
+        let whoami = person::local(&*storage).map_err(Error::LocalIdentity)?;
+        let store = Store::new(whoami, &ctx.paths, &storage);
+        let patches = patch::PatchStore::new(&store);
+        let patch = patches
+            .get(&urn, &patch_id)
+            .map_err(Error::from)?
+            .ok_or(Error::NotFound)?;
+
+        patch
+            .revisions
+            .iter()
+            .map(|revision| *revision.oid)
+            .collect::<Vec<_>>()
+    };
+    let revisions = tokio::task::spawn_blocking(move || {
+        let repo = git2::Repository::open_bare(ctx.paths.git_dir())?;
+        let head = get_head_commit(&repo, &urn, &meta.default_branch, &meta.delegates)?;
+
+        // A revision that fails to merge, eg. because its commits are missing, is reported
+        // with an error, without failing the other revisions.
+        let revisions = oids
+            .into_iter()
+            .enumerate()
+            .map(
+                |(ix, oid)| match ctx.mergeability.get(&repo, head.id, oid) {
+                    Ok(mergeability) => json!({
+                        "revision": ix,
+                        "oid": oid.to_string(),
+                        "mergeability": mergeability,
+                    }),
+                    Err(e) => {
+                        tracing::warn!("Failed to test merge of revision {}: {}", oid, e);
+
+                        json!({
+                            "revision": ix,
+                            "oid": oid.to_string(),
+                            "mergeability": null,
+                            "error": e.message(),
+                        })
+                    }
+                },
+            )
+            .collect::<Vec<_>>();
+
+        Ok::<_, Error>(revisions)
+    })
+    .await??;
+
+    Ok::<_, Error>(Json(revisions))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchComment {
+    pub body: String,
+    /// Revision to comment on; defaults to the latest.
+    pub revision: Option<patch::RevisionIx>,
+    /// Line of the revision's diff the comment refers to, if any.
+    pub anchor: Option<Anchor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchReview {
+    /// Either `accept` or `reject`; reviews without a verdict are comments.
+    pub verdict: Option<String>,
+    #[serde(default)]
+    pub comment: String,
+    /// Revision to review; defaults to the latest.
+    pub revision: Option<patch::RevisionIx>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchMerge {
+    /// Commit the revision was merged in.
+    pub commit: String,
+    /// Revision that was merged; defaults to the latest.
+    pub revision: Option<patch::RevisionIx>,
+}
+
+/// A line of a revision's diff, that a comment refers to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Anchor {
+    /// Path of the file.
+    pub path: String,
+    /// Line number in the file.
+    pub line: u32,
+    /// Side of the diff the line number refers to.
+    #[serde(default)]
+    pub side: Side,
+}
+
+/// Side of a diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Side {
+    /// The revision, ie. added and context lines.
+    New,
+    /// The base, ie. deleted and context lines.
+    Old,
+}
+
+impl Default for Side {
+    fn default() -> Self {
+        Self::New
+    }
+}
+
+impl Anchor {
+    /// Anchors are stored in comments as a trailer, eg. `Anchor: src/lib.rs:+42`.
+    const TRAILER: &'static str = "Anchor: ";
+
+    /// Append the anchor to a comment, as a trailer.
+    fn sign(&self, body: &str) -> String {
+        let side = match self.side {
+            Side::New => '+',
+            Side::Old => '-',
+        };
+        format!(
+            "{}\n\n{}{}:{}{}",
+            body.trim_end(),
+            Self::TRAILER,
+            self.path,
+            side,
+            self.line
+        )
+    }
+
+    /// Get the anchor of a comment, if any.
+    fn from_comment(body: &str) -> Option<Self> {
+        let (path, line) = body
+            .lines()
+            .rev()
+            .find_map(|l| l.strip_prefix(Self::TRAILER))?
+            .rsplit_once(':')?;
+        let (side, line) = if let Some(line) = line.strip_prefix('+') {
+            (Side::New, line)
+        } else if let Some(line) = line.strip_prefix('-') {
+            (Side::Old, line)
+        } else {
+            return None;
+        };
+
+        Some(Self {
+            path: path.to_owned(),
+            line: line.parse().ok()?,
+            side,
+        })
+    }
+
+    /// Whether a diff line is the anchored line.
+    fn matches(&self, line: &diff::LineDiff) -> bool {
+        match (self.side, line) {
+            (Side::New, diff::LineDiff::Addition { line_num, .. }) => *line_num == self.line,
+            (Side::New, diff::LineDiff::Context { line_num_new, .. }) => *line_num_new == self.line,
+            (Side::Old, diff::LineDiff::Deletion { line_num, .. }) => *line_num == self.line,
+            (Side::Old, diff::LineDiff::Context { line_num_old, .. }) => *line_num_old == self.line,
+            _ => false,
+        }
+    }
+}
 
-    let mut browser = git::Browser::new_with_namespace(
-        &repo,
-        &git::Namespace::try_from(urn.encode_id().as_str()).map_err(|_| Error::MissingNamespace)?,
-        remote_branch(meta.default_branch.as_str(), &patch.author.peer),
+/// Comment on a patch revision on behalf of the signed-in user, optionally anchored to a line of
+/// the revision's diff.
+/// `POST /projects/:project/patches/:id/comments`
+async fn patch_comment_handler(
+    Extension(ctx): Extension<Context>,
+    Path((project, patch_id)): Path<(Urn, ObjectId)>,
+    auth: AuthSession,
+    Json(request): Json<PatchComment>,
+) -> impl IntoResponse {
+    let attribution = ctx.attribution(&auth)?;
+    if request.body.trim().is_empty() {
+        return Err(Error::BadRequest(String::from("comment is empty")));
+    }
+    let body = match &request.anchor {
+        // A line break in the path would let it add trailers of its own.
+        Some(anchor)
+            if anchor.path.is_empty() || anchor.path.contains(['\n', '\r']) || anchor.line == 0 =>
+        {
+            return Err(Error::BadRequest(String::from("invalid comment anchor")));
+        }
+        Some(anchor) => anchor.sign(&request.body),
+        None => request.body.clone(),
+    };
+    let body = attribution.sign(&body)?;
+    update_patch(
+        &ctx,
+        &project,
+        &patch_id,
+        request.revision,
+        |patches, revision| {
+            patches
+                .comment(&project, &patch_id, revision, &body)
+                .map(|_| ())
+        },
     )
-    .map_err(Error::from)?;
+    .await?;
+
+    Ok::<_, Error>(StatusCode::CREATED)
+}
+
+/// Review a patch revision on behalf of the signed-in user.
+/// `POST /projects/:project/patches/:id/reviews`
+async fn patch_review_handler(
+    Extension(ctx): Extension<Context>,
+    Path((project, patch_id)): Path<(Urn, ObjectId)>,
+    auth: AuthSession,
+    Json(request): Json<PatchReview>,
+) -> impl IntoResponse {
+    let attribution = ctx.attribution(&auth)?;
+    let verdict = match request.verdict.as_deref() {
+        Some("accept") => Some(patch::Verdict::Accept),
+        Some("reject") => Some(patch::Verdict::Reject),
+        None => None,
+        Some(other) => return Err(Error::BadRequest(format!("invalid verdict {:?}", other))),
+    };
+    let comment = attribution.sign(&request.comment)?;
+    update_patch(
+        &ctx,
+        &project,
+        &patch_id,
+        request.revision,
+        |patches, revision| patches.review(&project, &patch_id, revision, verdict, comment, vec![]),
+    )
+    .await?;
+
+    Ok::<_, Error>(StatusCode::CREATED)
+}
+
+/// Record that a patch revision was merged. Only delegates of the project may record merges.
+/// `POST /projects/:project/patches/:id/merges`
+async fn patch_merge_handler(
+    Extension(ctx): Extension<Context>,
+    Path((project, patch_id)): Path<(Urn, ObjectId)>,
+    auth: AuthSession,
+    Json(request): Json<PatchMerge>,
+) -> impl IntoResponse {
+    let attribution = ctx.attribution(&auth)?;
+    if attribution.person.is_none() {
+        return Err(Error::Forbidden("Account is not linked to a person"));
+    }
+    let commit = git2::Oid::from_str(&request.commit)
+        .map_err(|_| Error::BadRequest(format!("invalid commit {:?}", request.commit)))?;
+    {
+        let storage = ctx.storage().await?;
+        if !is_delegate(storage.as_ref(), &project, &attribution)? {
+            return Err(Error::Forbidden("Only delegates can record merges"));
+        }
+    }
+    let repo = git2::Repository::open_bare(ctx.paths.git_dir())?;
+    if repo.find_commit(commit).is_err() {
+        return Err(Error::BadRequest(format!("unknown commit {}", commit)));
+    }
+
+    update_patch(
+        &ctx,
+        &project,
+        &patch_id,
+        request.revision,
+        |patches, revision| {
+            patches
+                .merge(&project, &patch_id, revision, commit.into())
+                .map(|_| ())
+        },
+    )
+    .await?;
+    record_attribution(&ctx, &attribution, &project, &patch_id, "merge").await?;
+
+    Ok::<_, Error>(StatusCode::CREATED)
+}
+
+/// Apply a change to a revision of an existing patch, through the seed's identity. The latest
+/// revision is changed if none is given.
+async fn update_patch<F>(
+    ctx: &Context,
+    project: &Urn,
+    patch_id: &ObjectId,
+    revision: Option<patch::RevisionIx>,
+    change: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(&patch::PatchStore, patch::RevisionIx) -> Result<(), cobs::Error>,
+{
+    // Scoped, so that the patch store isn't held across the `await` below.
+    {
+        let storage = ctx.storage().await?;
+        let whoami = person::local(&*storage).map_err(Error::LocalIdentity)?;
+        let store = Store::new(whoami, &ctx.paths, &storage);
+        let patches = patch::PatchStore::new(&store);
+        let patch = patches
+            .get(project, patch_id)
+            .map_err(Error::from)?
+            .ok_or(Error::NotFound)?;
+        let latest = patch.revisions.len() - 1;
+        let revision = match revision {
+            Some(revision) if revision > latest => return Err(Error::NotFound),
+            Some(revision) => revision,
+            None => latest,
+        };
+        change(&patches, revision).map_err(Error::Cobs)?;
+    }
+
+    if let Err(e) = ctx.index_project(project).await {
+        tracing::warn!("Failed to index project {}: {}", project, e);
+    }
+    ctx.events.publish(
+        project,
+        Some(ctx.peer_id),
+        Kind::PatchUpdated {
+            id: patch_id.to_string(),
+        },
+    );
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InterdiffQuery {
+    /// Revision to diff from; defaults to the one before `to`.
+    pub from: Option<patch::RevisionIx>,
+    /// Revision to diff to; defaults to the latest.
+    pub to: Option<patch::RevisionIx>,
+}
+
+/// The changes between two revisions of a patch.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Interdiff {
+    from: patch::RevisionIx,
+    to: patch::RevisionIx,
+    /// Base both revisions were compared on, if the older revision could be rebased onto the
+    /// base of the newer one.
+    #[serde(with = "crate::project::option")]
+    base: Option<git2::Oid>,
+    diff: git::Diff,
+    stats: Stats,
+    /// Commits of the newer revision that aren't part of the older one.
+    commits: Vec<RangeCommit>,
+}
 
-    Ok::<_, Error>(Json(Cob::new(
-        patch_id,
-        resolve_revisions(patch, &mut browser, &meta, storage.as_ref(), true),
-    )))
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RangeCommit {
+    id: String,
+    summary: Option<String>,
 }
 
-/// Get project patches list.
+/// Get the changes between two revisions of a patch.
+/// `GET /projects/:project/patches/:id/interdiff?from=<rev>&to=<rev>`
+async fn patch_interdiff_handler(
+    Extension(ctx): Extension<Context>,
+    Path((project, patch_id)): Path<(Urn, ObjectId)>,
+    Query(query): Query<InterdiffQuery>,
+) -> impl IntoResponse {
+    let storage = ctx.storage().await?;
+    // Scoped, so that the patch store isn't held across the `await` below.
+    let (from, old, to, new) = {
+        let whoami = person::local(&*storage).map_err(Error::LocalIdentity)?;
+        let store = Store::new(whoami, &ctx.paths, &storage);
+        let patches = patch::PatchStore::new(&store);
+        let patch = patches
+            .get(&project, &patch_id)
+            .map_err(Error::from)?
+            .ok_or(Error::NotFound)?;
+
+        let to = query.to.unwrap_or(patch.revisions.len() - 1);
+        let from = query.from.unwrap_or_else(|| to.saturating_sub(1));
+        match (patch.revisions.get(from), patch.revisions.get(to)) {
+            (Some(old), Some(new)) => (from, (*old.base, *old.oid), to, (*new.base, *new.oid)),
+            _ => return Err(Error::NotFound),
+        }
+    };
+    let interdiff = tokio::task::spawn_blocking(move || {
+        let repo = git2::Repository::open_bare(ctx.paths.git_dir())?;
+
+        Interdiff::compute(&repo, (from, old), (to, new))
+    })
+    .await??;
+
+    Ok::<_, Error>(Json(interdiff))
+}
+
+impl Interdiff {
+    /// Compute the changes between two revisions, given by index along with their
+    /// `(base, head)` pairs.
+    fn compute(
+        repo: &git2::Repository,
+        (from, old): (patch::RevisionIx, (git2::Oid, git2::Oid)),
+        (to, new): (patch::RevisionIx, (git2::Oid, git2::Oid)),
+    ) -> Result<Self, Error> {
+        let (base, diff) = interdiff(repo, old, new)?;
+
+        let mut walk = repo.revwalk()?;
+        walk.push(new.1)?;
+        walk.hide(old.1)?;
+        walk.hide(new.0)?;
+        let commits = walk
+            .map(|oid| -> Result<RangeCommit, git2::Error> {
+                let commit = repo.find_commit(oid?)?;
+
+                Ok(RangeCommit {
+                    id: commit.id().to_string(),
+                    summary: commit.summary().map(ToOwned::to_owned),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            from,
+            to,
+            base,
+            stats: Changeset::stats(&diff),
+            diff,
+            commits,
+        })
+    }
+}
+
+/// Diff two revisions, given as `(base, head)` pairs. When the bases differ, the old revision is
+/// first rebased onto the new base, so that upstream changes aren't part of the diff. If that
+/// isn't possible because of conflicts, the revision heads are diffed as they are.
+///
+/// Returns the base of the diff, if both revisions were compared on the same base.
+fn interdiff(
+    repo: &git2::Repository,
+    old: (git2::Oid, git2::Oid),
+    new: (git2::Oid, git2::Oid),
+) -> Result<(Option<git2::Oid>, git::Diff), Error> {
+    let tree = |oid: git2::Oid| repo.find_commit(oid).and_then(|c| c.tree());
+    let new_tree = tree(new.1)?;
+    let (base, old_tree) = if old.0 == new.0 {
+        (Some(new.0), tree(old.1)?)
+    } else {
+        let mut index = repo.merge_trees(&tree(old.0)?, &tree(new.0)?, &tree(old.1)?, None)?;
+
+        if index.has_conflicts() {
+            (None, tree(old.1)?)
+        } else {
+            let oid = index.write_tree_to(repo)?;
+            (Some(new.0), repo.find_tree(oid)?)
+        }
+    };
+    let diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+    let diff = git::Diff::try_from(diff).map_err(|e| Error::Diff(e.to_string()))?;
+
+    Ok((base, diff))
+}
+
+/// Get project patches list, filtered, sorted and paginated.
 /// `GET /projects/:project/patches`
 async fn patches_handler(
     Extension(ctx): Extension<Context>,
     Path(urn): Path<Urn>,
+    Query(qs): Query<ListingQueryString>,
 ) -> impl IntoResponse {
     let storage = ctx.storage().await?;
     let whoami = person::local(&*storage).map_err(Error::LocalIdentity)?;
     let store = Store::new(whoami, &ctx.paths, &storage);
     let patches = patch::PatchStore::new(&store);
-    let all: Vec<_> = patches
-        .all(&urn)
-        .map_err(Error::Cobs)?
-        .into_iter()
-        .map(|(id, mut patch)| {
-            if let Err(e) = patch
-                .resolve(storage.as_ref())
-                .map_err(Error::IdentityResolve)
-            {
-                tracing::warn!("Failed to resolve identities in patch {}: {}", id, e);
-            }
-
-            Cob::new(id, patch)
-        })
-        .collect();
+    let (ids, total) = ctx
+        .search
+        .list(&storage, &ctx.paths, &urn, search::Kind::Patch, &qs)?;
+    let mut page = Vec::with_capacity(ids.len());
+
+    for id in ids {
+        // Patches removed since they were indexed are left out.
+        let mut patch = match patches.get(&urn, &id).map_err(Error::from)? {
+            Some(patch) => patch,
+            None => continue,
+        };
+        if let Err(e) = patch
+            .resolve(storage.as_ref())
+            .map_err(Error::IdentityResolve)
+        {
+            tracing::warn!("Failed to resolve identities in patch {}: {}", id, e);
+        }
+        page.push(Cob::new(id, patch));
+    }
 
-    Ok::<_, Error>(Json(all))
+    Ok::<_, Error>((listing::headers(total), Json(page)))
 }
 
-/// Get project issues list.
+/// Get project issues list, filtered, sorted and paginated.
 /// `GET /projects/:project/issues`
 async fn issues_handler(
     Extension(ctx): Extension<Context>,
     Path(project): Path<Urn>,
+    Query(qs): Query<ListingQueryString>,
 ) -> impl IntoResponse {
     // TODO: Handle non-existing project.
     let storage = ctx.storage().await?;
     let whoami = person::local(&*storage).map_err(Error::LocalIdentity)?;
     let store = Store::new(whoami, &ctx.paths, &storage);
     let issues = issue::IssueStore::new(&store);
-    let all: Vec<_> = issues
-        .all(&project)
-        .map_err(Error::Cobs)?
-        .into_iter()
-        .map(|(id, mut issue)| {
-            if let Err(e) = issue
-                .resolve(storage.as_ref())
-                .map_err(Error::IdentityResolve)
-            {
-                tracing::warn!("Failed to resolve identities in issue {}: {}", id, e);
-            }
-
-            Cob::new(id, issue)
-        })
-        .collect();
+    let (ids, total) = ctx
+        .search
+        .list(&storage, &ctx.paths, &project, search::Kind::Issue, &qs)?;
+    let mut page = Vec::with_capacity(ids.len());
+
+    for id in ids {
+        // Issues removed since they were indexed are left out.
+        let mut issue = match issues.get(&project, &id).map_err(Error::from)? {
+            Some(issue) => issue,
+            None => continue,
+        };
+        if let Err(e) = issue
+            .resolve(storage.as_ref())
+            .map_err(Error::IdentityResolve)
+        {
+            tracing::warn!("Failed to resolve identities in issue {}: {}", id, e);
+        }
+        page.push(Cob::new(id, issue));
+    }
 
-    Ok::<_, Error>(Json(all))
+    Ok::<_, Error>((listing::headers(total), Json(page)))
 }
 
 /// Get project issue.
@@ -614,47 +1270,414 @@ async fn issue_handler(
     Ok::<_, Error>(Json(Cob::new(issue_id, issue)))
 }
 
-async fn browse<T, F>(reference: Reference<Single>, paths: Paths, callback: F) -> Result<T, Error>
-where
-    F: FnOnce(&mut git::Browser) -> Result<T, radicle_source::Error> + Send,
-{
-    let namespace = git::namespace::Namespace::try_from(
-        reference
-            .namespace
-            .ok_or(Error::MissingNamespace)?
-            .to_string()
-            .as_str(),
-    )
-    .map_err(Error::from)?;
+/// Get the timeline of an issue: its comments, and its current state and labels.
+/// `GET /projects/:project/issues/:id/timeline`
+async fn issue_timeline_handler(
+    Extension(ctx): Extension<Context>,
+    Path((project, issue_id)): Path<(Urn, ObjectId)>,
+    Query(qs): Query<TimelineQueryString>,
+) -> impl IntoResponse {
+    let storage = ctx.storage().await?;
+    let whoami = person::local(&*storage).map_err(Error::LocalIdentity)?;
+    let store = Store::new(whoami, &ctx.paths, &storage);
+    let issues = issue::IssueStore::new(&store);
+    let mut issue = issues
+        .get(&project, &issue_id)
+        .map_err(Error::from)?
+        .ok_or(Error::NotFound)?;
+    if let Err(e) = issue
+        .resolve(storage.as_ref())
+        .map_err(Error::IdentityResolve)
+    {
+        tracing::warn!("Failed to resolve identities in issue {}: {}", issue_id, e);
+    }
+    let events = timeline::issue(&issue);
+    let (total, page) = timeline::paginate(events, qs);
 
-    let revision: git::Rev = match git::Oid::from_str(reference.name.as_str()) {
-        Ok(oid) => oid.try_into().map_err(|_| Error::NotFound)?,
-        Err(_) => remote_branch(&reference.name, &reference.remote.ok_or(Error::NotFound)?)
-            .try_into()
-            .map_err(|_| Error::NotFound)?,
-    };
-    let repo = git::Repository::new(paths.git_dir())?;
-    let mut browser = git::Browser::new_with_namespace(&repo, &namespace, revision)?;
+    Ok::<_, Error>((listing::headers(total), Json(page)))
+}
 
-    callback(&mut browser).map_err(|err| match &err {
-        radicle_source::error::Error::PathNotFound(_) => Error::NotFound,
-        _ => Error::from(err),
-    })
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IssueCreate {
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub labels: Vec<String>,
 }
 
-fn remote_branch(branch_name: &str, peer_id: &PeerId) -> git::Branch {
-    // NOTE<sebastinez>: We should be able to pass simply a branch name without heads/ and be able to query that later.
-    // Needs work on radicle_surf I assume.
-    git::Branch::remote(
-        &format!("heads/{}", branch_name),
-        &peer_id.default_encoding(),
-    )
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IssueComment {
+    pub body: String,
 }
 
-/// A collaborative object that includes its id.
-#[derive(serde::Serialize)]
-struct Cob<T: serde::Serialize> {
-    id: ObjectId,
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IssueStateUpdate {
+    /// Either `open` or `closed`.
+    pub state: String,
+    /// Why the issue was closed: `solved` or `other`.
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IssueLabels {
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IssueReaction {
+    /// Comment to react to; the issue description is comment `0`.
+    #[serde(default)]
+    pub comment: CommentId,
+    pub reaction: String,
+}
+
+/// Open an issue on behalf of the signed-in user.
+/// `POST /projects/:project/issues`
+async fn issue_create_handler(
+    Extension(ctx): Extension<Context>,
+    Path(project): Path<Urn>,
+    auth: AuthSession,
+    Json(request): Json<IssueCreate>,
+) -> impl IntoResponse {
+    let attribution = ctx.attribution(&auth)?;
+    let title = request.title.trim();
+    if title.is_empty() {
+        return Err(Error::BadRequest(String::from("issue title is empty")));
+    }
+    let labels = parse_labels(&request.labels)?;
+    let description = attribution.sign(&request.description)?;
+
+    // Scoped, so that the issue store isn't held across the `await` below.
+    let issue_id = {
+        let storage = ctx.storage().await?;
+        identities::project::get(storage.as_ref(), &project)?.ok_or(Error::NotFound)?;
+
+        let whoami = person::local(&*storage).map_err(Error::LocalIdentity)?;
+        let store = Store::new(whoami, &ctx.paths, &storage);
+        let issues = issue::IssueStore::new(&store);
+
+        issues
+            .create(&project, title, &description, &labels)
+            .map_err(Error::Cobs)?
+    };
+
+    // Changes made here don't go through the git-server, so there is no notification of them.
+    if let Err(e) = ctx.index_project(&project).await {
+        tracing::warn!("Failed to index project {}: {}", &project, e);
+    }
+    ctx.events.publish(
+        &project,
+        Some(ctx.peer_id),
+        Kind::IssueCreated {
+            id: issue_id.to_string(),
+        },
+    );
+
+    Ok::<_, Error>((
+        StatusCode::CREATED,
+        Json(json!({ "id": issue_id.to_string() })),
+    ))
+}
+
+/// Comment on an issue on behalf of the signed-in user.
+/// `POST /projects/:project/issues/:id/comments`
+async fn issue_comment_handler(
+    Extension(ctx): Extension<Context>,
+    Path((project, issue_id)): Path<(Urn, ObjectId)>,
+    auth: AuthSession,
+    Json(request): Json<IssueComment>,
+) -> impl IntoResponse {
+    let attribution = ctx.attribution(&auth)?;
+    if request.body.trim().is_empty() {
+        return Err(Error::BadRequest(String::from("comment is empty")));
+    }
+    let body = attribution.sign(&request.body)?;
+    update_issue(
+        &ctx,
+        &project,
+        &issue_id,
+        &attribution,
+        IssueChange::Comment,
+        |issues| issues.comment(&project, &issue_id, &body).map(|_| ()),
+    )
+    .await?;
+
+    Ok::<_, Error>(StatusCode::CREATED)
+}
+
+/// Open or close an issue on behalf of the signed-in user, who must be its author or a delegate
+/// of the project.
+/// `PUT /projects/:project/issues/:id/state`
+async fn issue_state_handler(
+    Extension(ctx): Extension<Context>,
+    Path((project, issue_id)): Path<(Urn, ObjectId)>,
+    auth: AuthSession,
+    Json(request): Json<IssueStateUpdate>,
+) -> impl IntoResponse {
+    let attribution = ctx.attribution(&auth)?;
+    let state = match (request.state.as_str(), request.reason.as_deref()) {
+        ("open", None) => issue::State::Open,
+        ("closed", None | Some("other")) => issue::State::Closed {
+            reason: issue::CloseReason::Other,
+        },
+        ("closed", Some("solved")) => issue::State::Closed {
+            reason: issue::CloseReason::Solved,
+        },
+        _ => {
+            return Err(Error::BadRequest(format!(
+                "invalid issue state {:?}",
+                request.state
+            )))
+        }
+    };
+    update_issue(
+        &ctx,
+        &project,
+        &issue_id,
+        &attribution,
+        IssueChange::State,
+        |issues| issues.lifecycle(&project, &issue_id, state),
+    )
+    .await?;
+
+    Ok::<_, Error>(StatusCode::NO_CONTENT)
+}
+
+/// Label an issue on behalf of the signed-in user, who must be its author or a delegate of the
+/// project.
+/// `POST /projects/:project/issues/:id/labels`
+async fn issue_labels_handler(
+    Extension(ctx): Extension<Context>,
+    Path((project, issue_id)): Path<(Urn, ObjectId)>,
+    auth: AuthSession,
+    Json(request): Json<IssueLabels>,
+) -> impl IntoResponse {
+    let attribution = ctx.attribution(&auth)?;
+    let labels = parse_labels(&request.labels)?;
+    update_issue(
+        &ctx,
+        &project,
+        &issue_id,
+        &attribution,
+        IssueChange::Labels,
+        |issues| issues.label(&project, &issue_id, &labels),
+    )
+    .await?;
+
+    Ok::<_, Error>(StatusCode::NO_CONTENT)
+}
+
+/// React to an issue comment on behalf of the signed-in user.
+/// `POST /projects/:project/issues/:id/reactions`
+async fn issue_react_handler(
+    Extension(ctx): Extension<Context>,
+    Path((project, issue_id)): Path<(Urn, ObjectId)>,
+    auth: AuthSession,
+    Json(request): Json<IssueReaction>,
+) -> impl IntoResponse {
+    let attribution = ctx.attribution(&auth)?;
+    let reaction = Reaction::from_str(&request.reaction)
+        .map_err(|_| Error::BadRequest(format!("invalid reaction {:?}", request.reaction)))?;
+    update_issue(
+        &ctx,
+        &project,
+        &issue_id,
+        &attribution,
+        IssueChange::Reaction,
+        |issues| issues.react(&project, &issue_id, request.comment, reaction),
+    )
+    .await?;
+
+    Ok::<_, Error>(StatusCode::NO_CONTENT)
+}
+
+/// A change to an existing issue.
+#[derive(Debug, Clone, Copy)]
+enum IssueChange {
+    Comment,
+    State,
+    Labels,
+    Reaction,
+}
+
+impl IssueChange {
+    /// Action recorded in the attribution log.
+    fn action(&self) -> &'static str {
+        match self {
+            Self::Comment => "comment",
+            Self::State => "state",
+            Self::Labels => "labels",
+            Self::Reaction => "reaction",
+        }
+    }
+
+    /// Whether only the issue author and delegates of the project may make the change.
+    fn is_restricted(&self) -> bool {
+        matches!(self, Self::State | Self::Labels)
+    }
+}
+
+/// Apply a change to an existing issue, through the seed's identity, and record it in the
+/// attribution log.
+async fn update_issue<F>(
+    ctx: &Context,
+    project: &Urn,
+    issue_id: &ObjectId,
+    attribution: &Attribution,
+    kind: IssueChange,
+    change: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(&issue::IssueStore) -> Result<(), cobs::Error>,
+{
+    // Scoped, so that the issue store isn't held across the `await` below.
+    {
+        let storage = ctx.storage().await?;
+        let whoami = person::local(&*storage).map_err(Error::LocalIdentity)?;
+        let store = Store::new(whoami, &ctx.paths, &storage);
+        let issues = issue::IssueStore::new(&store);
+
+        let issue = issues
+            .get(project, issue_id)
+            .map_err(Error::from)?
+            .ok_or(Error::NotFound)?;
+        if kind.is_restricted()
+            && !is_issue_author(ctx, &issue, attribution)
+            && !is_delegate(storage.as_ref(), project, attribution)?
+        {
+            return Err(Error::Forbidden(
+                "Only the issue author and delegates can change its state and labels",
+            ));
+        }
+        change(&issues).map_err(Error::Cobs)?;
+    }
+
+    record_attribution(ctx, attribution, project, issue_id, kind.action()).await?;
+
+    if let Err(e) = ctx.index_project(project).await {
+        tracing::warn!("Failed to index project {}: {}", project, e);
+    }
+    ctx.events.publish(
+        project,
+        Some(ctx.peer_id),
+        Kind::IssueUpdated {
+            id: issue_id.to_string(),
+        },
+    );
+    Ok(())
+}
+
+/// Record a change made on behalf of the signed-in user in the attribution log. Failing to do so
+/// doesn't fail the request, since the change was already made.
+async fn record_attribution(
+    ctx: &Context,
+    attribution: &Attribution,
+    project: &Urn,
+    object: &ObjectId,
+    action: &'static str,
+) -> Result<(), Error> {
+    let git_dir = ctx.paths.git_dir().to_owned();
+    let (urn, id, attribution) = (project.clone(), object.to_string(), attribution.clone());
+    let recorded =
+        tokio::task::spawn_blocking(move || attribution.record(&git_dir, &urn, &id, action))
+            .await?;
+
+    if let Err(e) = recorded {
+        tracing::warn!("Failed to record attribution of {}: {}", object, e);
+    }
+    Ok(())
+}
+
+/// Whether the signed-in user is the author of an issue. Issues opened through the seed are
+/// authored by the account their description is attributed to.
+fn is_issue_author(ctx: &Context, issue: &issue::Issue, attribution: &Attribution) -> bool {
+    if issue.author.peer == ctx.peer_id {
+        Attribution::parse(&issue.comment.body).map_or(false, |a| a.account == attribution.account)
+    } else {
+        attribution.person.as_ref() == Some(&issue.author.urn)
+    }
+}
+
+/// Whether the person linked to the signed-in account is a delegate of the project.
+fn is_delegate(storage: &Storage, project: &Urn, attribution: &Attribution) -> Result<bool, Error> {
+    use either::Either;
+
+    let person = match &attribution.person {
+        Some(person) => person,
+        None => return Ok(false),
+    };
+    let doc = identities::project::get(storage, project)?.ok_or(Error::NotFound)?;
+
+    Ok(doc.delegations().iter().any(|d| match d {
+        Either::Right(indirect) => &indirect.urn() == person,
+        Either::Left(_) => false,
+    }))
+}
+
+fn parse_labels(labels: &[String]) -> Result<Vec<Label>, Error> {
+    labels
+        .iter()
+        .map(|l| {
+            Label::from_str(l).map_err(|_| Error::BadRequest(format!("invalid label {:?}", l)))
+        })
+        .collect()
+}
+
+async fn browse<T, F>(reference: Reference<Single>, paths: Paths, callback: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut git::Browser) -> Result<T, radicle_source::Error> + Send,
+{
+    browse_sync(reference, paths, callback)
+}
+
+/// Like [`browse`], for use within blocking tasks.
+fn browse_sync<T, F>(reference: Reference<Single>, paths: Paths, callback: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut git::Browser) -> Result<T, radicle_source::Error>,
+{
+    let namespace = git::namespace::Namespace::try_from(
+        reference
+            .namespace
+            .ok_or(Error::MissingNamespace)?
+            .to_string()
+            .as_str(),
+    )
+    .map_err(Error::from)?;
+
+    let revision: git::Rev = match git::Oid::from_str(reference.name.as_str()) {
+        Ok(oid) => oid.try_into().map_err(|_| Error::NotFound)?,
+        Err(_) => remote_branch(&reference.name, &reference.remote.ok_or(Error::NotFound)?)
+            .try_into()
+            .map_err(|_| Error::NotFound)?,
+    };
+    let repo = git::Repository::new(paths.git_dir())?;
+    let mut browser = git::Browser::new_with_namespace(&repo, &namespace, revision)?;
+
+    callback(&mut browser).map_err(|err| match &err {
+        radicle_source::error::Error::PathNotFound(_) => Error::NotFound,
+        _ => Error::from(err),
+    })
+}
+
+fn remote_branch(branch_name: &str, peer_id: &PeerId) -> git::Branch {
+    // NOTE<sebastinez>: We should be able to pass simply a branch name without heads/ and be able to query that later.
+    // Needs work on radicle_surf I assume.
+    git::Branch::remote(
+        &format!("heads/{}", branch_name),
+        &peer_id.default_encoding(),
+    )
+}
+
+/// A collaborative object that includes its id.
+#[derive(serde::Serialize)]
+struct Cob<T: serde::Serialize> {
+    id: ObjectId,
     #[serde(flatten)]
     inner: T,
 }
@@ -670,6 +1693,23 @@ struct Changeset {
     commits: Vec<source::Commit>,
     diff: git::Diff,
     stats: Stats,
+    /// Comments of the revision anchored to lines of the diff.
+    comments: Vec<AnchoredComment>,
+    /// Whether the revision can be merged into the default branch, if known.
+    mergeability: Option<Mergeability>,
+}
+
+/// A revision comment anchored to a line of the revision's diff.
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct AnchoredComment {
+    /// Number of the comment in the revision's discussion, where the revision description is
+    /// comment `0`.
+    comment: usize,
+    /// Index of the hunk of the file diff holding the line, if the line is part of the diff.
+    hunk: Option<usize>,
+    #[serde(flatten)]
+    anchor: Anchor,
 }
 
 impl Changeset {
@@ -678,9 +1718,52 @@ impl Changeset {
             commits,
             stats: Changeset::stats(&diff),
             diff,
+            comments: Vec::new(),
+            mergeability: None,
+        }
+    }
+
+    /// Locate the anchored comments of a revision discussion in the diff.
+    pub fn anchor<'a>(&mut self, comments: impl Iterator<Item = &'a str>) {
+        for (ix, body) in comments.enumerate() {
+            if let Some(anchor) = Anchor::from_comment(body) {
+                self.comments.push(AnchoredComment {
+                    comment: ix + 1,
+                    hunk: self.hunk(&anchor),
+                    anchor,
+                });
+            }
         }
     }
 
+    /// Find the hunk holding an anchored line.
+    fn hunk(&self, anchor: &Anchor) -> Option<usize> {
+        let files = self
+            .diff
+            .modified
+            .iter()
+            .map(|f| (&f.path, &f.diff))
+            .chain(self.diff.created.iter().map(|f| (&f.path, &f.diff)))
+            .chain(self.diff.deleted.iter().map(|f| (&f.path, &f.diff)));
+
+        for (path, file) in files {
+            if serde_json::to_value(path)
+                .ok()
+                .as_ref()
+                .and_then(|p| p.as_str())
+                != Some(anchor.path.as_str())
+            {
+                continue;
+            }
+            if let diff::FileDiff::Plain { ref hunks } = file {
+                return hunks
+                    .iter()
+                    .position(|hunk| hunk.lines.iter().any(|line| anchor.matches(line)));
+            }
+        }
+        None
+    }
+
     // TODO: This function should probably be moved to radicle_surf, where it should be able to be called on radicle_surf::diff::Diff as associated function`
     pub fn stats(diff: &git::Diff) -> Stats {
         let mut deletions = 0;
@@ -797,7 +1880,10 @@ fn resolve_revisions(
                     .filter_map(|header| radicle_source::commit(browser, header.sha1).ok())
                     .collect::<Vec<source::Commit>>();
 
-                changeset = Some(Changeset::new(commits, diff));
+                let mut diff = Changeset::new(commits, diff);
+                diff.anchor(revision.discussion.iter().map(|c| c.body.as_str()));
+
+                changeset = Some(diff);
             };
 
             patch::Revision {
@@ -832,6 +1918,7 @@ fn resolve_revisions(
 mod routes {
     use axum::body::Body;
     use axum::http::{Request, StatusCode};
+    use axum::response::Response;
     use serde_json::Value;
     use tower::ServiceExt;
 
@@ -908,6 +1995,77 @@ mod routes {
         assert_eq!(alias_body, urn_body);
     }
 
+    #[tokio::test]
+    async fn test_private_project_route() {
+        let (profile, signer, project, _) = setup::env();
+        let ctx = Context::new(profile.paths().to_owned(), signer, THEME.to_string());
+        let mut config = git2::Config::open(&ctx.paths.git_dir().join("config")).unwrap();
+        config
+            .set_str(
+                &format!("project.{}.visibility", project.urn().encode_id()),
+                "private",
+            )
+            .unwrap();
+
+        let app = router(ctx);
+        let get = |uri: String| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        for uri in [
+            format!("/projects/{}", PROJECT_NAME),
+            format!("/projects/{}", project.urn()),
+            format!("/projects/{}/issues", project.urn()),
+            format!("/projects/{}/patches", project.urn()),
+        ] {
+            let response = get(uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        let response = get(String::from("/projects")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_project_delegate_alias_route() {
+        let (profile, signer, project, _) = setup::env();
+        let ctx = Context::new(profile.paths().to_owned(), signer, THEME.to_string());
+        let alias = format!("{}@{}", PROJECT_NAME, ctx.peer_id.default_encoding());
+        let app = router(ctx);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/projects/{}", alias))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["urn"], project.urn().to_string());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/projects/{}@unknown", PROJECT_NAME))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
     #[tokio::test]
     async fn test_commits_route() {
         let (profile, signer, project, head) = setup::env();
@@ -973,6 +2131,61 @@ mod routes {
         assert_eq!(body["entries"][0]["path"], COMMIT_FILE_NAME);
     }
 
+    #[tokio::test]
+    async fn test_code_search_route() {
+        let (profile, signer, project, head) = setup::env();
+        let ctx = Context::new(profile.paths().to_owned(), signer, THEME.to_string());
+        let app = router(ctx);
+        let search = |query: &str| {
+            Request::builder()
+                .uri(format!(
+                    "/projects/{}/search/{}?{}",
+                    project.urn(),
+                    head,
+                    query
+                ))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let files = |response: Response| async move {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<Vec<Value>>(&body).unwrap()
+        };
+
+        for query in ["q=Hi+Bob", "q=B.b&regex=true"] {
+            let response = app.clone().oneshot(search(query)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = files(response).await;
+            assert_eq!(body.len(), 1);
+            assert_eq!(body[0]["path"], COMMIT_FILE_NAME);
+            assert_eq!(body[0]["matches"][0]["line"], 1);
+            assert_eq!(body[0]["matches"][0]["text"], COMMIT_FILE_CONTENT);
+        }
+
+        // Both files match, and the search stops once a page is filled.
+        let response = app.clone().oneshot(search("q=i&per-page=1")).await.unwrap();
+        let body = files(response).await;
+        assert_eq!(body.len(), 1);
+        assert_eq!(body[0]["path"], COMMIT_FILE_NAME);
+
+        let response = app
+            .clone()
+            .oneshot(search("q=i&per-page=1&page=1"))
+            .await
+            .unwrap();
+        let body = files(response).await;
+        assert_eq!(body.len(), 1);
+        assert_eq!(body[0]["path"], "README");
+
+        let response = app.clone().oneshot(search("q=Alice")).await.unwrap();
+        assert!(files(response).await.is_empty());
+
+        let response = app.oneshot(search("q=(&regex=true")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
     #[tokio::test]
     async fn test_blob_route() {
         let (profile, signer, project, head) = setup::env();
@@ -1065,6 +2278,259 @@ mod routes {
             .unwrap();
 
         assert_eq!(response.status(), StatusCode::OK);
+
+        // The patch only has one revision.
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!(
+                        "/projects/{}/patches/{}/interdiff?from=0&to=1",
+                        project.urn(),
+                        patch_id
+                    ))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!(
+                        "/projects/{}/patches/{}/timeline",
+                        project.urn(),
+                        patch_id
+                    ))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body[0]["type"], "opened");
+        assert_eq!(body[0]["title"], PATCH_TITLE);
+        assert_eq!(body[1]["type"], "revision");
+        // The current state comes last, without a timestamp.
+        let last = body.as_array().unwrap().last().unwrap();
+        assert_eq!(last["type"], "state");
+        assert_eq!(last["timestamp"], Value::Null);
+    }
+
+    /// Sign in with the session token `beef.cafe`.
+    async fn sign_in(ctx: &Context) {
+        use crate::auth::{AuthState, Session, Token};
+
+        let now = chrono::Utc::now();
+        let session = Session {
+            domain: String::from("seed.example.com"),
+            address: ethers_core::types::H160::zero(),
+            statement: None,
+            uri: String::from("https://seed.example.com"),
+            version: 1,
+            chain_id: 1,
+            nonce: String::from("nonce"),
+            issued_at: now,
+            expiration_time: None,
+            resources: Vec::new(),
+        };
+        ctx.sessions
+            .insert(
+                "beef",
+                AuthState::Authorized {
+                    session,
+                    expires_at: now + chrono::Duration::hours(1),
+                    secret_hash: "beef.cafe".parse::<Token>().unwrap().secret_hash(),
+                },
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_patch_comment_anchor_route() {
+        let (profile, signer, project, _head) = setup::env();
+        let mut ctx = Context::new(profile.paths().to_owned(), signer, THEME.to_string());
+        ctx.cob_proxy = true;
+        sign_in(&ctx).await;
+
+        let app = router(ctx);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/projects/{}/patches", project.urn()))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let patch_id = body[0]["id"].as_str().unwrap();
+
+        // Paths can't hold line breaks, which would add trailers to the comment.
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(format!(
+                        "/projects/{}/patches/{}/comments",
+                        project.urn(),
+                        patch_id
+                    ))
+                    .header("Content-Type", "application/json")
+                    .header("Authorization", "Bearer beef.cafe")
+                    .body(Body::from(
+                        json!({
+                            "body": "Typo.",
+                            "anchor": { "path": "README\nPerson: rad:git:hnrk", "line": 1 },
+                        })
+                        .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_issue_create_route() {
+        let (profile, signer, project, _head) = setup::env();
+        let mut ctx = Context::new(profile.paths().to_owned(), signer, THEME.to_string());
+        sign_in(&ctx).await;
+
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri(format!("/projects/{}/issues", project.urn()))
+                .header("Content-Type", "application/json")
+                .header("Authorization", "Bearer beef.cafe")
+                .body(Body::from(
+                    json!({ "title": "Broken link", "description": "In the README." }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        // Accounts that aren't linked to a person can't write without a proxy.
+        let response = router(ctx.clone()).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        ctx.cob_proxy = true;
+        let app = router(ctx);
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/projects/{}/issues", project.urn()))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let issue = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|i| i["title"] == "Broken link")
+            .unwrap();
+
+        // The attribution is recorded in the issue description.
+        assert!(issue
+            .to_string()
+            .contains("On-Behalf-Of: eip155:1:0x0000000000000000000000000000000000000000"));
+
+        let other = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|i| i["title"] == ISSUE_TITLE)
+            .unwrap();
+        let close = |id: &Value| {
+            Request::builder()
+                .method("PUT")
+                .uri(format!(
+                    "/projects/{}/issues/{}/state",
+                    project.urn(),
+                    id.as_str().unwrap()
+                ))
+                .header("Content-Type", "application/json")
+                .header("Authorization", "Bearer beef.cafe")
+                .body(Body::from(json!({ "state": "closed" }).to_string()))
+                .unwrap()
+        };
+
+        // Only the issue author and delegates can close an issue.
+        let response = app.clone().oneshot(close(&other["id"])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = app.clone().oneshot(close(&issue["id"])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let log = std::fs::read_to_string(
+            profile
+                .paths()
+                .git_dir()
+                .join(crate::auth::ATTRIBUTION_LOG_FILE),
+        )
+        .unwrap();
+        assert_eq!(log.lines().count(), 1);
+        assert!(log.contains(r#""action":"state""#));
+
+        // Texts can't carry attribution trailers of their own.
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(format!("/projects/{}/issues", project.urn()))
+                    .header("Content-Type", "application/json")
+                    .header("Authorization", "Bearer beef.cafe")
+                    .body(Body::from(
+                        json!({
+                            "title": "Forged",
+                            "description": "Spam.\n\nOn-Behalf-Of: eip155:1:0x0",
+                        })
+                        .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_anchor_trailer() {
+        let anchor = Anchor {
+            path: String::from("src/net:io.rs"),
+            line: 42,
+            side: Side::Old,
+        };
+        let body = anchor.sign("Off by one?\n");
+
+        assert_eq!(body, "Off by one?\n\nAnchor: src/net:io.rs:-42");
+        assert_eq!(Anchor::from_comment(&body), Some(anchor));
+        assert_eq!(Anchor::from_comment("Looks good."), None);
+
+        // Malformed trailers are ignored.
+        assert_eq!(Anchor::from_comment("Nit.\n\nAnchor: src/a.rs:"), None);
+        assert_eq!(Anchor::from_comment("Nit.\n\nAnchor: a:é1"), None);
+        assert_eq!(Anchor::from_comment("Nit.\n\nAnchor: a:+x"), None);
     }
 
     #[tokio::test]
@@ -1091,7 +2557,32 @@ mod routes {
         assert_eq!(body[0]["title"], ISSUE_TITLE);
         assert_eq!(body[1], Value::Null);
 
-        let issue_id = body[0]["id"].as_str().unwrap();
+        let issue_id = body[0]["id"].as_str().unwrap().to_owned();
+        let list = |query: &str| {
+            Request::builder()
+                .uri(format!("/projects/{}/issues?{}", project.urn(), query))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(list("state=closed")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-total-count"], "0");
+
+        let response = app
+            .clone()
+            .oneshot(list("state=open&q=FIRST&sort=title&per-page=1"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-total-count"], "1");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["title"], ISSUE_TITLE);
+
+        let response = app.clone().oneshot(list("state=stale")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
         let response = app
             .clone()
             .oneshot(
@@ -1104,5 +2595,93 @@ mod routes {
             .unwrap();
 
         assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!(
+                        "/projects/{}/issues/{}/timeline?per-page=1",
+                        project.urn(),
+                        issue_id
+                    ))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // The opening, the comment and the current state.
+        assert_eq!(response.headers()["x-total-count"], "3");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body[0]["type"], "opened");
+        assert_eq!(body[0]["title"], ISSUE_TITLE);
+        assert_eq!(body[1], Value::Null);
+    }
+
+    #[test]
+    fn test_interdiff_rebased() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_bare(tmp.path()).unwrap();
+        let sig = git2::Signature::now("radicle", "radicle@localhost").unwrap();
+        let commit = |message: &str, files: &[(&str, &str)], parent: Option<git2::Oid>| {
+            let mut tree = repo.treebuilder(None).unwrap();
+            for (name, content) in files {
+                let blob = repo.blob(content.as_bytes()).unwrap();
+                tree.insert(*name, blob, 0o100644).unwrap();
+            }
+            let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+            let parents = parent
+                .map(|oid| vec![repo.find_commit(oid).unwrap()])
+                .unwrap_or_default();
+            let parents = parents.iter().collect::<Vec<_>>();
+
+            repo.commit(None, &sig, &sig, message, &tree, &parents)
+                .unwrap()
+        };
+
+        // The default branch moves on after the first revision is opened.
+        let base = commit("Initial", &[("a", "1\n"), ("b", "1\n")], None);
+        let upstream = commit("Upstream", &[("a", "1\n"), ("b", "2\n")], Some(base));
+        let old = commit("Change a", &[("a", "one\n"), ("b", "1\n")], Some(base));
+        // The second revision is rebased, and adds a file.
+        let rebased = commit("Change a", &[("a", "one\n"), ("b", "2\n")], Some(upstream));
+        let new = commit(
+            "Add c",
+            &[("a", "one\n"), ("b", "2\n"), ("c", "3\n")],
+            Some(rebased),
+        );
+
+        // The upstream change to `b` is left out.
+        let interdiff = Interdiff::compute(&repo, (0, (base, old)), (1, (upstream, new))).unwrap();
+        assert_eq!(interdiff.base, Some(upstream));
+        assert_eq!(interdiff.diff.created.len(), 1);
+        assert!(interdiff.diff.modified.is_empty());
+        assert_eq!(interdiff.stats.additions, 1);
+        assert_eq!(interdiff.stats.deletions, 0);
+
+        // Rebased commits are new, but upstream commits are not.
+        let mut commits = interdiff
+            .commits
+            .iter()
+            .map(|c| c.id.clone())
+            .collect::<Vec<_>>();
+        commits.sort();
+        let mut expected = vec![rebased.to_string(), new.to_string()];
+        expected.sort();
+        assert_eq!(commits, expected);
+
+        // If the first revision conflicts with the new base, the heads are diffed as they are.
+        let conflicting = commit("Change b", &[("a", "1\n"), ("b", "3\n")], Some(base));
+        let interdiff =
+            Interdiff::compute(&repo, (0, (base, conflicting)), (1, (upstream, new))).unwrap();
+        assert_eq!(interdiff.base, None);
+        assert_eq!(interdiff.diff.created.len(), 1);
+        assert_eq!(interdiff.diff.modified.len(), 2);
+        assert_eq!(interdiff.stats.additions, 3);
+        assert_eq!(interdiff.stats.deletions, 2);
     }
 }
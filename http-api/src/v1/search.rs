@@ -0,0 +1,133 @@
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use serde::{Deserialize, Serialize};
+
+use crate::axum_extra::Query;
+use crate::listing::{self, DEFAULT_PER_PAGE};
+use crate::search::Kind;
+use crate::{Context, Error};
+
+pub fn router(ctx: Context) -> Router {
+    Router::new()
+        .route("/search", get(search_handler))
+        .layer(Extension(ctx))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct SearchQueryString {
+    pub q: String,
+    /// Only return results of this type: `project`, `issue` or `patch`.
+    #[serde(rename = "type")]
+    pub kind: Option<Kind>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+/// Search projects, issues and patches, best matches first.
+/// `GET /search?q=<query>`
+async fn search_handler(
+    Extension(ctx): Extension<Context>,
+    Query(qs): Query<SearchQueryString>,
+) -> impl IntoResponse {
+    let page = qs.page.unwrap_or(0);
+    let per_page = qs.per_page.unwrap_or(DEFAULT_PER_PAGE);
+    // Projects may have been made private since they were indexed.
+    let private = ctx.private_projects().await?;
+    let hits = ctx
+        .search
+        .search(&qs.q, qs.kind)
+        .into_iter()
+        .filter(|hit| !private.contains(&hit.document.project.encode_id()))
+        .collect::<Vec<_>>();
+    let total = hits.len();
+    let hits = hits
+        .into_iter()
+        .skip(page * per_page)
+        .take(per_page)
+        .collect::<Vec<_>>();
+
+    Ok::<_, Error>((listing::headers(total), Json(hits)))
+}
+
+#[cfg(test)]
+mod routes {
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_extra::setup;
+
+    const THEME: &str = "base16-ocean.dark";
+    const PROJECT_NAME: &str = "nakamoto";
+    const ISSUE_TITLE: &str = "My first issue";
+
+    #[tokio::test]
+    async fn test_search_route() {
+        let (profile, signer, project, _) = setup::env();
+        let ctx = Context::new(profile.paths().to_owned(), signer, THEME.to_string());
+        {
+            let storage = ctx.storage().await.unwrap();
+            ctx.search.build(&storage, &ctx.paths).unwrap();
+        }
+        let app = router(ctx);
+        let search = |query: &str| {
+            Request::builder()
+                .uri(format!("/search?{}", query))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Projects are found by the names of their delegates.
+        let response = app.clone().oneshot(search("q=cloudhead")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-total-count"], "1");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["type"], "project");
+        assert_eq!(body[0]["title"], PROJECT_NAME);
+        assert_eq!(body[0]["project"], project.urn().to_string());
+
+        let response = app
+            .clone()
+            .oneshot(search("q=first+iss&type=issue"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["type"], "issue");
+        assert_eq!(body[0]["title"], ISSUE_TITLE);
+        assert_eq!(body[1], Value::Null);
+
+        let response = app.oneshot(search("q=satoshi")).await.unwrap();
+        assert_eq!(response.headers()["x-total-count"], "0");
+    }
+
+    #[tokio::test]
+    async fn test_search_reconcile() {
+        use radicle_common::{cobs, person};
+
+        let (profile, signer, project, _) = setup::env();
+        let ctx = Context::new(profile.paths().to_owned(), signer, THEME.to_string());
+        let storage = ctx.storage().await.unwrap();
+
+        // Everything is indexed the first time, and nothing after that.
+        assert!(ctx.search.reconcile(&storage, &ctx.paths).unwrap() > 0);
+        assert_eq!(ctx.search.reconcile(&storage, &ctx.paths).unwrap(), 0);
+
+        // Issues replicated from peers aren't notified.
+        let whoami = person::local(&*storage).unwrap();
+        let cobs = cobs::Store::new(whoami, &ctx.paths, &storage);
+        cobs.issues()
+            .create(&project.urn(), "Replicated issue", "From a peer.", &[])
+            .unwrap();
+        assert!(ctx.search.search("replicated", None).is_empty());
+
+        assert_ne!(ctx.search.reconcile(&storage, &ctx.paths).unwrap(), 0);
+        assert_eq!(ctx.search.search("replicated", None).len(), 1);
+    }
+}
@@ -1,21 +1,22 @@
-use std::collections::HashMap;
-use std::convert::TryInto;
-use std::env;
-use std::iter::repeat_with;
+use std::convert::{TryFrom, TryInto};
 use std::str::FromStr;
 use std::time::Duration;
 
+use axum::http::header::SET_COOKIE;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use axum::{Extension, Json, Router};
 use chrono::{DateTime, Utc};
-use ethers_core::utils::hex;
-use hyper::http::uri::Authority;
+use ethers_core::types::H160;
 use serde_json::json;
-use siwe::Message;
+use siwe::{Message, VerificationError};
 
-use crate::auth::{AuthRequest, AuthState, Session};
+use crate::auth::{
+    random_hex, AuthRequest, AuthSession, AuthState, Session, SigninError, Token, SESSION_COOKIE,
+};
 use crate::axum_extra::Path;
+use crate::sessions::Sessions;
 use crate::{Context, Error};
 
 pub const UNAUTHORIZED_SESSIONS_EXPIRATION: Duration = Duration::from_secs(60);
@@ -25,8 +26,11 @@ pub fn router(ctx: Context) -> Router {
         .route("/sessions", post(session_create_handler))
         .route(
             "/sessions/:id",
-            get(session_get_handler).put(session_signin_handler),
+            get(session_get_handler)
+                .put(session_signin_handler)
+                .delete(session_delete_handler),
         )
+        .route("/sessions/:id/refresh", post(session_refresh_handler))
         .layer(Extension(ctx))
 }
 
@@ -35,10 +39,9 @@ pub fn router(ctx: Context) -> Router {
 async fn session_create_handler(Extension(ctx): Extension<Context>) -> impl IntoResponse {
     let expiration_time =
         Utc::now() + chrono::Duration::from_std(UNAUTHORIZED_SESSIONS_EXPIRATION).unwrap();
-    let mut sessions = ctx.sessions.write().await;
-    let (session_id, nonce) = create_session(&mut sessions, expiration_time);
+    let (session_id, nonce) = create_session(&ctx.sessions, expiration_time).await?;
 
-    Json(json!({ "id": session_id, "nonce": nonce }))
+    Ok::<_, Error>(Json(json!({ "id": session_id, "nonce": nonce })))
 }
 
 /// Get session.
@@ -47,13 +50,16 @@ async fn session_get_handler(
     Extension(ctx): Extension<Context>,
     Path(id): Path<String>,
 ) -> impl IntoResponse {
-    let sessions = ctx.sessions.read().await;
-    let session = sessions.get(&id).ok_or(Error::NotFound)?;
+    let session = ctx.sessions.get(&id).await?.ok_or(Error::NotFound)?;
 
     match session {
-        AuthState::Authorized(session) => {
-            Ok::<_, Error>(Json(json!({ "id": id, "session": session })))
-        }
+        AuthState::Authorized {
+            session,
+            expires_at,
+            ..
+        } => Ok::<_, Error>(Json(
+            json!({ "id": id, "session": session, "expiresAt": expires_at }),
+        )),
         AuthState::Unauthorized {
             nonce,
             expiration_time,
@@ -71,58 +77,295 @@ async fn session_signin_handler(
     Json(request): Json<AuthRequest>,
 ) -> impl IntoResponse {
     // Get unauthenticated session data, return early if not found
-    let mut sessions = ctx.sessions.write().await;
-    let session = sessions.get(&id).ok_or(Error::NotFound)?;
+    let session = ctx.sessions.get(&id).await?.ok_or(Error::NotFound)?;
 
     if let AuthState::Unauthorized { nonce, .. } = session {
         let message = Message::from_str(request.message.as_str()).map_err(Error::from)?;
 
-        let host = env::var("RADICLE_DOMAIN").map_err(Error::from)?;
-
-        // Validate nonce
-        if *nonce != message.nonce {
-            return Err(Error::Auth("Invalid nonce"));
+        if nonce != message.nonce {
+            return Err(SigninError::InvalidNonce.into());
         }
+        // Verify the domain, URI, chain and statement.
+        ctx.siwe.check(&message)?;
 
-        // Verify that domain is the correct one
-        let authority = Authority::from_str(&host).map_err(|_| Error::Auth("Invalid host"))?;
-        if authority != message.domain {
-            return Err(Error::Auth("Invalid domain"));
-        }
+        verify(&ctx, &message, &request).await?;
+
+        let session: Session = message.try_into()?;
+        let expires_at = session.expiry(Utc::now(), ctx.siwe.session_lifetime());
+        let token = Token::generate(id.clone());
+        ctx.sessions
+            .insert(
+                &id,
+                AuthState::Authorized {
+                    session: session.clone(),
+                    expires_at,
+                    secret_hash: token.secret_hash(),
+                },
+            )
+            .await?;
 
+        return Ok::<_, Error>((
+            cookie(&ctx, Some((&token, expires_at))),
+            Json(json!({
+                "id": id,
+                "token": token.to_string(),
+                "session": session,
+                "expiresAt": expires_at
+            })),
+        ));
+    }
+
+    Err(SigninError::AlreadySignedIn.into())
+}
+
+/// Verify the signature of a sign-in message. Signatures that aren't valid ECDSA signatures of the
+/// signer are verified as contract wallet signatures, if enabled.
+async fn verify(ctx: &Context, message: &Message, request: &AuthRequest) -> Result<(), Error> {
+    let invalid = match <[u8; 65]>::try_from(request.signature.as_ref()) {
         // Verifies the following:
         // - AuthRequest sig matches the address passed in the AuthRequest message.
         // - expirationTime is not in the past.
         // - notBefore time is in the future.
-        message
-            .verify(request.signature.into())
-            .map_err(Error::from)?;
+        Ok(signature) => match message.verify(signature) {
+            Ok(_) => return Ok(()),
+            Err(VerificationError::Time) => return Err(SigninError::NotCurrentlyValid.into()),
+            Err(VerificationError::Signer | VerificationError::Crypto(_)) => {
+                SigninError::InvalidSignature
+            }
+            Err(err) => return Err(err.into()),
+        },
+        Err(_) => SigninError::InvalidSignature,
+    };
+    let verifier = ctx.erc1271.as_ref().ok_or(invalid)?;
 
-        let session: Session = message.try_into()?;
-        sessions.insert(id.clone(), AuthState::Authorized(session.clone()));
+    let now = Utc::now();
+    if matches!(&message.expiration_time, Some(t) if now >= t.as_ref().with_timezone(&Utc))
+        || matches!(&message.not_before, Some(t) if now < t.as_ref().with_timezone(&Utc))
+    {
+        return Err(SigninError::NotCurrentlyValid.into());
+    }
+    let valid = verifier
+        .verify(
+            H160(message.address),
+            &request.message,
+            &message.nonce,
+            &request.signature,
+        )
+        .await?;
+
+    if !valid {
+        return Err(invalid.into());
+    }
+    Ok(())
+}
+
+/// Extend an authorized session, up to the expiration time of its signed message.
+/// `POST /sessions/:id/refresh`
+///
+/// The session token must be sent as a bearer token or cookie.
+async fn session_refresh_handler(
+    Extension(ctx): Extension<Context>,
+    Path(id): Path<String>,
+    auth: AuthSession,
+) -> impl IntoResponse {
+    if auth.id != id {
+        return Err(Error::Unauthorized("Session mismatch"));
+    }
+    let now = Utc::now();
+    let expires_at = auth.session.expiry(now, ctx.siwe.session_lifetime());
 
-        return Ok::<_, Error>(Json(json!({ "id": id, "session": session })));
+    // Sessions can't be extended past the expiration time of their signed message.
+    if expires_at <= now {
+        ctx.sessions.remove(&id).await?;
+
+        return Err(Error::Unauthorized("Session expired"));
     }
+    ctx.sessions
+        .insert(
+            &id,
+            AuthState::Authorized {
+                session: auth.session.clone(),
+                expires_at,
+                secret_hash: auth.token.secret_hash(),
+            },
+        )
+        .await?;
 
-    Err(Error::Auth("Session already authorized"))
+    Ok::<_, Error>((
+        cookie(&ctx, Some((&auth.token, expires_at))),
+        Json(json!({ "id": id, "session": auth.session, "expiresAt": expires_at })),
+    ))
 }
 
-fn create_session(
-    map: &mut HashMap<String, AuthState>,
+/// Revoke an authorized session.
+/// `DELETE /sessions/:id`
+///
+/// Like for refreshes, the session token must be sent as a bearer token or cookie.
+async fn session_delete_handler(
+    Extension(ctx): Extension<Context>,
+    Path(id): Path<String>,
+    auth: AuthSession,
+) -> impl IntoResponse {
+    if auth.id != id {
+        return Err(Error::Unauthorized("Session mismatch"));
+    }
+    ctx.sessions.remove(&id).await?.ok_or(Error::NotFound)?;
+
+    Ok::<_, Error>((cookie(&ctx, None), StatusCode::NO_CONTENT))
+}
+
+/// Get the headers setting the session cookie to the given token, until it expires, if
+/// sessions are sent as cookies. Without a token, the cookie is cleared.
+fn cookie(ctx: &Context, token: Option<(&Token, DateTime<Utc>)>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    if ctx.session_cookie {
+        let (value, max_age) = match token {
+            Some((token, expires_at)) => (
+                token.to_string(),
+                (expires_at - Utc::now()).num_seconds().max(0),
+            ),
+            None => (String::new(), 0),
+        };
+        let value = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+            SESSION_COOKIE, value, max_age
+        );
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(SET_COOKIE, value);
+        }
+    }
+    headers
+}
+
+async fn create_session(
+    sessions: &Sessions,
     expiration_time: DateTime<Utc>,
-) -> (String, String) {
+) -> Result<(String, String), Error> {
     let nonce = siwe::nonce::generate_nonce();
-
-    // We generate a value from the RNG for the session id
-    let rng = fastrand::Rng::new();
-    let id = hex::encode(repeat_with(|| rng.u8(..)).take(32).collect::<Vec<u8>>());
+    let id = random_hex(32);
 
     let auth_state = AuthState::Unauthorized {
         nonce: nonce.clone(),
         expiration_time,
     };
 
-    map.insert(id.clone(), auth_state);
+    sessions.insert(&id, auth_state).await?;
+
+    Ok((id, nonce))
+}
+
+#[cfg(test)]
+mod routes {
+    use axum::body::Body;
+    use axum::http::header::AUTHORIZATION;
+    use axum::http::{Method, Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_extra::setup;
 
-    (id, nonce)
+    const THEME: &str = "base16-ocean.dark";
+
+    #[tokio::test]
+    async fn test_session_refresh_delete_route() {
+        let (profile, signer, _, _) = setup::env();
+        let ctx = Context::new(profile.paths().to_owned(), signer, THEME.to_string());
+        let expiration_time = Utc::now() + chrono::Duration::seconds(60);
+        let (id, _) = create_session(&ctx.sessions, expiration_time)
+            .await
+            .unwrap();
+        let sessions = ctx.sessions.clone();
+        let app = router(ctx);
+
+        // Sessions that weren't signed in can't be refreshed.
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("/sessions/{}/refresh", id))
+                    .header(AUTHORIZATION, format!("Bearer {}.cafe", id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let now = Utc::now();
+        let session = Session {
+            domain: String::from("seed.example.com"),
+            address: H160::zero(),
+            statement: None,
+            uri: String::from("https://seed.example.com"),
+            version: 1,
+            chain_id: 1,
+            nonce: String::from("nonce"),
+            issued_at: now,
+            expiration_time: None,
+            resources: Vec::new(),
+        };
+        let token = Token::generate(id.clone());
+        let other = Token::generate(String::from("beef"));
+        for token in [&token, &other] {
+            sessions
+                .insert(
+                    &token.id,
+                    AuthState::Authorized {
+                        session: session.clone(),
+                        expires_at: now + chrono::Duration::hours(1),
+                        secret_hash: token.secret_hash(),
+                    },
+                )
+                .await
+                .unwrap();
+        }
+        let delete = |bearer: Option<&str>| {
+            let mut request = Request::builder()
+                .method(Method::DELETE)
+                .uri(format!("/sessions/{}", id));
+            if let Some(bearer) = bearer {
+                request = request.header(AUTHORIZATION, format!("Bearer {}", bearer));
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        // Sessions can only be revoked by their holder.
+        let response = app.clone().oneshot(delete(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(delete(Some(&other.to_string())))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // The session id isn't enough, since it appears in URLs.
+        for bearer in [id.clone(), format!("{}.{}", id, other.secret)] {
+            let response = app.clone().oneshot(delete(Some(&bearer))).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = app
+            .clone()
+            .oneshot(delete(Some(&token.to_string())))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}", id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
 }
@@ -0,0 +1,189 @@
+//! Read access control for private projects.
+//!
+//! Projects are public by default. A project can be made private in the monorepo's git config,
+//! optionally listing peers that may read it in addition to its delegates:
+//!
+//! ```text
+//! [project "hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo"]
+//!     visibility = private
+//!     reader = hyypw8z5g7tbui9ceh6tng58i1qk696isjnzix9fq9g41fzgjgqk8g
+//! ```
+//!
+//! Private projects can only be read by an authenticated [`Principal`] that is allowed to read
+//! them. Services that don't authenticate readers, such as the HTTP API, hide them altogether.
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use librad::PeerId;
+
+/// An access control error.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Git error.
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+
+    /// The visibility of a project is invalid.
+    #[error("project visibility must be 'public' or 'private', got '{0}'")]
+    InvalidVisibility(String),
+
+    /// A reader of a project is not a valid peer id.
+    #[error("project reader '{0}' is not a valid peer id")]
+    InvalidReader(String),
+}
+
+/// Who can read a project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    /// Anyone can read the project.
+    Public,
+    /// Only authenticated principals can read the project.
+    Private,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Self::Public
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Self::Public),
+            "private" => Ok(Self::Private),
+            _ => Err(Error::InvalidVisibility(s.to_owned())),
+        }
+    }
+}
+
+/// Who is reading a project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Principal<'a> {
+    /// An unauthenticated reader.
+    Anonymous,
+    /// A reader authenticated for a single project, by namespace.
+    Project(&'a str),
+    /// A reader authenticated as a peer.
+    Peer(&'a PeerId),
+}
+
+/// Access settings of a project.
+#[derive(Debug, Default, Clone)]
+pub struct Access {
+    /// Who can read the project.
+    pub visibility: Visibility,
+    /// Peers allowed to read the project, in addition to its delegates.
+    pub readers: Vec<PeerId>,
+}
+
+impl Access {
+    /// Load the access settings of a project.
+    pub fn load(config: &git2::Config, namespace: &str) -> Result<Self, Error> {
+        let visibility = match config.get_string(&format!("project.{}.visibility", namespace)) {
+            Ok(visibility) => visibility.parse()?,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Visibility::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut readers = Vec::new();
+
+        match config.multivar(&format!("project.{}.reader", namespace), None) {
+            Ok(entries) => {
+                for entry in &entries {
+                    if let Some(value) = entry?.value() {
+                        readers.push(
+                            PeerId::from_default_encoding(value)
+                                .map_err(|_| Error::InvalidReader(value.to_owned()))?,
+                        );
+                    }
+                }
+            }
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(Self {
+            visibility,
+            readers,
+        })
+    }
+
+    /// Whether the project is private.
+    pub fn is_private(&self) -> bool {
+        self.visibility == Visibility::Private
+    }
+
+    /// Check whether the given principal may read the project.
+    pub fn can_read(&self, namespace: &str, delegates: &[PeerId], principal: Principal) -> bool {
+        if !self.is_private() {
+            return true;
+        }
+        match principal {
+            Principal::Anonymous => false,
+            // Principals authenticated for a project only have access to that project.
+            Principal::Project(project) => project == namespace,
+            Principal::Peer(peer) => delegates.contains(peer) || self.readers.contains(peer),
+        }
+    }
+}
+
+/// Get the namespaces of all private projects. Projects with an invalid visibility are
+/// considered private.
+pub fn private(config: &git2::Config) -> Result<HashSet<String>, Error> {
+    let mut namespaces = HashSet::new();
+    let entries = config.entries(Some(r"^project\..+\.visibility$"))?;
+
+    for entry in &entries {
+        let entry = entry?;
+        let namespace = entry
+            .name()
+            .and_then(|name| name.strip_prefix("project."))
+            .and_then(|name| name.strip_suffix(".visibility"));
+
+        if let Some(namespace) = namespace {
+            if entry.value().and_then(|v| v.parse().ok()) != Some(Visibility::Public) {
+                namespaces.insert(namespace.to_owned());
+            }
+        }
+    }
+    Ok(namespaces)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_private() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = git2::Config::open(&tmp.path().join("config")).unwrap();
+
+        config
+            .set_str("project.acme.visibility", "private")
+            .unwrap();
+        config
+            .set_str("project.radicle.visibility", "public")
+            .unwrap();
+        config.set_str("project.typo.visibility", "privat").unwrap();
+        config
+            .set_str("project.nakamoto.alias", "nakamoto")
+            .unwrap();
+
+        let private = private(&config.snapshot().unwrap()).unwrap();
+        let mut private = private.into_iter().collect::<Vec<_>>();
+        private.sort();
+
+        assert_eq!(private, vec!["acme", "typo"]);
+        let acme = Access::load(&config, "acme").unwrap();
+        assert!(acme.is_private());
+        assert!(!acme.can_read("acme", &[], Principal::Anonymous));
+        assert!(acme.can_read("acme", &[], Principal::Project("acme")));
+        assert!(!acme.can_read("acme", &[], Principal::Project("radicle")));
+
+        assert!(!Access::load(&config, "radicle").unwrap().is_private());
+        assert!(!Access::load(&config, "nakamoto").unwrap().is_private());
+        assert!(Access::load(&config, "typo").is_err());
+    }
+}
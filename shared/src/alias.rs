@@ -0,0 +1,506 @@
+//! Project aliases.
+//!
+//! Projects can be referred to by name instead of by id, eg. `nakamoto` or `nakamoto.git`. Since
+//! project names aren't unique, the registry decides which project an alias resolves to:
+//!
+//! 1. Aliases assigned by the operator in the monorepo's git config always win:
+//!
+//!    ```text
+//!    [project "hnrkyghsrokxzxpy9pww69xr11dr9q7edbxfo"]
+//!        alias = nakamoto
+//!    ```
+//!
+//! 2. Otherwise, a name resolves to the first project that claimed it. Claims are persisted in
+//!    the monorepo git directory, so that they survive restarts and are shared by all services
+//!    using the same monorepo, which update them under a file lock. A claim is released when its project is renamed or removed. When
+//!    several projects claim a name at once, the oldest project, by identity creation time, wins.
+//!
+//! 3. `<name>@<delegate>` resolves to the project with that name and delegate peer id, which
+//!    disambiguates projects sharing a name.
+//!
+//! Entries are invalidated when the identity of the project they resolve to changes. Aliases
+//! that don't resolve are remembered until the registry is next updated, eg. on a notification,
+//! so that unknown names don't cause a refresh on every request.
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+use either::Either;
+use serde::{Deserialize, Serialize};
+
+use librad::git::identities::{self, SomeIdentity};
+use librad::git::storage::ReadOnly;
+use librad::git::Urn;
+use librad::PeerId;
+
+use crate::lock::FileLock;
+
+/// File in the monorepo git directory where alias claims are stored.
+pub const ALIASES_FILE: &str = "aliases.json";
+/// Maximum number of aliases remembered as not resolving.
+pub const MAX_MISSES: usize = 1024;
+/// How long aliases are remembered as not resolving, for services that aren't notified of
+/// changes.
+pub const MISS_LIFETIME: Duration = Duration::from_secs(60);
+
+/// An alias registry error.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Git error.
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+
+    /// An error occured with radicle identities.
+    #[error(transparent)]
+    Identities(#[from] identities::Error),
+
+    /// The aliases file could not be parsed.
+    #[error("aliases file is corrupted: {0}")]
+    Corrupted(#[from] serde_json::Error),
+}
+
+/// A project known to the registry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Project {
+    /// Project name.
+    name: String,
+    /// Delegate peer ids of the project.
+    delegates: Vec<String>,
+    /// Commit of the project identity the entry was computed from.
+    revision: String,
+    /// Creation time of the project identity, in seconds since the epoch.
+    #[serde(default)]
+    created: i64,
+}
+
+impl Project {
+    fn new(doc: &identities::Project, repo: &git2::Repository) -> Self {
+        let delegates = doc
+            .delegations()
+            .into_iter()
+            .flat_map(|d| match d {
+                Either::Left(key) => vec![PeerId::from(*key)],
+                Either::Right(person) => person
+                    .delegations()
+                    .iter()
+                    .cloned()
+                    .map(PeerId::from)
+                    .collect(),
+            })
+            .map(|peer| peer.default_encoding())
+            .collect();
+
+        let created = repo
+            .find_commit(*doc.root)
+            .map(|c| c.time().seconds())
+            .unwrap_or_default();
+
+        Self {
+            name: doc.payload().subject.name.to_string(),
+            delegates,
+            revision: doc.content_id.to_string(),
+            created,
+        }
+    }
+}
+
+/// Outcome of resolving an alias without refreshing the registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cached {
+    /// The alias resolves to this project.
+    Found(Urn),
+    /// The alias is known not to resolve.
+    Missing,
+    /// The registry must be refreshed to tell.
+    Unknown,
+}
+
+/// Persisted registry state.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct State {
+    /// Known projects, by URN.
+    projects: BTreeMap<String, Project>,
+    /// Names claimed by projects.
+    claims: BTreeMap<String, String>,
+}
+
+/// The alias registry of a monorepo.
+#[derive(Debug)]
+pub struct Aliases {
+    git_dir: PathBuf,
+    state: State,
+    /// Modification time of the aliases file when it was last read or written.
+    modified: Option<SystemTime>,
+    /// Aliases that didn't resolve after a refresh, and when.
+    misses: HashMap<String, Instant>,
+}
+
+impl Aliases {
+    /// Create a registry for the monorepo at the given git directory. The persisted state is
+    /// loaded on first use.
+    pub fn new(git_dir: &Path) -> Self {
+        Self {
+            git_dir: git_dir.to_path_buf(),
+            state: State::default(),
+            modified: None,
+            misses: HashMap::new(),
+        }
+    }
+
+    /// Resolve an alias without modifying the registry, so that callers sharing the registry
+    /// only need a read lock. If this returns [`Cached::Unknown`], the alias must be resolved
+    /// with [`Aliases::resolve`].
+    pub fn resolve_cached(&self, storage: &ReadOnly, alias: &str) -> Result<Cached, Error> {
+        if !self.is_loaded()? {
+            return Ok(Cached::Unknown);
+        }
+        match self.lookup(alias)? {
+            Some(urn) if self.is_current(storage, &urn)? => Ok(Cached::Found(urn)),
+            Some(_) => Ok(Cached::Unknown),
+            None if self.is_missing(alias) => Ok(Cached::Missing),
+            None => Ok(Cached::Unknown),
+        }
+    }
+
+    /// Resolve an alias to a project.
+    ///
+    /// The registry is refreshed from storage if the alias is unknown, or if the identity of the
+    /// project it resolves to changed. Aliases that still don't resolve aren't refreshed again
+    /// until the registry is updated.
+    pub fn resolve(&mut self, storage: &ReadOnly, alias: &str) -> Result<Option<Urn>, Error> {
+        self.reload()?;
+
+        match self.lookup(alias)? {
+            Some(urn) if self.is_current(storage, &urn)? => return Ok(Some(urn)),
+            Some(_) => {}
+            None if self.is_missing(alias) => return Ok(None),
+            None => {}
+        }
+        self.refresh(storage)?;
+
+        let urn = self.lookup(alias)?;
+        if urn.is_none() {
+            self.miss(alias);
+        }
+        Ok(urn)
+    }
+
+    /// Rebuild the registry from the identities in storage, releasing the claims of renamed or
+    /// removed projects, and persist it.
+    pub fn refresh(&mut self, storage: &ReadOnly) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        self.misses.clear();
+
+        let repo = git2::Repository::open_bare(&self.git_dir)?;
+        let mut state = State::default();
+        for identity in identities::any::list(storage)?.flatten() {
+            if let SomeIdentity::Project(doc) = identity {
+                state
+                    .projects
+                    .insert(doc.urn().to_string(), Project::new(&doc, &repo));
+            }
+        }
+        state.claims = self.state.claims.clone();
+
+        self.settle(state)
+    }
+
+    /// Update the registry entry of a single project, eg. after its refs changed. The entry is
+    /// removed if the project no longer exists.
+    pub fn update(&mut self, storage: &ReadOnly, urn: &Urn) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        self.misses.clear();
+
+        let mut state = self.state.clone();
+        match identities::any::get(storage, urn)? {
+            Some(SomeIdentity::Project(doc)) => {
+                let repo = git2::Repository::open_bare(&self.git_dir)?;
+                state
+                    .projects
+                    .insert(urn.to_string(), Project::new(&doc, &repo));
+            }
+            _ => {
+                state.projects.remove(&urn.to_string());
+            }
+        }
+
+        self.settle(state)
+    }
+
+    /// Settle the claims of the given state, and persist it if it changed.
+    fn settle(&mut self, mut state: State) -> Result<(), Error> {
+        // Existing claims are kept as long as the project still has that name.
+        let projects = &state.projects;
+        state
+            .claims
+            .retain(|name, urn| matches!(projects.get(urn.as_str()), Some(p) if p.name == *name));
+
+        // Unclaimed names go to the oldest project having them.
+        let mut projects = state.projects.iter().collect::<Vec<_>>();
+        projects.sort_by_key(|(urn, project)| (project.created, *urn));
+
+        for (urn, project) in projects {
+            if !state.claims.contains_key(&project.name) {
+                tracing::info!("alias {:?} for {:?}", project.name, urn);
+
+                state.claims.insert(project.name.clone(), urn.clone());
+            }
+        }
+
+        if state != self.state {
+            self.state = state;
+            self.write()?;
+        }
+        Ok(())
+    }
+
+    /// Look up an alias, without refreshing the registry.
+    fn lookup(&self, alias: &str) -> Result<Option<Urn>, Error> {
+        let urn = if let Some(urn) = self.assigned(alias)? {
+            Some(urn)
+        } else if let Some(urn) = self.state.claims.get(alias) {
+            Some(urn.clone())
+        } else if let Some((name, delegate)) = alias.rsplit_once('@') {
+            let delegate = match PeerId::from_default_encoding(delegate) {
+                Ok(peer) => peer.default_encoding(),
+                Err(_) => return Ok(None),
+            };
+            let mut matching = self
+                .state
+                .projects
+                .iter()
+                .filter(|(_, p)| p.name == name && p.delegates.contains(&delegate))
+                .map(|(urn, _)| urn.clone());
+
+            // Ambiguous aliases don't resolve.
+            match (matching.next(), matching.next()) {
+                (Some(urn), None) => Some(urn),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        Ok(urn.and_then(|urn| Urn::from_str(&urn).ok()))
+    }
+
+    /// Get the project an alias was assigned to by the operator, if any.
+    fn assigned(&self, alias: &str) -> Result<Option<String>, Error> {
+        let config = match git2::Config::open(&self.git_dir.join("config")) {
+            Ok(config) => config,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let entries = config.entries(Some(r"project\..*\.alias"))?;
+
+        for entry in &entries {
+            let entry = entry?;
+
+            if entry.value() == Some(alias) {
+                let id = entry
+                    .name()
+                    .and_then(|n| n.strip_prefix("project."))
+                    .and_then(|n| n.strip_suffix(".alias"));
+
+                if let Some(id) = id {
+                    return Ok(Some(format!("rad:git:{}", id)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether an alias is remembered as not resolving.
+    fn is_missing(&self, alias: &str) -> bool {
+        matches!(self.misses.get(alias), Some(t) if t.elapsed() < MISS_LIFETIME)
+    }
+
+    /// Remember that an alias doesn't resolve.
+    fn miss(&mut self, alias: &str) {
+        if self.misses.len() >= MAX_MISSES {
+            self.misses.clear();
+        }
+        self.misses.insert(alias.to_owned(), Instant::now());
+    }
+
+    /// Whether the registry entry of a project matches its identity in storage.
+    fn is_current(&self, storage: &ReadOnly, urn: &Urn) -> Result<bool, Error> {
+        let entry = match self.state.projects.get(&urn.to_string()) {
+            Some(entry) => entry,
+            // Operator-assigned aliases may point to projects we don't know about yet.
+            None => return Ok(true),
+        };
+        match identities::any::get(storage, urn)? {
+            Some(SomeIdentity::Project(doc)) => Ok(doc.content_id.to_string() == entry.revision),
+            _ => Ok(false),
+        }
+    }
+
+    /// Whether the persisted state is loaded, ie. it wasn't modified since it was last read.
+    fn is_loaded(&self) -> Result<bool, Error> {
+        match fs::metadata(self.git_dir.join(ALIASES_FILE)) {
+            Ok(meta) => Ok(self.modified == Some(meta.modified()?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Load the persisted state, if it was modified since it was last read. Aliases that didn't
+    /// resolve may resolve in the new state.
+    fn reload(&mut self) -> Result<(), Error> {
+        let path = self.git_dir.join(ALIASES_FILE);
+        let modified = match fs::metadata(&path) {
+            Ok(meta) => meta.modified()?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if self.modified == Some(modified) {
+            return Ok(());
+        }
+        self.state = serde_json::from_slice(&fs::read(&path)?)?;
+        self.modified = Some(modified);
+        self.misses.clear();
+
+        Ok(())
+    }
+
+    /// Lock the persisted state for an update, and load it, so that the changes of other
+    /// processes aren't overwritten. The state is read even if its modification time didn't
+    /// change, since it may have been written again within the time's resolution.
+    fn lock(&mut self) -> Result<FileLock, Error> {
+        let lock = FileLock::exclusive(&self.git_dir.join(ALIASES_FILE))?;
+
+        self.modified = None;
+        self.reload()?;
+
+        Ok(lock)
+    }
+
+    fn write(&mut self) -> Result<(), Error> {
+        let path = self.git_dir.join(ALIASES_FILE);
+        let tmp = path.with_extension("json.tmp");
+
+        fs::write(&tmp, serde_json::to_vec_pretty(&self.state)?)?;
+        fs::rename(&tmp, &path)?;
+
+        self.modified = Some(fs::metadata(&path)?.modified()?);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use librad::SecretKey;
+
+    use super::*;
+
+    fn urn(seed: &str) -> Urn {
+        let oid = git2::Oid::hash_object(git2::ObjectType::Blob, seed.as_bytes()).unwrap();
+
+        Urn::new(oid.into())
+    }
+
+    fn project(name: &str, delegate: &PeerId, created: i64) -> Project {
+        Project {
+            name: name.to_owned(),
+            delegates: vec![delegate.default_encoding()],
+            revision: String::new(),
+            created,
+        }
+    }
+
+    #[test]
+    fn test_aliases() {
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path();
+        let mut aliases = Aliases::new(git_dir);
+        let alice = PeerId::from(SecretKey::new());
+        let bob = PeerId::from(SecretKey::new());
+        // The oldest project sorts last, so that it isn't first by URN.
+        let (old, new) = {
+            let (a, b) = (urn("a"), urn("b"));
+            if a.to_string() > b.to_string() {
+                (a, b)
+            } else {
+                (b, a)
+            }
+        };
+        let other = urn("other");
+
+        // Names are claimed by the oldest project.
+        let mut state = State::default();
+        state
+            .projects
+            .insert(new.to_string(), project("nakamoto", &alice, 2));
+        state
+            .projects
+            .insert(old.to_string(), project("nakamoto", &bob, 1));
+        state.claims = aliases.state.claims.clone();
+        aliases.settle(state.clone()).unwrap();
+
+        assert_eq!(aliases.lookup("nakamoto").unwrap(), Some(old.clone()));
+
+        // Projects sharing a name are told apart by delegate.
+        let alias = format!("nakamoto@{}", alice.default_encoding());
+        assert_eq!(aliases.lookup(&alias).unwrap(), Some(new.clone()));
+        let alias = format!("nakamoto@{}", bob.default_encoding());
+        assert_eq!(aliases.lookup(&alias).unwrap(), Some(old.clone()));
+        assert_eq!(aliases.lookup("nakamoto@bob").unwrap(), None);
+
+        // Claims are kept, even over older projects added later.
+        state
+            .projects
+            .insert(other.to_string(), project("nakamoto", &alice, 0));
+        state.claims = aliases.state.claims.clone();
+        aliases.settle(state.clone()).unwrap();
+
+        assert_eq!(aliases.lookup("nakamoto").unwrap(), Some(old.clone()));
+        // Ambiguous aliases don't resolve.
+        let alias = format!("nakamoto@{}", alice.default_encoding());
+        assert_eq!(aliases.lookup(&alias).unwrap(), None);
+
+        // Renaming a project releases its claim, to the oldest remaining project.
+        state
+            .projects
+            .insert(old.to_string(), project("satoshi", &bob, 1));
+        state.claims = aliases.state.claims.clone();
+        aliases.settle(state.clone()).unwrap();
+
+        assert_eq!(aliases.lookup("nakamoto").unwrap(), Some(other.clone()));
+        assert_eq!(aliases.lookup("satoshi").unwrap(), Some(old.clone()));
+
+        // Aliases assigned by the operator win.
+        git2::Config::open(&git_dir.join("config"))
+            .unwrap()
+            .set_str(&format!("project.{}.alias", new.encode_id()), "nakamoto")
+            .unwrap();
+
+        assert_eq!(aliases.lookup("nakamoto").unwrap(), Some(new.clone()));
+
+        // Other registries of the monorepo pick up the changes, and forget their misses.
+        let mut registry = Aliases::new(git_dir);
+        registry.miss("satoshi");
+        assert!(registry.is_missing("satoshi"));
+
+        registry.reload().unwrap();
+        assert!(!registry.is_missing("satoshi"));
+        assert_eq!(registry.state, aliases.state);
+        assert_eq!(registry.lookup("satoshi").unwrap(), Some(old));
+
+        // Locking the state for an update reads it, even if it looks unchanged.
+        registry.state = State::default();
+        let lock = registry.lock().unwrap();
+        assert_eq!(registry.state, aliases.state);
+        drop(lock);
+    }
+}
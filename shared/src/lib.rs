@@ -1,4 +1,10 @@
+pub mod access;
+pub mod alias;
 pub mod identity;
+pub mod lock;
+pub mod notify;
+pub mod proxy;
+pub mod ratelimit;
 pub mod signer;
 
 mod logging;
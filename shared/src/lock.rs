@@ -0,0 +1,61 @@
+//! Advisory file locks, for state files that are read, modified and written back by several
+//! processes, such as the HTTP API, the git server and its hooks.
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt as _;
+
+/// An exclusive lock on a file, released when dropped.
+///
+/// The lock is taken on a `<file>.lock` file next to the locked file, since state files are
+/// written atomically by renaming a temporary file over them, which would drop a lock held on
+/// the file itself.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    /// Lock the given file exclusively, blocking until the lock is available.
+    pub fn exclusive(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(lock_path(path))?;
+
+        file.lock_exclusive()?;
+
+        Ok(Self { file })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        self.file.unlock().ok();
+    }
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_file_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("tokens.json");
+        let lock = FileLock::exclusive(&path).unwrap();
+        let other = File::open(tmp.path().join("tokens.json.lock")).unwrap();
+
+        assert!(other.try_lock_exclusive().is_err());
+        drop(lock);
+        assert!(other.try_lock_exclusive().is_ok());
+    }
+}
@@ -0,0 +1,110 @@
+//! Notifications of ref changes between services sharing a monorepo.
+//!
+//! The HTTP API binds a unix datagram socket in the monorepo git directory, and the git-server
+//! sends a notification on it whenever the refs of a project change, eg. from the `post-receive`
+//! hook. Notifications are best-effort: they are dropped when no one is listening.
+use std::io;
+use std::os::unix::net::UnixDatagram as StdUnixDatagram;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UnixDatagram;
+
+/// Socket in the monorepo git directory that notifications are sent to.
+pub const NOTIFY_SOCKET: &str = "notify.sock";
+/// Maximum size of a notification.
+pub const MAX_NOTIFICATION_SIZE: usize = 64 * 1024;
+
+/// A ref update, relative to the project namespace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefUpdate {
+    /// Ref name, eg. `refs/remotes/<peer>/heads/master`.
+    pub name: String,
+    /// Previous target of the ref; all zeros if the ref was created.
+    pub old: String,
+    /// New target of the ref; all zeros if the ref was deleted.
+    pub new: String,
+}
+
+/// Notification that the refs of a project changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    /// Namespace of the project, ie. its id.
+    pub namespace: String,
+    /// The ref updates, if known.
+    #[serde(default)]
+    pub updates: Vec<RefUpdate>,
+}
+
+/// Send a notification to the listener of the monorepo at the given git directory, if any.
+pub fn send(git_dir: &Path, notification: &Notification) -> io::Result<()> {
+    let path = git_dir.join(NOTIFY_SOCKET);
+    let socket = StdUnixDatagram::unbound()?;
+    let mut bytes = serde_json::to_vec(notification)?;
+
+    // Large pushes don't fit in a datagram: only send the namespace then.
+    if bytes.len() > MAX_NOTIFICATION_SIZE {
+        bytes = serde_json::to_vec(&Notification {
+            namespace: notification.namespace.clone(),
+            updates: Vec::new(),
+        })?;
+    }
+    match socket.send_to(&bytes, &path) {
+        Ok(_) => Ok(()),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Receives notifications sent to a monorepo.
+#[derive(Debug)]
+pub struct Listener {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl Listener {
+    /// Bind the notification socket of the monorepo at the given git directory, replacing any
+    /// stale socket.
+    pub fn bind(git_dir: &Path) -> io::Result<Self> {
+        let path = git_dir.join(NOTIFY_SOCKET);
+
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let socket = UnixDatagram::bind(&path)?;
+
+        Ok(Self { socket, path })
+    }
+
+    /// Receive the next notification. Malformed notifications are skipped.
+    pub async fn recv(&self) -> io::Result<Notification> {
+        let mut buf = vec![0; MAX_NOTIFICATION_SIZE];
+
+        loop {
+            let n = self.socket.recv(&mut buf).await?;
+
+            match serde_json::from_slice(&buf[..n]) {
+                Ok(notification) => return Ok(notification),
+                Err(err) => {
+                    tracing::warn!("Skipping malformed notification: {}", err);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
@@ -0,0 +1,545 @@
+//! Reverse-proxy awareness.
+//!
+//! When a service runs behind a reverse proxy or load balancer, the TCP peer of every request is
+//! the proxy. Given a list of trusted proxies, the address of the client is instead taken from
+//! the forwarding header set by the proxies, either `X-Forwarded-For` or `Forwarded`, or from a
+//! PROXY protocol v2 header sent at the start of the connection.
+//!
+//! Only the header the proxies are configured to set is read: proxies usually append to the
+//! header they set, and pass other headers through untouched, so that clients could spoof them.
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::extract::connect_info::{ConnectInfo, Connected};
+use axum::middleware::Next;
+use axum::response::Response;
+use http::{HeaderMap, Request};
+use hyper::server::accept::Accept;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+pub use ipnet::IpNet;
+
+/// PROXY protocol v2 signature.
+const PROXY_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// How long a proxy has to send the PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before accepting connections again after an error, eg. when out of file
+/// descriptors, so that the listener doesn't spin.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Address of the client that made a request, after taking trusted proxies into account.
+///
+/// Inserted as a request extension by [`middleware`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub IpAddr);
+
+/// The forwarding header set by trusted proxies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardingHeader {
+    /// The de-facto standard `X-Forwarded-For` header, eg. set by nginx.
+    XForwardedFor,
+    /// The standard `Forwarded` header, from RFC 7239.
+    Forwarded,
+}
+
+impl Default for ForwardingHeader {
+    fn default() -> Self {
+        Self::XForwardedFor
+    }
+}
+
+impl FromStr for ForwardingHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(Self::XForwardedFor),
+            "forwarded" => Ok(Self::Forwarded),
+            _ => Err(String::from(
+                "forwarding header must be 'x-forwarded-for' or 'forwarded'",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ForwardingHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::XForwardedFor => write!(f, "x-forwarded-for"),
+            Self::Forwarded => write!(f, "forwarded"),
+        }
+    }
+}
+
+/// The proxies whose forwarding headers are trusted.
+#[derive(Debug, Default, Clone)]
+pub struct TrustedProxies {
+    networks: Arc<Vec<IpNet>>,
+    header: ForwardingHeader,
+}
+
+impl TrustedProxies {
+    /// Trust the given header, when set by proxies in the given networks.
+    pub fn new(networks: Vec<IpNet>, header: ForwardingHeader) -> Self {
+        Self {
+            networks: Arc::new(networks),
+            header,
+        }
+    }
+
+    /// Whether no proxy is trusted.
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    /// Whether the given address belongs to a trusted proxy.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = canonical(*addr);
+
+        self.networks.iter().any(|n| n.contains(&addr))
+    }
+
+    /// Resolve the client address of a request received from the given peer.
+    ///
+    /// The forwarding chain is walked from the closest hop, skipping trusted proxies, so that
+    /// addresses added by untrusted clients are never used.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(&peer) {
+            return peer;
+        }
+        let chain = forwarded_for(headers, self.header);
+        let mut client = peer;
+
+        for addr in chain.into_iter().rev() {
+            client = addr;
+
+            if !self.contains(&addr) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Middleware that resolves the client address of each request, and inserts it as a
+/// [`ClientAddr`] extension. Requires the [`TrustedProxies`] as an extension, and the
+/// service to be made with `into_make_service_with_connect_info::<SocketAddr>`.
+pub async fn middleware<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
+    if let Some(peer) = peer {
+        let client = match req.extensions().get::<TrustedProxies>() {
+            Some(proxies) => proxies.resolve(peer, req.headers()),
+            None => peer,
+        };
+        req.extensions_mut().insert(ClientAddr(client));
+    }
+    next.run(req).await
+}
+
+/// Get the addresses listed in the given forwarding header, from the furthest to the closest hop.
+fn forwarded_for(headers: &HeaderMap, header: ForwardingHeader) -> Vec<IpAddr> {
+    match header {
+        ForwardingHeader::XForwardedFor => headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .filter_map(|addr| parse_node(addr.trim()))
+            .collect(),
+        ForwardingHeader::Forwarded => headers
+            .get_all("forwarded")
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+
+                    if key.eq_ignore_ascii_case("for") {
+                        parse_node(value.trim_matches('"'))
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect(),
+    }
+}
+
+/// Parse a forwarded node, eg. `192.0.2.43`, `192.0.2.43:4711` or `[2001:db8::1]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(addr) = IpAddr::from_str(node) {
+        return Some(addr);
+    }
+    if let Ok(addr) = SocketAddr::from_str(node) {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .and_then(|n| Ipv6Addr::from_str(n).ok())
+        .map(IpAddr::V6)
+}
+
+/// Map IPv4-mapped IPv6 addresses to IPv4, so that they match IPv4 networks.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+/// A connection accepted by a [`ProxyListener`].
+#[derive(Debug)]
+pub struct ProxyStream {
+    inner: TcpStream,
+    /// Address of the client, as reported by the proxy.
+    remote: SocketAddr,
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Connected<&ProxyStream> for SocketAddr {
+    fn connect_info(target: &ProxyStream) -> Self {
+        target.remote
+    }
+}
+
+/// A listener that expects every connection to start with a PROXY protocol v2 header, sent by
+/// one of the trusted proxies. Connections from other peers, or without a valid header, are
+/// dropped.
+pub struct ProxyListener {
+    conns: mpsc::Receiver<ProxyStream>,
+}
+
+impl ProxyListener {
+    /// Bind to the given address, and start accepting connections.
+    pub async fn bind(addr: SocketAddr, proxies: TrustedProxies) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, rx) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        tracing::error!("Failed to accept connection: {}", err);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                if !proxies.contains(&peer.ip()) {
+                    tracing::warn!("Dropping connection from untrusted proxy {}", peer);
+                    continue;
+                }
+                let tx = tx.clone();
+
+                // Read the header in a separate task, so that a slow proxy doesn't hold up
+                // other connections.
+                tokio::spawn(async move {
+                    match tokio::time::timeout(PROXY_HEADER_TIMEOUT, accept(stream, peer)).await {
+                        Ok(Ok(stream)) => {
+                            tx.send(stream).await.ok();
+                        }
+                        Ok(Err(err)) => {
+                            tracing::warn!("Invalid PROXY header from {}: {}", peer, err);
+                        }
+                        Err(_) => {
+                            tracing::warn!("Timed out reading PROXY header from {}", peer);
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Self { conns: rx })
+    }
+}
+
+impl Accept for ProxyListener {
+    type Conn = ProxyStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.conns.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+/// Read the PROXY protocol v2 header of a connection.
+async fn accept(mut stream: TcpStream, peer: SocketAddr) -> io::Result<ProxyStream> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut header = [0; 16];
+
+    stream.read_exact(&mut header).await?;
+
+    if header[..12] != PROXY_SIGNATURE {
+        return Err(invalid("missing signature"));
+    }
+    let (version, command) = (header[12] >> 4, header[12] & 0x0f);
+    if version != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    let family = header[13];
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut addrs = vec![0; len];
+
+    stream.read_exact(&mut addrs).await?;
+
+    let remote = match (command, family >> 4) {
+        // LOCAL: the connection was made by the proxy itself, eg. for health checks.
+        (0x0, _) => peer,
+        // PROXY over IPv4.
+        (0x1, 0x1) if len >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+
+            SocketAddr::new(IpAddr::V4(ip), port)
+        }
+        // PROXY over IPv6.
+        (0x1, 0x2) if len >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)
+        }
+        // Unspecified or unix socket addresses.
+        (0x1, _) => peer,
+        _ => return Err(invalid("unsupported command")),
+    };
+
+    Ok(ProxyStream {
+        inner: stream,
+        remote,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+    use tokio::io::AsyncWriteExt;
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()], Default::default())
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(*value));
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// Read a PROXY header sent by a client writing the given bytes, then closing the connection.
+    async fn read_header(bytes: Vec<u8>) -> io::Result<ProxyStream> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(&bytes).await.unwrap();
+        });
+        let (stream, peer) = listener.accept().await.unwrap();
+
+        client.await.unwrap();
+        accept(stream, peer).await
+    }
+
+    /// A PROXY header for a TCP over IPv4 connection from the given client.
+    fn header_v4(client: Ipv4Addr, port: u16) -> Vec<u8> {
+        let mut header = PROXY_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend(client.octets());
+        header.extend([127, 0, 0, 1]);
+        header.extend(port.to_be_bytes());
+        header.extend(8777u16.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn test_resolve_spoofed() {
+        let proxies = proxies();
+        let spoofed = headers(&[("x-forwarded-for", "192.0.2.1, 203.0.113.7, 10.0.0.2")]);
+
+        // Entries added by the client before reaching the proxies are ignored.
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &spoofed), ip("203.0.113.7"));
+        // So are the headers of untrusted peers.
+        assert_eq!(
+            proxies.resolve(ip("203.0.113.9"), &spoofed),
+            ip("203.0.113.9")
+        );
+        // If every hop is a trusted proxy, the furthest one is the client.
+        let internal = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &internal), ip("10.0.0.3"));
+        // Without headers, the peer is the client.
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_resolve_forwarded() {
+        let proxies = TrustedProxies::new(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            ForwardingHeader::Forwarded,
+        );
+        let forwarded = headers(&[
+            ("forwarded", "for=192.0.2.60;proto=http"),
+            ("forwarded", "For=\"[2001:db8::1]:4711\";by=10.0.0.2"),
+            ("x-forwarded-for", "198.51.100.1"),
+        ]);
+
+        // The `Forwarded` header may hold quoted IPv6 addresses with a port.
+        assert_eq!(
+            forwarded_for(&forwarded, ForwardingHeader::Forwarded),
+            vec![ip("192.0.2.60"), ip("2001:db8::1")]
+        );
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), &forwarded),
+            ip("2001:db8::1")
+        );
+        assert_eq!("Forwarded".parse(), Ok(ForwardingHeader::Forwarded));
+        assert_eq!(
+            "x-forwarded-for".parse(),
+            Ok(ForwardingHeader::XForwardedFor)
+        );
+        assert!("x-real-ip".parse::<ForwardingHeader>().is_err());
+
+        assert_eq!(parse_node("192.0.2.43:4711"), Some(ip("192.0.2.43")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn test_resolve_spoofed_forwarded() {
+        let proxies = proxies();
+        // The proxy appends to `X-Forwarded-For`, and passes the client's `Forwarded` through.
+        let spoofed = headers(&[
+            ("forwarded", "for=192.0.2.1"),
+            ("x-forwarded-for", "203.0.113.7"),
+        ]);
+
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &spoofed), ip("203.0.113.7"));
+
+        // And the other way around.
+        let proxies = TrustedProxies::new(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            ForwardingHeader::Forwarded,
+        );
+        let spoofed = headers(&[
+            ("forwarded", "for=203.0.113.7"),
+            ("x-forwarded-for", "192.0.2.1"),
+        ]);
+
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &spoofed), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn test_resolve_ipv4_mapped() {
+        let proxies = proxies();
+
+        assert!(proxies.contains(&ip("::ffff:10.0.0.1")));
+        assert!(!proxies.contains(&ip("::ffff:192.0.2.1")));
+        assert_eq!(canonical(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(canonical(ip("2001:db8::1")), ip("2001:db8::1"));
+
+        // Mapped proxies are trusted, and skipped in the forwarding chain.
+        let mapped = headers(&[("x-forwarded-for", "192.0.2.1, ::ffff:10.0.0.2")]);
+        assert_eq!(
+            proxies.resolve(ip("::ffff:10.0.0.1"), &mapped),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        let stream = read_header(header_v4(Ipv4Addr::new(192, 0, 2, 1), 4711))
+            .await
+            .unwrap();
+        assert_eq!(stream.remote, "192.0.2.1:4711".parse().unwrap());
+
+        // LOCAL connections are made by the proxy itself.
+        let mut local = PROXY_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        let stream = read_header(local).await.unwrap();
+        assert_eq!(stream.remote.ip(), ip("127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_accept_invalid() {
+        let valid = header_v4(Ipv4Addr::new(192, 0, 2, 1), 4711);
+
+        // Truncated in the signature, or in the addresses.
+        for len in [8, 20] {
+            let err = read_header(valid[..len].to_vec()).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+
+        // A PROXY protocol v1 header.
+        let err = read_header(b"PROXY TCP4 192.0.2.1 127.0.0.1 4711 8777\r\n".to_vec())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Unsupported version or command.
+        for byte in [0x11, 0x2f] {
+            let mut header = valid.clone();
+            header[12] = byte;
+
+            let err = read_header(header).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Per-client rate limiting.
+//!
+//! Requests are limited by client address, as resolved by [`crate::proxy::middleware`], so that
+//! clients behind a trusted proxy are limited individually instead of sharing the proxy's limit.
+//! Each client has a bucket of requests which refills continuously, up to the limit per minute.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::{header, Request, StatusCode};
+
+use crate::proxy::ClientAddr;
+
+/// Number of clients tracked before the clients with a full bucket are forgotten.
+const MAX_CLIENTS: usize = 10_000;
+/// Period over which the limit applies.
+const PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// Requests left.
+    tokens: f64,
+    /// When the bucket was last refilled.
+    refilled: Instant,
+}
+
+/// Limits the number of requests per minute of each client.
+#[derive(Debug, Default, Clone)]
+pub struct RateLimiter {
+    /// Requests allowed per minute, or `None` if requests aren't limited.
+    limit: Option<NonZeroU32>,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter allowing the given number of requests per minute and client.
+    /// With `None`, requests aren't limited.
+    pub fn new(limit: Option<NonZeroU32>) -> Self {
+        Self {
+            limit,
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Count a request of the given client. If the client is over the limit, returns how long
+    /// it should wait before retrying.
+    pub fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let limit = match self.limit {
+            Some(limit) => limit.get() as f64,
+            None => return Ok(()),
+        };
+        let rate = limit / PERIOD.as_secs_f64();
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+            (bucket.tokens + elapsed * rate).min(limit)
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            buckets.retain(|_, bucket| refill(bucket) < limit);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: limit,
+            refilled: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1. - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// Middleware that rejects requests of clients over the limit with `429 Too Many Requests`.
+/// Requires the [`RateLimiter`] as an extension, and to run after [`crate::proxy::middleware`].
+pub async fn middleware<B>(req: Request<B>, next: Next<B>) -> Response {
+    let limited = match (
+        req.extensions().get::<RateLimiter>(),
+        req.extensions().get::<ClientAddr>(),
+    ) {
+        (Some(limiter), Some(ClientAddr(client))) => limiter.check(*client, Instant::now()).err(),
+        _ => None,
+    };
+
+    if let Some(wait) = limited {
+        // Round up, so that the client doesn't retry too early.
+        let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, secs.to_string())],
+            "Too many requests\n",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_check() {
+        let limiter = RateLimiter::new(NonZeroU32::new(2));
+        let alice = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let bob = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(alice, now), Ok(()));
+        assert_eq!(limiter.check(alice, now), Ok(()));
+        let wait = limiter.check(alice, now).unwrap_err();
+        assert_eq!(wait.as_secs_f64().round(), 30.);
+
+        // Other clients have their own limit.
+        assert_eq!(limiter.check(bob, now), Ok(()));
+
+        // A request is allowed again once the bucket has refilled by one.
+        let later = now + Duration::from_secs(31);
+        assert_eq!(limiter.check(alice, later), Ok(()));
+        assert!(limiter.check(alice, later).is_err());
+
+        // Without a limit, every request is allowed.
+        let limiter = RateLimiter::new(None);
+        for _ in 0..100 {
+            assert_eq!(limiter.check(alice, now), Ok(()));
+        }
+    }
+}